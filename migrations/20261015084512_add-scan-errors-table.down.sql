@@ -0,0 +1,2 @@
+-- Revert tracking packages that failed to scan
+DROP TABLE IF EXISTS pv_scan_errors;
@@ -0,0 +1,6 @@
+-- Track packages that failed to scan
+CREATE TABLE IF NOT EXISTS pv_scan_errors (
+    filename TEXT PRIMARY KEY,
+    error TEXT NOT NULL,
+    last_seen TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT (now())
+);
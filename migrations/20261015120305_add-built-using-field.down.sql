@@ -0,0 +1,3 @@
+-- Revert adding Built-Using field
+ALTER TABLE pv_packages DROP COLUMN IF EXISTS built_using;
+ALTER TABLE pv_package_duplicate DROP COLUMN IF EXISTS built_using;
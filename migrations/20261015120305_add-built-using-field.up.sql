@@ -0,0 +1,3 @@
+-- Add Built-Using field
+ALTER TABLE pv_packages ADD COLUMN IF NOT EXISTS built_using TEXT;
+ALTER TABLE pv_package_duplicate ADD COLUMN IF NOT EXISTS built_using TEXT;
@@ -0,0 +1,2 @@
+-- Revert adding parsed dependency values
+ALTER TABLE pv_package_dependencies DROP COLUMN IF EXISTS parsed;
@@ -0,0 +1,2 @@
+-- Add structured (parsed) form of the dependency value
+ALTER TABLE pv_package_dependencies ADD COLUMN IF NOT EXISTS parsed JSONB;
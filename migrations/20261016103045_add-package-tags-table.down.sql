@@ -0,0 +1,2 @@
+-- Revert tracking package debtags
+DROP TABLE IF EXISTS pv_package_tags;
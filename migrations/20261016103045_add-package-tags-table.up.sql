@@ -0,0 +1,11 @@
+-- Track package debtags (Tag field)
+CREATE TABLE IF NOT EXISTS pv_package_tags (
+    package TEXT NOT NULL,
+    version TEXT NOT NULL,
+    repo TEXT NOT NULL,
+    tag TEXT NOT NULL,
+    PRIMARY KEY (package, version, repo, tag),
+    CONSTRAINT fkey_package FOREIGN KEY (package, version, repo)
+    REFERENCES pv_packages (package, version, repo) ON DELETE CASCADE INITIALLY DEFERRED
+);
+CREATE INDEX IF NOT EXISTS idx_pv_package_tags_tag ON pv_package_tags (tag);
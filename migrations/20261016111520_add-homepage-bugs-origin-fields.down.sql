@@ -0,0 +1,7 @@
+-- Revert adding Homepage, Bugs and Origin fields
+ALTER TABLE pv_packages DROP COLUMN IF EXISTS homepage;
+ALTER TABLE pv_packages DROP COLUMN IF EXISTS bugs;
+ALTER TABLE pv_packages DROP COLUMN IF EXISTS origin;
+ALTER TABLE pv_package_duplicate DROP COLUMN IF EXISTS homepage;
+ALTER TABLE pv_package_duplicate DROP COLUMN IF EXISTS bugs;
+ALTER TABLE pv_package_duplicate DROP COLUMN IF EXISTS origin;
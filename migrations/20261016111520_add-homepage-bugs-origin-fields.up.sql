@@ -0,0 +1,7 @@
+-- Add Homepage, Bugs and Origin fields
+ALTER TABLE pv_packages ADD COLUMN IF NOT EXISTS homepage TEXT;
+ALTER TABLE pv_packages ADD COLUMN IF NOT EXISTS bugs TEXT;
+ALTER TABLE pv_packages ADD COLUMN IF NOT EXISTS origin TEXT;
+ALTER TABLE pv_package_duplicate ADD COLUMN IF NOT EXISTS homepage TEXT;
+ALTER TABLE pv_package_duplicate ADD COLUMN IF NOT EXISTS bugs TEXT;
+ALTER TABLE pv_package_duplicate ADD COLUMN IF NOT EXISTS origin TEXT;
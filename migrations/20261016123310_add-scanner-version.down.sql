@@ -0,0 +1,5 @@
+-- Revert recording the scanner version
+ALTER TABLE pv_packages DROP COLUMN IF EXISTS scanner_version;
+ALTER TABLE pv_packages DROP COLUMN IF EXISTS scanned_at;
+ALTER TABLE pv_package_duplicate DROP COLUMN IF EXISTS scanner_version;
+ALTER TABLE pv_package_duplicate DROP COLUMN IF EXISTS scanned_at;
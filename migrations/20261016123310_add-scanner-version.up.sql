@@ -0,0 +1,5 @@
+-- Record which p-vector version scanned each package and when
+ALTER TABLE pv_packages ADD COLUMN IF NOT EXISTS scanner_version TEXT;
+ALTER TABLE pv_packages ADD COLUMN IF NOT EXISTS scanned_at TIMESTAMP WITH TIME ZONE;
+ALTER TABLE pv_package_duplicate ADD COLUMN IF NOT EXISTS scanner_version TEXT;
+ALTER TABLE pv_package_duplicate ADD COLUMN IF NOT EXISTS scanned_at TIMESTAMP WITH TIME ZONE;
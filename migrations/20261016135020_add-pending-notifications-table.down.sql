@@ -0,0 +1,2 @@
+-- Revert queueing undelivered change notifications
+DROP TABLE IF EXISTS pv_pending_notifications;
@@ -0,0 +1,6 @@
+-- Queue change notifications that could not be delivered to the change notifier
+CREATE TABLE IF NOT EXISTS pv_pending_notifications (
+    id BIGSERIAL PRIMARY KEY,
+    payload TEXT NOT NULL,
+    created TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
@@ -0,0 +1,2 @@
+-- Revert storing the pre-rendered Contents lines of each package
+DROP TABLE IF EXISTS pv_package_contents;
@@ -0,0 +1,20 @@
+-- Store the pre-rendered Contents lines of each package
+CREATE TABLE IF NOT EXISTS pv_package_contents (
+    package TEXT NOT NULL,
+    version TEXT NOT NULL,
+    repo TEXT NOT NULL,
+    contents TEXT NOT NULL,
+    PRIMARY KEY (package, version, repo),
+    CONSTRAINT fkey_package FOREIGN KEY (package, version, repo)
+    REFERENCES pv_packages (package, version, repo) ON DELETE CASCADE INITIALLY DEFERRED
+);
+-- Render the lines of the existing packages from their file lists
+INSERT INTO pv_package_contents
+SELECT p.package, p.version, p.repo, coalesce(string_agg(
+    f.path || '/' || f.name || '   ' || p.section || '/' || p.package || chr(10), ''
+    ORDER BY f.path || '/' || f.name COLLATE "C"
+) FILTER (WHERE f.ftype < 53), '')
+FROM pv_packages p
+LEFT JOIN pv_package_files f USING (package, version, repo)
+GROUP BY p.package, p.version, p.repo, p.section
+ON CONFLICT DO NOTHING;
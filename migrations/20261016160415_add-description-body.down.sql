@@ -0,0 +1,3 @@
+-- Revert storing the extended description separately
+ALTER TABLE pv_packages DROP COLUMN IF EXISTS description_body;
+ALTER TABLE pv_package_duplicate DROP COLUMN IF EXISTS description_body;
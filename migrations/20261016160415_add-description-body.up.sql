@@ -0,0 +1,3 @@
+-- Store the extended description separately from the synopsis
+ALTER TABLE pv_packages ADD COLUMN IF NOT EXISTS description_body TEXT;
+ALTER TABLE pv_package_duplicate ADD COLUMN IF NOT EXISTS description_body TEXT;
@@ -0,0 +1,2 @@
+-- Revert tracking the extra control fields
+DROP TABLE IF EXISTS pv_package_extra_fields;
@@ -0,0 +1,11 @@
+-- Track the extra control fields passed through to Packages (extra_packages_fields)
+CREATE TABLE IF NOT EXISTS pv_package_extra_fields (
+    package TEXT NOT NULL,
+    version TEXT NOT NULL,
+    repo TEXT NOT NULL,
+    field TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (package, version, repo, field),
+    CONSTRAINT fkey_package FOREIGN KEY (package, version, repo)
+    REFERENCES pv_packages (package, version, repo) ON DELETE CASCADE INITIALLY DEFERRED
+);
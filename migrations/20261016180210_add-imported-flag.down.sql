@@ -0,0 +1,3 @@
+-- Revert marking the imported packages
+ALTER TABLE pv_packages DROP COLUMN IF EXISTS imported;
+ALTER TABLE pv_package_duplicate DROP COLUMN IF EXISTS imported;
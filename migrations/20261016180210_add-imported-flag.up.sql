@@ -0,0 +1,3 @@
+-- Mark the packages imported from an existing Packages index (without their files)
+ALTER TABLE pv_packages ADD COLUMN IF NOT EXISTS imported BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE pv_package_duplicate ADD COLUMN IF NOT EXISTS imported BOOLEAN NOT NULL DEFAULT false;
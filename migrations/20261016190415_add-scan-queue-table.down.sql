@@ -0,0 +1,2 @@
+-- Revert persisting the scan queue
+DROP TABLE IF EXISTS pv_scan_queue;
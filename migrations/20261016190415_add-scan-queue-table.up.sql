@@ -0,0 +1,7 @@
+-- Persist the packages pending a scan, so that an interrupted scan can be resumed
+CREATE TABLE IF NOT EXISTS pv_scan_queue (
+    filename TEXT PRIMARY KEY,
+    size BIGINT NOT NULL,
+    mtime BIGINT NOT NULL,
+    queued TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
@@ -0,0 +1,2 @@
+-- Revert tracking the interpreter and library search paths of the ELF files
+DROP TABLE IF EXISTS pv_package_elf;
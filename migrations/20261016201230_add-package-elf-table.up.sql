@@ -0,0 +1,13 @@
+-- Track the interpreter and library search paths (RPATH/RUNPATH) of the ELF files
+CREATE TABLE IF NOT EXISTS pv_package_elf (
+    package TEXT NOT NULL,
+    version TEXT NOT NULL,
+    repo TEXT NOT NULL,
+    path TEXT NOT NULL,
+    interpreter TEXT,
+    rpath TEXT,
+    runpath TEXT,
+    PRIMARY KEY (package, version, repo, path),
+    CONSTRAINT fkey_package FOREIGN KEY (package, version, repo)
+    REFERENCES pv_packages (package, version, repo) ON DELETE CASCADE INITIALLY DEFERRED
+);
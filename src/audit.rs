@@ -0,0 +1,319 @@
+//! Consistency checks between the database and the published indices
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use rayon::prelude::*;
+use sqlx::PgPool;
+
+use crate::db::{self, PVPackage};
+use crate::parser::{parse_packages_stream, PackageMap};
+use crate::scan::{sha256sum, PackageSource};
+
+/// A package listed in a `Packages` index
+#[derive(Debug)]
+pub struct IndexEntry {
+    package: String,
+    version: String,
+    architecture: String,
+    sha256: String,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct AuditReport {
+    /// Packages in the index that are unknown to the database (or have a different checksum)
+    pub only_in_index: BTreeSet<String>,
+    /// Packages in the database that are missing from the index
+    pub only_in_database: BTreeSet<String>,
+}
+
+impl AuditReport {
+    pub fn is_consistent(&self) -> bool {
+        self.only_in_index.is_empty() && self.only_in_database.is_empty()
+    }
+}
+
+fn display_name(package: &str, version: &str, architecture: &str) -> String {
+    format!("{} {} ({})", package, version, architecture)
+}
+
+/// Read the packages listed in a `Packages` index
+pub fn read_packages_index(content: &[u8]) -> Result<Vec<IndexEntry>> {
+    let (rest, packages) =
+        parse_packages_stream(content).map_err(|e| anyhow!("Malformed index: {}", e))?;
+    if !rest.is_empty() {
+        return Err(anyhow!(
+            "Malformed index: unexpected data at byte {}",
+            content.len() - rest.len()
+        ));
+    }
+    let field = |package: &PackageMap, name: &str| -> Result<String> {
+        package
+            .get(name.as_bytes())
+            .map(|v| String::from_utf8_lossy(v).to_string())
+            .ok_or_else(|| anyhow!("Malformed index: missing {} field", name))
+    };
+
+    packages
+        .iter()
+        .map(|p| {
+            Ok(IndexEntry {
+                package: field(p, "Package")?,
+                version: field(p, "Version")?,
+                architecture: field(p, "Architecture")?,
+                sha256: field(p, "SHA256")?,
+            })
+        })
+        .collect()
+}
+
+/// Compare the packages known to the database with the ones listed in the index
+pub fn compare_packages(database: &[PVPackage], index: &[IndexEntry]) -> AuditReport {
+    // duplicated packages share the same name, version and architecture
+    let mut known: HashMap<(&str, &str, &str), HashSet<&str>> = HashMap::new();
+    for p in database {
+        let key = (
+            p.package.as_deref().unwrap_or_default(),
+            p.version.as_deref().unwrap_or_default(),
+            p.architecture.as_deref().unwrap_or_default(),
+        );
+        let checksums = known.entry(key).or_default();
+        if let Some(sha256) = p.sha256.as_deref() {
+            checksums.insert(sha256);
+        }
+    }
+    let mut report = AuditReport::default();
+    let mut listed = HashSet::new();
+    for p in index {
+        let key = (
+            p.package.as_str(),
+            p.version.as_str(),
+            p.architecture.as_str(),
+        );
+        listed.insert(key);
+        if !known
+            .get(&key)
+            .is_some_and(|checksums| checksums.contains(p.sha256.as_str()))
+        {
+            report
+                .only_in_index
+                .insert(display_name(key.0, key.1, key.2));
+        }
+    }
+    for key in known.keys() {
+        if !listed.contains(key) {
+            report
+                .only_in_database
+                .insert(display_name(key.0, key.1, key.2));
+        }
+    }
+
+    report
+}
+
+/// Audit a component (e.g. `stable/main`) against its published `Packages` indices,
+/// or against the specified index file
+pub async fn audit_component(
+    pool: &PgPool,
+    mirror_root: &Path,
+    component: &str,
+    index: Option<&Path>,
+) -> Result<AuditReport> {
+    let mut files = Vec::new();
+    match index {
+        Some(index) => files.push(index.to_owned()),
+        None => {
+            let component_root = mirror_root.join("dists").join(component);
+            for entry in std::fs::read_dir(&component_root)? {
+                let path = entry?.path();
+                let is_binary = path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with("binary-"));
+                if is_binary && path.join("Packages").is_file() {
+                    files.push(path.join("Packages"));
+                }
+            }
+        }
+    }
+    let mut entries = Vec::new();
+    for file in files {
+        let content = tokio::fs::read(&file).await?;
+        entries.extend(
+            read_packages_index(&content).map_err(|e| anyhow!("{}: {}", file.display(), e))?,
+        );
+    }
+    let packages = db::list_packages_in_component(pool, component).await?;
+
+    Ok(compare_packages(&packages, &entries))
+}
+
+/// Check that the package listed in an index is in the pool with the listed size and checksum
+fn check_index_entry(
+    source: &dyn PackageSource,
+    filename: &str,
+    size: u64,
+    sha256: &str,
+) -> Option<String> {
+    let stat = match source.stat(filename) {
+        Ok(Some(stat)) => stat,
+        Ok(None) => return Some(format!("{} does not exist", filename)),
+        Err(e) => return Some(format!("{}: {}", filename, e)),
+    };
+    if stat.size != size {
+        return Some(format!(
+            "{} is {} bytes, the index says {}",
+            filename, stat.size, size
+        ));
+    }
+    match source.open(filename).and_then(sha256sum) {
+        Ok(actual) if actual == sha256 => None,
+        Ok(actual) => Some(format!(
+            "{} has checksum {}, the index says {}",
+            filename, actual, sha256
+        )),
+        Err(e) => Some(format!("{}: {}", filename, e)),
+    }
+}
+
+/// Check the `Packages` indices under the directory: they must parse cleanly, and every
+/// package they list must be in the pool with the listed size and checksum.
+/// Returns the discrepancies found.
+pub fn validate_indices(root: &Path, source: &dyn PackageSource) -> Result<Vec<String>> {
+    let mut problems = Vec::new();
+    let mut entries = Vec::new();
+    for entry in walkdir::WalkDir::new(root) {
+        let entry = entry?;
+        if !entry.file_type().is_file() || entry.file_name() != "Packages" {
+            continue;
+        }
+        let index = entry.path().display().to_string();
+        let content = std::fs::read(entry.path())?;
+        let packages = match parse_packages_stream(&content) {
+            Ok((rest, _)) if !rest.is_empty() => {
+                problems.push(format!(
+                    "{}: unexpected data at byte {}",
+                    index,
+                    content.len() - rest.len()
+                ));
+                continue;
+            }
+            Ok((_, packages)) => packages,
+            Err(e) => {
+                problems.push(format!("{}: malformed index: {}", index, e));
+                continue;
+            }
+        };
+        for p in packages {
+            let field = |name: &str| {
+                p.get(name.as_bytes())
+                    .map(|v| String::from_utf8_lossy(v).to_string())
+            };
+            match (field("Filename"), field("Size"), field("SHA256")) {
+                (Some(filename), Some(size), Some(sha256)) => match size.parse::<u64>() {
+                    Ok(size) => entries.push((index.clone(), filename, size, sha256)),
+                    Err(_) => {
+                        problems.push(format!("{}: {}: invalid Size {}", index, filename, size))
+                    }
+                },
+                _ => problems.push(format!(
+                    "{}: {} lacks Filename, Size or SHA256",
+                    index,
+                    field("Package").unwrap_or_default()
+                )),
+            }
+        }
+    }
+    source.preload()?;
+    problems.par_extend(
+        entries
+            .par_iter()
+            .filter_map(|(index, filename, size, sha256)| {
+                check_index_entry(source, filename, *size, sha256)
+                    .map(|p| format!("{}: {}", index, p))
+            }),
+    );
+    problems.sort();
+
+    Ok(problems)
+}
+
+#[test]
+fn test_compare_packages() {
+    let package = |name: &str, version: &str, sha256: &str| PVPackage {
+        package: Some(name.to_string()),
+        version: Some(version.to_string()),
+        repo: Some("amd64/stable".to_string()),
+        architecture: Some("amd64".to_string()),
+        filename: None,
+        size: None,
+        mtime: None,
+        sha256: Some(sha256.to_string()),
+        imported: None,
+    };
+    let database = vec![
+        package("bash", "5.2", "aaaa"),
+        // duplicated package with a different checksum
+        package("bash", "5.2", "bbbb"),
+        package("zsh", "5.9", "cccc"),
+        package("curl", "8.0", "dddd"),
+    ];
+    let index = read_packages_index(
+        b"Package: bash\nVersion: 5.2\nArchitecture: amd64\nSHA256: bbbb\n\n\
+Package: zsh\nVersion: 5.9\nArchitecture: amd64\nSHA256: ffff\n\n\
+Package: vim\nVersion: 9.1\nArchitecture: amd64\nSHA256: eeee\n\n",
+    )
+    .unwrap();
+    let report = compare_packages(&database, &index);
+    assert_eq!(
+        report.only_in_index.into_iter().collect::<Vec<_>>(),
+        vec!["vim 9.1 (amd64)", "zsh 5.9 (amd64)"]
+    );
+    assert_eq!(
+        report.only_in_database.into_iter().collect::<Vec<_>>(),
+        vec!["curl 8.0 (amd64)"]
+    );
+    assert!(read_packages_index(b"Package: bash\nVersion: 5.2\n\n").is_err());
+}
+
+#[test]
+fn test_validate_indices() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = crate::scan::source::LocalSource::new(dir.path());
+    std::fs::create_dir_all(dir.path().join("pool/main")).unwrap();
+    std::fs::write(dir.path().join("pool/main/a.deb"), b"a").unwrap();
+    let sha256 = sha256sum(&b"a"[..]).unwrap();
+    let index = dir.path().join("dists/stable/main/binary-amd64");
+    std::fs::create_dir_all(&index).unwrap();
+    std::fs::write(
+        index.join("Packages"),
+        format!(
+            "Package: a\nFilename: pool/main/a.deb\nSize: 1\nSHA256: {}\n\n",
+            sha256
+        ),
+    )
+    .unwrap();
+    let dists = dir.path().join("dists");
+    assert!(validate_indices(&dists, &source).unwrap().is_empty());
+    std::fs::write(
+        index.join("Packages"),
+        format!(
+            "Package: a\nFilename: pool/main/a.deb\nSize: 2\nSHA256: {}\n\n\
+Package: b\nFilename: pool/main/b.deb\nSize: 1\nSHA256: {}\n\n\
+Package: c\nFilename: pool/main/a.deb\nSize: 1\nSHA256: ffff\n\n\
+Package: d\n\n",
+            sha256, sha256
+        ),
+    )
+    .unwrap();
+    let problems = validate_indices(&dists, &source).unwrap();
+    assert_eq!(problems.len(), 4);
+    assert!(problems[0].ends_with("d lacks Filename, Size or SHA256"));
+    assert!(problems[1].ends_with(&format!(
+        "pool/main/a.deb has checksum {}, the index says ffff",
+        sha256
+    )));
+    assert!(problems[2].ends_with("pool/main/a.deb is 1 bytes, the index says 2"));
+    assert!(problems[3].ends_with("pool/main/b.deb does not exist"));
+}
@@ -1,14 +1,69 @@
 use argh::FromArgs;
 
-#[derive(FromArgs, PartialEq, Debug)]
+use crate::sign::KeyAlgorithm;
+
+#[derive(FromArgs, PartialEq, Debug, Default)]
 /// run scan phase only: scan all the packages and commit to database
 #[argh(subcommand, name = "scan")]
-pub(crate) struct PVectorScan {}
+pub(crate) struct PVectorScan {
+    /// reject packages whose section does not match the component they are filed under
+    #[argh(switch)]
+    pub strict: bool,
+    /// save each package separately so that a package failing to save does not discard the others
+    #[argh(switch)]
+    pub keep_going: bool,
+    /// only scan the packages listed in the file (one path relative to the pool per line, `-` for stdin)
+    #[argh(option)]
+    pub from_list: Option<String>,
+    /// retry publishing the change notifications this many times before queueing them for the next run (default: 3)
+    #[argh(option)]
+    pub max_retries: Option<u32>,
+    /// only refresh the file metadata (mtime) of the known packages from the pool, without reading their contents
+    #[argh(switch)]
+    pub refresh_metadata: bool,
+    /// exit with an error (after saving the other packages) if any package failed to scan or save
+    #[argh(switch)]
+    pub fail_on_error: bool,
+    /// do not scan the files of the specified package, matched by their file name (can be repeated)
+    #[argh(option)]
+    pub exclude_package: Vec<String>,
+}
 
-#[derive(FromArgs, PartialEq, Debug)]
+#[derive(FromArgs, PartialEq, Debug, Default)]
 /// run release phase only: generate Release files
 #[argh(subcommand, name = "release")]
-pub(crate) struct PVectorRelease {}
+pub(crate) struct PVectorRelease {
+    /// only generate Packages and Contents for the specified architecture (and `all`)
+    #[argh(option)]
+    pub arch: Option<String>,
+    /// do not generate Packages and Contents for the specified architecture, nor list it in the Release files (can be repeated)
+    #[argh(option)]
+    pub exclude_arch: Vec<String>,
+    /// leave the specified package out of the regenerated Packages and Contents files (can be repeated)
+    #[argh(option)]
+    pub exclude_package: Vec<String>,
+    /// do not sign the Release files even if a certificate is configured
+    #[argh(switch)]
+    pub no_sign: bool,
+    /// regenerate all the branches regardless of whether they have changed
+    #[argh(switch)]
+    pub force: bool,
+    /// place the generated dists tree under this directory instead of the mirror root
+    #[argh(option)]
+    pub output_dir: Option<String>,
+    /// fail if any of the Release files can't be generated or verified
+    #[argh(switch)]
+    pub strict: bool,
+    /// only regenerate the Contents files, leaving the Packages and Release files untouched
+    #[argh(switch)]
+    pub contents_only: bool,
+    /// check the regenerated Packages indices afterwards: they must parse, and list the packages in the pool with their actual size and checksum
+    #[argh(switch)]
+    pub validate: bool,
+    /// only regenerate (and sign) the Release files of the specified branch from its existing dists, leaving the indices untouched
+    #[argh(option)]
+    pub branch: Option<String>,
+}
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// run maintenance phase only: run database maintenance
@@ -20,10 +75,17 @@ pub(crate) struct PVectorMaintenance {}
 #[argh(subcommand, name = "reset")]
 pub(crate) struct PVectorReset {}
 
-#[derive(FromArgs, PartialEq, Debug)]
+#[derive(FromArgs, PartialEq, Debug, Default)]
 /// run gc phase only: remove all the deleted branches
 #[argh(subcommand, name = "gc")]
-pub(crate) struct PVectorGC {}
+pub(crate) struct PVectorGC {
+    /// only collect garbage in the specified branch
+    #[argh(option)]
+    pub branch: Option<String>,
+    /// only collect garbage in the specified component
+    #[argh(option)]
+    pub component: Option<String>,
+}
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// run a full cycle: equals to running scan, release, sync, analyze and gc
@@ -33,7 +95,122 @@ pub(crate) struct PVectorFullCycle {}
 #[derive(FromArgs, PartialEq, Debug)]
 /// generate a new certificate used for signing
 #[argh(subcommand, name = "gen-key")]
-pub(crate) struct PVectorGenKey {}
+pub(crate) struct PVectorGenKey {
+    /// key algorithm to use: ed25519 (default) or rsa4096
+    #[argh(option, default = "KeyAlgorithm::default()")]
+    pub algo: KeyAlgorithm,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// export the list of all the known packages as JSON
+#[argh(subcommand, name = "export")]
+pub(crate) struct PVectorExport {
+    /// only export the packages whose maintainer contains this string (e.g. a name or an email, case-insensitive)
+    #[argh(option)]
+    pub maintainer: Option<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// show the status of the repository (e.g. packages that failed to scan)
+#[argh(subcommand, name = "status")]
+pub(crate) struct PVectorStatus {
+    /// only show the packages whose maintainer contains this string (e.g. a name or an email, case-insensitive)
+    #[argh(option)]
+    pub maintainer: Option<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// compare the published Packages indices of a component against the database
+#[argh(subcommand, name = "audit")]
+pub(crate) struct PVectorAudit {
+    /// component to audit (e.g. `stable/main`)
+    #[argh(positional)]
+    pub component: String,
+    /// read the specified Packages file instead of the published indices
+    #[argh(option)]
+    pub index: Option<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// report the packages requiring shared libraries that no package provides
+#[argh(subcommand, name = "so-breaks")]
+pub(crate) struct PVectorSoBreaks {
+    /// only report the specified branch
+    #[argh(option)]
+    pub branch: Option<String>,
+    /// write one report per branch (`<branch>.txt`) to this directory instead of printing them
+    #[argh(option)]
+    pub output_dir: Option<String>,
+    /// only report the packages whose maintainer contains this string (e.g. a name or an email, case-insensitive)
+    #[argh(option)]
+    pub maintainer: Option<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// report the ELF files with non-standard interpreters or insecure library search paths (RPATH/RUNPATH)
+#[argh(subcommand, name = "elf-issues")]
+pub(crate) struct PVectorElfIssues {
+    /// only report the specified branch
+    #[argh(option)]
+    pub branch: Option<String>,
+    /// write one report per branch (`<branch>.txt`) to this directory instead of printing them
+    #[argh(option)]
+    pub output_dir: Option<String>,
+    /// only report the packages whose maintainer contains this string (e.g. a name or an email, case-insensitive)
+    #[argh(option)]
+    pub maintainer: Option<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// list the shared libraries provided or required by the packages (as JSON)
+#[argh(subcommand, name = "sodep")]
+pub(crate) struct PVectorSodep {
+    /// list the shared libraries provided by the packages
+    #[argh(switch)]
+    pub provides: bool,
+    /// list the shared libraries required by the packages
+    #[argh(switch)]
+    pub requires: bool,
+    /// only list the specified branch
+    #[argh(option)]
+    pub branch: Option<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// print the effective configuration (with defaults applied and secrets redacted)
+#[argh(subcommand, name = "print-config")]
+pub(crate) struct PVectorPrintConfig {
+    /// print the configuration as JSON instead of TOML
+    #[argh(switch)]
+    pub json: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// import the packages listed in existing Packages indices into the database (without their files), to be backfilled by later scans
+#[argh(subcommand, name = "import")]
+pub(crate) struct PVectorImport {
+    /// uncompressed Packages files to import
+    #[argh(positional)]
+    pub index: Vec<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// mark a branch as deprecated: its indices are still served, but its Release files carry a deprecation notice
+#[argh(subcommand, name = "deprecate")]
+pub(crate) struct PVectorDeprecate {
+    /// branch to deprecate
+    #[argh(positional)]
+    pub branch: String,
+    /// deprecation notice shown in the Release description (e.g. what to use instead)
+    #[argh(option)]
+    pub notice: Option<String>,
+    /// remove the deprecation mark instead
+    #[argh(switch)]
+    pub undo: bool,
+    /// do not sign the regenerated Release files even if a certificate is configured
+    #[argh(switch)]
+    pub no_sign: bool,
+}
 
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand)]
@@ -45,14 +222,26 @@ pub(crate) enum PVectorCommand {
     GC(PVectorGC),
     Full(PVectorFullCycle),
     GenKey(PVectorGenKey),
+    Export(PVectorExport),
+    Status(PVectorStatus),
+    PrintConfig(PVectorPrintConfig),
+    Audit(PVectorAudit),
+    SoBreaks(PVectorSoBreaks),
+    ElfIssues(PVectorElfIssues),
+    Sodep(PVectorSodep),
+    Import(PVectorImport),
+    Deprecate(PVectorDeprecate),
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// P-Vector: Scanner for deb packages
 pub(crate) struct PVector {
-    /// specify the location of the config file
+    /// specify the location of the config file (`-` to read from stdin)
     #[argh(option, short = 'c')]
     pub config: String,
+    /// override the database connection string (`db_pgconn`) of the config file
+    #[argh(option)]
+    pub database_url: Option<String>,
     #[argh(subcommand)]
     pub command: PVectorCommand,
 }
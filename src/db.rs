@@ -1,13 +1,33 @@
 use std::collections::HashSet;
+use std::future::Future;
 use std::path::Path;
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use log::{error, info};
-use sqlx::{Executor, PgPool};
+use sqlx::postgres::PgPoolOptions;
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+
+use crate::scan::PackageEntry;
 
 const PV_RS_SQL_SCRIPT_PV: &str = include_str!("../migrations/20210621205620_pv-base.down.sql");
 const PV_RS_SQL_SCRIPT_AB: &str = include_str!("../migrations/20210621205247_abbsdb-base.down.sql");
+const PV_RS_SQL_SCRIPT_SCAN_ERRORS: &str =
+    include_str!("../migrations/20261015084512_add-scan-errors-table.down.sql");
+const PV_RS_SQL_SCRIPT_PACKAGE_TAGS: &str =
+    include_str!("../migrations/20261016103045_add-package-tags-table.down.sql");
+const PV_RS_SQL_SCRIPT_PACKAGE_EXTRA_FIELDS: &str =
+    include_str!("../migrations/20261016170530_add-package-extra-fields-table.down.sql");
+const PV_RS_SQL_SCRIPT_PENDING_NOTIFICATIONS: &str =
+    include_str!("../migrations/20261016135020_add-pending-notifications-table.down.sql");
+const PV_RS_SQL_SCRIPT_PACKAGE_CONTENTS: &str =
+    include_str!("../migrations/20261016150240_add-package-contents-table.down.sql");
+const PV_RS_SQL_SCRIPT_SCAN_QUEUE: &str =
+    include_str!("../migrations/20261016190415_add-scan-queue-table.down.sql");
+const PV_RS_SQL_SCRIPT_PACKAGE_ELF: &str =
+    include_str!("../migrations/20261016201230_add-package-elf-table.down.sql");
 
+#[allow(dead_code)]
 pub struct PVPackage {
     pub package: Option<String>,
     pub version: Option<String>,
@@ -17,6 +37,7 @@ pub struct PVPackage {
     pub size: Option<i64>,
     pub mtime: Option<i32>,
     pub sha256: Option<String>,
+    pub imported: Option<bool>,
 }
 
 /// Run all the pending migrations in `migrations` directory
@@ -24,9 +45,133 @@ pub async fn run_migrate(pool: &PgPool) -> Result<()> {
     Ok(sqlx::migrate!().run(pool).await?)
 }
 
-/// Connect to the database
-pub async fn connect_database(connspec: &str) -> Result<PgPool> {
-    Ok(PgPool::connect(connspec).await?)
+/// Migration creating the version comparison functions
+const VERCOMP_MIGRATION: &str = "migrations/20210621205250_vercomp.up.sql";
+
+/// Check that the version comparison functions created by the migrations are usable. They can
+/// go missing while the migrations are recorded as applied, e.g. after restoring a dump
+/// without its functions, which otherwise only surfaces as opaque errors while saving packages.
+pub async fn check_functions(pool: &PgPool) -> Result<()> {
+    let exists = sqlx::query_scalar!(
+        r#"SELECT to_regprocedure('comparable_dpkgver(text)') IS NOT NULL AS "exists!""#
+    )
+    .fetch_one(pool)
+    .await?;
+    if !exists {
+        return Err(anyhow!(
+            "The comparable_dpkgver function is missing from the database (was it restored from \
+a dump without its functions?). Recreate it by running {} from the p-vector sources \
+(`psql -d <database> -f {}`).",
+            VERCOMP_MIGRATION,
+            VERCOMP_MIGRATION
+        ));
+    }
+    sqlx::query_scalar!("SELECT comparable_dpkgver('1:2.0-1')")
+        .fetch_one(pool)
+        .await
+        .map_err(|e| {
+            anyhow!(
+                "The comparable_dpkgver function is broken ({}). Recreate it by running {} \
+from the p-vector sources.",
+                e,
+                VERCOMP_MIGRATION
+            )
+        })?;
+
+    Ok(())
+}
+
+pub struct ScanError {
+    pub filename: String,
+    pub error: String,
+    pub last_seen: sqlx::types::chrono::DateTime<sqlx::types::chrono::Utc>,
+}
+
+/// Number of packages scanned by a p-vector version
+pub struct ScannerVersionStat {
+    pub scanner_version: Option<String>,
+    pub packages: Option<i64>,
+    pub last_scanned: Option<sqlx::types::chrono::DateTime<sqlx::types::chrono::Utc>>,
+}
+
+/// Extra time given to the server to cancel a statement before the client gives up on it
+const CLIENT_TIMEOUT_GRACE: Duration = Duration::from_secs(5);
+
+/// Connect to the database. When `statement_timeout` (in seconds) is set, it is applied
+/// to every connection so that the server cancels statements running for longer.
+pub async fn connect_database(connspec: &str, statement_timeout: Option<u64>) -> Result<PgPool> {
+    let options = PgPoolOptions::new().after_connect(move |conn, _meta| {
+        Box::pin(async move {
+            if let Some(timeout) = statement_timeout {
+                conn.execute(format!("SET statement_timeout = {}", timeout * 1000).as_str())
+                    .await?;
+            }
+            Ok(())
+        })
+    });
+
+    Ok(options.connect(connspec).await?)
+}
+
+/// A database snapshot, importable by the other connections while it is held
+pub struct Snapshot {
+    /// the exporting transaction, the snapshot is gone once it ends
+    _holder: Transaction<'static, Postgres>,
+    pub id: String,
+}
+
+/// Export a snapshot of the current state of the database, so that queries on different
+/// connections can see the very same state (see [`begin_in_snapshot`])
+pub async fn export_snapshot(pool: &PgPool) -> Result<Snapshot> {
+    let mut tx = pool.begin().await?;
+    tx.execute("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ READ ONLY")
+        .await?;
+    let id = sqlx::query_scalar!("SELECT pg_export_snapshot()")
+        .fetch_one(&mut *tx)
+        .await?
+        .ok_or_else(|| anyhow!("Unable to export a database snapshot"))?;
+
+    Ok(Snapshot { _holder: tx, id })
+}
+
+/// Begin a transaction, seeing the exported snapshot (read-only) if there is one
+pub async fn begin_in_snapshot(
+    pool: &PgPool,
+    snapshot: Option<&str>,
+) -> Result<Transaction<'static, Postgres>> {
+    let mut tx = pool.begin().await?;
+    if let Some(id) = snapshot {
+        tx.execute("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ READ ONLY")
+            .await?;
+        tx.execute(format!("SET TRANSACTION SNAPSHOT '{}'", id.replace('\'', "''")).as_str())
+            .await?;
+    }
+
+    Ok(tx)
+}
+
+/// Client-side deadline of the queries, slightly longer than the statement timeout (in seconds)
+pub fn client_timeout(statement_timeout: Option<u64>) -> Option<Duration> {
+    statement_timeout.map(|t| Duration::from_secs(t) + CLIENT_TIMEOUT_GRACE)
+}
+
+/// Wait for a query, failing if it does not complete within `timeout` (e.g. when the
+/// connection is stuck and the server-side statement timeout can't fire)
+pub async fn with_timeout<T, E, F>(timeout: Option<Duration>, query: F) -> Result<T>
+where
+    E: Into<anyhow::Error>,
+    F: Future<Output = std::result::Result<T, E>>,
+{
+    let Some(timeout) = timeout else {
+        return query.await.map_err(Into::into);
+    };
+    match tokio::time::timeout(timeout, query).await {
+        Ok(result) => result.map_err(Into::into),
+        Err(_) => Err(anyhow!(
+            "Query timed out after {} seconds",
+            timeout.as_secs()
+        )),
+    }
 }
 
 /// Run database maintenance
@@ -50,6 +195,13 @@ pub async fn reset_database(pool: &PgPool) -> Result<()> {
         .await?;
     info!("Resetting p-vector tables ...");
     tx.execute(PV_RS_SQL_SCRIPT_PV).await?;
+    tx.execute(PV_RS_SQL_SCRIPT_SCAN_ERRORS).await?;
+    tx.execute(PV_RS_SQL_SCRIPT_PACKAGE_TAGS).await?;
+    tx.execute(PV_RS_SQL_SCRIPT_PACKAGE_EXTRA_FIELDS).await?;
+    tx.execute(PV_RS_SQL_SCRIPT_PACKAGE_ELF).await?;
+    tx.execute(PV_RS_SQL_SCRIPT_PACKAGE_CONTENTS).await?;
+    tx.execute(PV_RS_SQL_SCRIPT_PENDING_NOTIFICATIONS).await?;
+    tx.execute(PV_RS_SQL_SCRIPT_SCAN_QUEUE).await?;
     info!("Resetting abbs sync tables ...");
     tx.execute(PV_RS_SQL_SCRIPT_AB).await?;
     tx.commit().await?;
@@ -64,10 +216,10 @@ pub async fn reset_database(pool: &PgPool) -> Result<()> {
 pub async fn list_packages_in_component(pool: &PgPool, component: &str) -> Result<Vec<PVPackage>> {
     let records = sqlx::query_as!(
         PVPackage,
-r#"SELECT p.package, p.version, p.repo, p.architecture, p.filename, p.size, p.mtime, p.sha256
+r#"SELECT p.package, p.version, p.repo, p.architecture, p.filename, p.size, p.mtime, p.sha256, p.imported
 FROM pv_packages p INNER JOIN pv_repos r ON p.repo=r.name WHERE r.path=$1
 UNION ALL
-SELECT p.package, p.version, p.repo, p.architecture, p.filename, p.size, p.mtime, p.sha256
+SELECT p.package, p.version, p.repo, p.architecture, p.filename, p.size, p.mtime, p.sha256, p.imported
 FROM pv_package_duplicate p INNER JOIN pv_repos r ON p.repo=r.name WHERE r.path=$1"#,
         component
     ).fetch_all(pool).await?;
@@ -75,6 +227,15 @@ FROM pv_package_duplicate p INNER JOIN pv_repos r ON p.repo=r.name WHERE r.path=
     Ok(records)
 }
 
+/// List all the known branches
+pub async fn list_branches(pool: &PgPool) -> Result<Vec<String>> {
+    let records = sqlx::query!("SELECT DISTINCT branch FROM pv_repos ORDER BY branch")
+        .fetch_all(pool)
+        .await?;
+
+    Ok(records.into_iter().map(|r| r.branch).collect())
+}
+
 /// Generate notifying messages for removed packages
 pub async fn get_removed_packages_message<P: AsRef<Path>>(
     pool: &PgPool,
@@ -103,30 +264,181 @@ pub async fn get_removed_packages_message<P: AsRef<Path>>(
 }
 
 pub async fn remove_packages_by_path<P: AsRef<Path>>(pool: &PgPool, path: &[P]) -> Result<()> {
+    let paths = path
+        .iter()
+        .map(|p| p.as_ref().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
     let mut tx = pool.begin().await?;
-    let mut changed_repos = HashSet::new();
-    for path in path {
-        let path = path.as_ref().to_string_lossy();
-        let p = sqlx::query!(
-            "DELETE FROM pv_packages WHERE filename = $1 RETURNING repo",
-            path.as_ref()
-        )
-        .fetch_optional(&mut *tx)
+    let removed = sqlx::query!(
+        "DELETE FROM pv_packages WHERE filename = ANY($1) RETURNING repo",
+        &paths
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let changed_repos = removed
+        .into_iter()
+        .map(|p| p.repo)
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    sqlx::query!(
+        "UPDATE pv_repos SET mtime=now() WHERE name = ANY($1)",
+        &changed_repos
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// List all the packages that failed to scan in the previous runs
+pub async fn list_scan_errors(pool: &PgPool) -> Result<Vec<ScanError>> {
+    let records = sqlx::query_as!(
+        ScanError,
+        "SELECT filename, error, last_seen FROM pv_scan_errors ORDER BY filename"
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(records)
+}
+
+/// Count the packages by the version of p-vector that scanned them, optionally only
+/// the ones whose maintainer contains `maintainer` (case-insensitive)
+pub async fn list_scanner_versions(
+    pool: &PgPool,
+    maintainer: Option<&str>,
+) -> Result<Vec<ScannerVersionStat>> {
+    let records = sqlx::query_as!(
+        ScannerVersionStat,
+        "SELECT scanner_version, count(*) AS packages, max(scanned_at) AS last_scanned FROM pv_packages
+WHERE $1::TEXT IS NULL OR strpos(lower(maintainer), lower($1)) > 0
+GROUP BY scanner_version ORDER BY last_scanned DESC NULLS LAST",
+        maintainer
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(records)
+}
+
+/// A package file duplicating the same version of a package in the same repository
+pub struct DuplicatePackage {
+    pub filename: String,
+    pub package: String,
+    pub version: String,
+}
+
+/// List the duplicated package files, optionally only the ones whose maintainer
+/// contains `maintainer` (case-insensitive)
+pub async fn list_duplicates(
+    pool: &PgPool,
+    maintainer: Option<&str>,
+) -> Result<Vec<DuplicatePackage>> {
+    let records = sqlx::query_as!(
+        DuplicatePackage,
+        "SELECT filename, package, version FROM pv_package_duplicate
+WHERE $1::TEXT IS NULL OR strpos(lower(maintainer), lower($1)) > 0
+ORDER BY filename",
+        maintainer
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(records)
+}
+
+/// A change notification that could not be delivered
+pub struct PendingNotification {
+    pub id: i64,
+    pub payload: String,
+}
+
+/// List the undelivered change notifications, oldest first
+pub async fn list_pending_notifications(pool: &PgPool) -> Result<Vec<PendingNotification>> {
+    let records = sqlx::query_as!(
+        PendingNotification,
+        "SELECT id, payload FROM pv_pending_notifications ORDER BY id"
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(records)
+}
+
+/// Queue the change notifications for delivery in the next run
+pub async fn add_pending_notifications(pool: &PgPool, payloads: &[String]) -> Result<()> {
+    sqlx::query!(
+        "INSERT INTO pv_pending_notifications (payload) SELECT * FROM unnest($1::TEXT[])",
+        payloads
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Remove the delivered change notifications from the queue
+pub async fn remove_pending_notifications(pool: &PgPool, ids: &[i64]) -> Result<()> {
+    sqlx::query!(
+        "DELETE FROM pv_pending_notifications WHERE id = ANY($1)",
+        ids
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// List the packages left in the scan queue by an interrupted scan
+pub async fn list_scan_queue(pool: &PgPool) -> Result<Vec<String>> {
+    let records = sqlx::query!("SELECT filename FROM pv_scan_queue ORDER BY filename")
+        .fetch_all(pool)
         .await?;
-        if let Some(p) = p {
-            changed_repos.insert(p.repo);
-        }
-    }
-    for b in changed_repos {
-        sqlx::query!("UPDATE pv_repos SET mtime=now() WHERE name = $1", b)
-            .execute(&mut *tx)
-            .await?;
-    }
+
+    Ok(records.into_iter().map(|r| r.filename).collect())
+}
+
+/// Queue the packages to be scanned, replacing the previous queue. Entries are removed
+/// when the packages are saved (see `save_packages_to_db`) or fail to scan.
+pub async fn set_scan_queue(pool: &PgPool, entries: &[&PackageEntry]) -> Result<()> {
+    let filenames = entries
+        .iter()
+        .map(|e| e.filename.clone())
+        .collect::<Vec<_>>();
+    let sizes = entries.iter().map(|e| e.size as i64).collect::<Vec<_>>();
+    let mtimes = entries.iter().map(|e| e.mtime as i64).collect::<Vec<_>>();
+    let mut tx = pool.begin().await?;
+    sqlx::query!("DELETE FROM pv_scan_queue")
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!(
+        "INSERT INTO pv_scan_queue (filename, size, mtime)
+SELECT * FROM unnest($1::TEXT[], $2::BIGINT[], $3::BIGINT[])",
+        &filenames,
+        &sizes,
+        &mtimes
+    )
+    .execute(&mut *tx)
+    .await?;
     tx.commit().await?;
 
     Ok(())
 }
 
+/// Remove the packages from the scan queue
+pub async fn remove_from_scan_queue(pool: &PgPool, filenames: &[String]) -> Result<()> {
+    sqlx::query!(
+        "DELETE FROM pv_scan_queue WHERE filename = ANY($1)",
+        filenames
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
 /// Refresh materialized views
 pub async fn refresh_views(pool: &PgPool) -> Result<()> {
     sqlx::query!("REFRESH MATERIALIZED VIEW v_packages_new")
@@ -144,3 +456,17 @@ pub async fn refresh_views(pool: &PgPool) -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_with_timeout() {
+    let timeout = Some(Duration::from_millis(10));
+    let result = with_timeout(timeout, async { Ok::<_, anyhow::Error>(1) }).await;
+    assert_eq!(result.unwrap(), 1);
+    let result = with_timeout(timeout, async {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        Ok::<_, anyhow::Error>(1)
+    })
+    .await;
+    assert!(result.is_err());
+    assert_eq!(client_timeout(Some(60)), Some(Duration::from_secs(65)));
+}
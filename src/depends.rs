@@ -0,0 +1,167 @@
+use anyhow::{anyhow, bail, Result};
+use serde::Serialize;
+
+/// A single package reference in a relationship field, e.g. `gcc-runtime:amd64 (>= 13.2.0) [amd64]`
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub struct Dependency {
+    pub package: String,
+    /// architecture qualifier after the colon, e.g. `any` or `native`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arch_qualifier: Option<String>,
+    /// version relation, one of `<<`, `<=`, `=`, `>=` or `>>`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub relation: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// architecture restriction list, e.g. `[amd64 !arm64]`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub architectures: Vec<String>,
+}
+
+/// A list of alternatives (`a | b`), any of which satisfies the clause
+pub type Clause = Vec<Dependency>;
+
+const VERSION_RELATIONS: &[&str] = &["<<", "<=", "=", ">=", ">>"];
+
+fn is_valid_package_name(name: &str) -> bool {
+    name.len() >= 2
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '+' | '-'))
+}
+
+fn parse_dependency(input: &str) -> Result<Dependency> {
+    let input = input.trim();
+    // the name ends at the first whitespace or opening bracket
+    let name_end = input
+        .find(|c: char| c.is_whitespace() || matches!(c, '(' | '[' | '<'))
+        .unwrap_or(input.len());
+    let (name, mut rest) = input.split_at(name_end);
+    let (package, arch_qualifier) = match name.split_once(':') {
+        Some((package, arch)) => (package, Some(arch.to_string())),
+        None => (name, None),
+    };
+    if !is_valid_package_name(package) {
+        bail!("Invalid package name in dependency: {:?}", input);
+    }
+    let mut dependency = Dependency {
+        package: package.to_string(),
+        arch_qualifier,
+        ..Default::default()
+    };
+    rest = rest.trim_start();
+    if let Some(version) = rest.strip_prefix('(') {
+        let (constraint, remaining) = version
+            .split_once(')')
+            .ok_or_else(|| anyhow!("Unterminated version constraint: {:?}", input))?;
+        let constraint = constraint.trim();
+        let relation_end = constraint
+            .find(|c| !matches!(c, '<' | '=' | '>'))
+            .unwrap_or(constraint.len());
+        let (relation, version) = constraint.split_at(relation_end);
+        if !VERSION_RELATIONS.contains(&relation) {
+            bail!("Invalid version relation {:?} in {:?}", relation, input);
+        }
+        let version = version.trim();
+        if version.is_empty() {
+            bail!("Missing version in {:?}", input);
+        }
+        dependency.relation = Some(relation.to_string());
+        dependency.version = Some(version.to_string());
+        rest = remaining.trim_start();
+    }
+    if let Some(architectures) = rest.strip_prefix('[') {
+        let (architectures, remaining) = architectures
+            .split_once(']')
+            .ok_or_else(|| anyhow!("Unterminated architecture list: {:?}", input))?;
+        dependency.architectures = architectures
+            .split_whitespace()
+            .map(|s| s.to_string())
+            .collect();
+        rest = remaining.trim_start();
+    }
+    // build profile restrictions only matter for source packages, skip them
+    while let Some(profiles) = rest.strip_prefix('<') {
+        let (_, remaining) = profiles
+            .split_once('>')
+            .ok_or_else(|| anyhow!("Unterminated build profile: {:?}", input))?;
+        rest = remaining.trim_start();
+    }
+    if !rest.is_empty() {
+        bail!("Trailing characters in dependency: {:?}", input);
+    }
+
+    Ok(dependency)
+}
+
+/// Parse the value of a relationship field (Depends, Breaks, etc.) into clauses of alternatives
+pub fn parse_relationship(value: &str) -> Result<Vec<Clause>> {
+    value
+        .split(',')
+        .map(|clause| clause.trim())
+        .filter(|clause| !clause.is_empty())
+        .map(|clause| clause.split('|').map(parse_dependency).collect())
+        .collect()
+}
+
+#[test]
+fn test_parse_simple() {
+    let clauses = parse_relationship("glibc, gcc-runtime (>= 13.2.0-2)").unwrap();
+    assert_eq!(clauses.len(), 2);
+    assert_eq!(
+        clauses[0],
+        vec![Dependency {
+            package: "glibc".to_string(),
+            ..Default::default()
+        }]
+    );
+    assert_eq!(
+        clauses[1],
+        vec![Dependency {
+            package: "gcc-runtime".to_string(),
+            relation: Some(">=".to_string()),
+            version: Some("13.2.0-2".to_string()),
+            ..Default::default()
+        }]
+    );
+}
+
+#[test]
+fn test_parse_alternatives() {
+    let clauses =
+        parse_relationship("python-3:any (>>3.10) | python-2 [amd64 !arm64], glibc (>= 1:2.37-1)")
+            .unwrap();
+    assert_eq!(clauses.len(), 2);
+    assert_eq!(
+        clauses[0],
+        vec![
+            Dependency {
+                package: "python-3".to_string(),
+                arch_qualifier: Some("any".to_string()),
+                relation: Some(">>".to_string()),
+                version: Some("3.10".to_string()),
+                ..Default::default()
+            },
+            Dependency {
+                package: "python-2".to_string(),
+                architectures: vec!["amd64".to_string(), "!arm64".to_string()],
+                ..Default::default()
+            }
+        ]
+    );
+    assert_eq!(clauses[1][0].version.as_deref(), Some("1:2.37-1"));
+    assert_eq!(
+        serde_json::to_string(&clauses[1]).unwrap(),
+        r#"[{"package":"glibc","relation":">=","version":"1:2.37-1"}]"#
+    );
+}
+
+#[test]
+fn test_parse_invalid() {
+    assert!(parse_relationship("glibc (>= )").is_err());
+    assert!(parse_relationship("glibc (~ 1.0)").is_err());
+    assert!(parse_relationship("glibc (>= 1.0").is_err());
+    assert!(parse_relationship("a").is_err());
+    assert!(parse_relationship("glibc foo").is_err());
+    assert!(parse_relationship("").unwrap().is_empty());
+}
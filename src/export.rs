@@ -0,0 +1,70 @@
+//! Package list export module
+
+use anyhow::Result;
+use serde::Serialize;
+use sqlx::PgPool;
+
+use crate::config::GeneralConfig;
+
+struct PackageRecord {
+    branch: String,
+    component: String,
+    package: String,
+    version: String,
+    architecture: String,
+    maintainer: String,
+    filename: String,
+    size: i64,
+    sha256: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ExportedPackage {
+    branch: String,
+    component: String,
+    package: String,
+    version: String,
+    architecture: String,
+    maintainer: String,
+    filename: String,
+    size: i64,
+    sha256: String,
+    /// Full download URL, only available when `mirror_base_url` is configured
+    url: Option<String>,
+}
+
+/// List all the known packages in the database for exporting, optionally only
+/// the ones whose maintainer contains `maintainer` (case-insensitive)
+pub async fn export_packages(
+    pool: &PgPool,
+    config: &GeneralConfig,
+    maintainer: Option<&str>,
+) -> Result<Vec<ExportedPackage>> {
+    let records = sqlx::query_as!(
+        PackageRecord,
+        r#"SELECT r.branch, r.component, p.package, p.version, p.architecture,
+    p.maintainer, p.filename, p.size, p.sha256
+FROM pv_packages p INNER JOIN pv_repos r ON p.repo = r.name
+WHERE $1::TEXT IS NULL OR strpos(lower(p.maintainer), lower($1)) > 0
+ORDER BY r.branch, r.component, p.package, p._vercomp"#,
+        maintainer
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(records
+        .into_iter()
+        .map(|r| ExportedPackage {
+            url: config.package_url(&r.filename),
+            branch: r.branch,
+            component: r.component,
+            package: r.package,
+            version: r.version,
+            architecture: r.architecture,
+            maintainer: r.maintainer,
+            filename: r.filename,
+            size: r.size,
+            sha256: r.sha256,
+        })
+        .collect())
+}
@@ -5,19 +5,45 @@ use std::path::Path;
 use anyhow::Result;
 use log::{error, info};
 use sqlx::PgPool;
-use tokio::fs::{remove_dir, remove_dir_all, remove_file};
+use tokio::fs::{metadata, read_dir, remove_dir, remove_dir_all, remove_file, symlink_metadata};
 
-/// List all the known branches in the database
-async fn list_existing_branches(pool: &PgPool) -> Result<Vec<String>> {
-    let records = sqlx::query!("SELECT DISTINCT path FROM pv_repos")
-        .fetch_all(pool)
-        .await?;
+/// List all the known branches in the database, optionally limited to the specified branch and component
+async fn list_existing_branches(
+    pool: &PgPool,
+    branch: Option<&str>,
+    component: Option<&str>,
+) -> Result<Vec<String>> {
+    let records = sqlx::query!(
+        "SELECT DISTINCT path FROM pv_repos WHERE ($1::TEXT IS NULL OR branch = $1) AND ($2::TEXT IS NULL OR component = $2)",
+        branch,
+        component
+    )
+    .fetch_all(pool)
+    .await?;
     let results = records.into_iter().map(|x| x.path).collect::<Vec<_>>();
 
     Ok(results)
 }
 
-async fn clean_dist_files(to_remove: &[&String], mirror_root: &Path) {
+/// List the branches of the removed paths (branch/component) without any repos left in
+/// `remaining`, whose Release files have to go as well
+fn list_removed_branches<'a>(to_remove: &[&'a String], remaining: &[String]) -> Vec<&'a str> {
+    let mut branches = to_remove
+        .iter()
+        .filter_map(|path| path.split('/').next())
+        .filter(|branch| {
+            !remaining
+                .iter()
+                .any(|path| path.split('/').next() == Some(branch))
+        })
+        .collect::<Vec<_>>();
+    branches.sort_unstable();
+    branches.dedup();
+
+    branches
+}
+
+async fn clean_dist_files(to_remove: &[&String], remaining: &[String], mirror_root: &Path) {
     let mut tasks = Vec::new();
     for remove in to_remove {
         tasks.push(async move {
@@ -26,40 +52,83 @@ async fn clean_dist_files(to_remove: &[&String], mirror_root: &Path) {
             if let Err(e) = remove_dir_all(&path).await {
                 error!("Failed to remove \"{}\": {}", remove, e);
             }
-            if let Some(p) = path.parent() {
-                // remove the inrelease file
-                remove_file(p.join("InRelease")).await.ok();
-                // remove the parent directory if it's empty
-                remove_dir(p).await.ok();
-            }
         });
     }
     futures::future::join_all(tasks).await;
+    // the other components of a branch still need its Release files
+    for branch in list_removed_branches(to_remove, remaining) {
+        let p = mirror_root.join("dists").join(branch);
+        // remove the (unsigned) release file
+        remove_file(p.join("InRelease")).await.ok();
+        remove_file(p.join("Release")).await.ok();
+        // remove the branch directory if it's empty
+        remove_dir(p).await.ok();
+    }
 }
 
-async fn clean_removed_main_branches(pool: &PgPool) -> Result<()> {
-    sqlx::query!(
+/// Remove the codename links in the dists directory whose branches are gone
+async fn clean_dangling_links(mirror_root: &Path) -> Result<()> {
+    let dists = mirror_root.join("dists");
+    if !dists.is_dir() {
+        return Ok(());
+    }
+    let mut entries = read_dir(dists).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if symlink_metadata(&path).await?.file_type().is_symlink() && metadata(&path).await.is_err()
+        {
+            info!("Deleting dangling link: {} ...", path.display());
+            remove_file(&path).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove the repos without any packages from the database, returning the paths
+/// (branch/component) that no longer have any repos left
+async fn clean_removed_main_branches(
+    pool: &PgPool,
+    branch: Option<&str>,
+    component: Option<&str>,
+) -> Result<Vec<String>> {
+    let records = sqlx::query!(
         "WITH deleted_branches AS (
     SELECT r.name FROM pv_repos r
     LEFT JOIN pv_packages p ON p.repo = r.name
+    WHERE ($1::TEXT IS NULL OR r.branch = $1) AND ($2::TEXT IS NULL OR r.component = $2)
     GROUP BY r.name HAVING COUNT(DISTINCT p.package) < 1
+), deleted AS (
+    DELETE FROM pv_repos USING deleted_branches
+    WHERE pv_repos.name = deleted_branches.name
+    RETURNING pv_repos.path
 )
-DELETE FROM pv_repos USING deleted_branches
-WHERE pv_repos.name = deleted_branches.name"
+SELECT DISTINCT d.path FROM deleted d WHERE NOT EXISTS (
+    SELECT 1 FROM pv_repos r WHERE r.path = d.path
+    AND r.name NOT IN (SELECT name FROM deleted_branches)
+)",
+        branch,
+        component
     )
-    .execute(pool)
+    .fetch_all(pool)
     .await?;
 
-    Ok(())
+    Ok(records.into_iter().map(|x| x.path).collect())
 }
 
-/// Execute garbage collection
-pub async fn run_gc<P: AsRef<Path>>(pool: &PgPool, mirror_root: P) -> Result<()> {
+/// Execute garbage collection. When `branch` or `component` is set, only the matching
+/// branches are considered, leaving the others untouched.
+pub async fn run_gc<P: AsRef<Path>>(
+    pool: &PgPool,
+    mirror_root: P,
+    branch: Option<&str>,
+    component: Option<&str>,
+) -> Result<()> {
     info!("Deleting duplicated and stale entries from the database ...");
     sqlx::query!("DELETE FROM pv_package_duplicate USING pv_packages WHERE pv_package_duplicate.filename = pv_packages.filename").execute(pool).await?;
-    clean_removed_main_branches(pool).await?;
-    let known_branches = list_existing_branches(pool).await?;
-    let to_remove = known_branches
+    let emptied = clean_removed_main_branches(pool, branch, component).await?;
+    let known_branches = list_existing_branches(pool, branch, component).await?;
+    let mut to_remove = known_branches
         .iter()
         .filter(|branch| {
             let path = mirror_root.as_ref().join("pool").join(branch);
@@ -67,6 +136,8 @@ pub async fn run_gc<P: AsRef<Path>>(pool: &PgPool, mirror_root: P) -> Result<()>
             !path.is_dir()
         })
         .collect::<Vec<_>>();
+    // branches without any packages left have their dists removed as well
+    to_remove.extend(emptied.iter());
     // exit early if no changes
     if to_remove.is_empty() {
         info!("Nothing to do.");
@@ -83,8 +154,24 @@ pub async fn run_gc<P: AsRef<Path>>(pool: &PgPool, mirror_root: P) -> Result<()>
             .execute(pool)
             .await?;
     }
-    clean_dist_files(&to_remove, mirror_root.as_ref()).await;
+    let remaining = list_existing_branches(pool, None, None).await?;
+    clean_dist_files(&to_remove, &remaining, mirror_root.as_ref()).await;
+    if let Err(e) = clean_dangling_links(mirror_root.as_ref()).await {
+        error!("Failed to remove the dangling codename links: {}", e);
+    }
     info!("GC finished.");
 
     Ok(())
 }
+
+#[test]
+fn test_list_removed_branches() {
+    let paths = ["stable/bsp", "topic/main", "topic/bsp", "old/main"].map(String::from);
+    let to_remove = paths.iter().collect::<Vec<_>>();
+    let remaining = ["stable/main", "stable/contrib"].map(String::from);
+    assert_eq!(
+        list_removed_branches(&to_remove, &remaining),
+        ["old", "topic"]
+    );
+    assert!(list_removed_branches(&[], &remaining).is_empty());
+}
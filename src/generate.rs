@@ -1,30 +1,93 @@
 //! Release file generation module
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::io::Write;
-use std::path::Path;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::{anyhow, Error, Result};
 use async_compression::tokio::write::{GzipEncoder, XzEncoder, ZstdEncoder};
+use async_compression::Level;
+use glob::Pattern;
 use log::{error, info, warn};
 use nom::bytes::complete::{tag, take_until};
 use nom::sequence::preceded;
 use nom::IResult;
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 use sailfish::TemplateSimple;
+use serde::Serialize;
 use serde_json::Value;
 use sqlx::PgPool;
-use time::{format_description::well_known::Rfc2822, macros::offset};
+use time::format_description::well_known::Rfc2822;
+use time::macros::{format_description, offset};
 use tokio::fs::{create_dir_all, metadata, File};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::task::spawn_blocking;
 
-use crate::config::ReleaseConfig;
-use crate::scan::{mtime, sha256sum};
-use crate::sign::{load_certificate, sign_message, sign_message_agent};
+use crate::config::{select_compression_tier, PackagesFormat, ReleaseConfig};
+use crate::db::{begin_in_snapshot, with_timeout};
+use crate::pdiff;
+use crate::scan::{mtime, HashedReader};
+use crate::sign::{
+    export_public_keys, load_certificates, sign_message, sign_message_agent, verify_message,
+};
+use crate::status::set_status;
+use crate::template::Templates;
 
-#[derive(Clone, Debug)]
+/// Bytes written for the generated indices during a run
+#[derive(Default, Debug)]
+pub struct GenerationStats {
+    packages: AtomicU64,
+    packages_xz: AtomicU64,
+    packages_zst: AtomicU64,
+    contents: AtomicU64,
+    contents_gz: AtomicU64,
+    contents_zst: AtomicU64,
+}
+
+impl GenerationStats {
+    /// Summary lines of the bytes written per format, and the compression ratios
+    pub fn summary(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        for (name, uncompressed, compressed) in [
+            (
+                "Packages",
+                &self.packages,
+                [("xz", &self.packages_xz), ("zst", &self.packages_zst)],
+            ),
+            (
+                "Contents",
+                &self.contents,
+                [("gz", &self.contents_gz), ("zst", &self.contents_zst)],
+            ),
+        ] {
+            let uncompressed = uncompressed.load(Ordering::Relaxed);
+            if uncompressed == 0 {
+                continue;
+            }
+            lines.push(format!("{}: {} bytes", name, uncompressed));
+            for (format, compressed) in compressed {
+                let compressed = compressed.load(Ordering::Relaxed);
+                if compressed == 0 {
+                    continue;
+                }
+                lines.push(format!(
+                    "{}.{}: {} bytes (ratio {:.2}%)",
+                    name,
+                    format,
+                    compressed,
+                    compressed as f64 / uncompressed as f64 * 100.0
+                ));
+            }
+        }
+
+        lines
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
 struct PackageTemplate {
     name: String,
     version: String,
@@ -36,17 +99,24 @@ struct PackageTemplate {
     size: Option<i64>,
     sha256: Option<String>,
     description: Option<String>,
+    description_body: Option<String>,
     dep: Option<Value>,
     features: Option<String>,
+    built_using: Option<String>,
+    homepage: Option<String>,
+    bugs: Option<String>,
+    origin: Option<String>,
+    tag: Option<String>,
+    extra_fields: Option<Value>,
 }
 
-#[derive(TemplateSimple)]
+#[derive(TemplateSimple, Serialize)]
 #[template(path = "Packages.stpl")]
 struct PackagesTemplate {
     packages: Vec<PackageTemplate>,
 }
 
-#[derive(TemplateSimple)]
+#[derive(TemplateSimple, Serialize)]
 #[template(path = "InRelease.stpl")]
 struct InReleaseTemplate {
     origin: String,
@@ -56,8 +126,11 @@ struct InReleaseTemplate {
     description: String,
     date: String,
     valid_until: String,
+    not_automatic: bool,
+    but_automatic_upgrades: bool,
     architectures: Vec<String>,
     components: Vec<String>,
+    changelogs: Option<String>,
     files: Vec<(String, u64, String)>,
 }
 
@@ -67,6 +140,23 @@ struct BranchMeta {
     comp: Option<Vec<String>>,
 }
 
+/// Check if the architecture is selected by the (optional) architecture filter and not excluded.
+/// `all` is always selected (unless excluded) since every architecture depends on it.
+fn is_arch_selected(arch: &str, filter: Option<&str>, excluded: &[String]) -> bool {
+    if excluded.iter().any(|e| e == arch) {
+        return false;
+    }
+    match filter {
+        Some(f) => arch == f || arch == "all",
+        None => true,
+    }
+}
+
+/// Check if the file path matches any of the exclusion patterns
+fn is_path_excluded(path: &str, excludes: &[Pattern]) -> bool {
+    excludes.iter().any(|p| p.matches(path))
+}
+
 fn match_valid_until(input: &[u8]) -> IResult<&[u8], &[u8]> {
     tag("Valid-Until: ")(input)
 }
@@ -83,19 +173,129 @@ fn parse_valid_date(input: &[u8]) -> IResult<&[u8], &[u8]> {
     preceded(skip_other, take_until("\n"))(input)
 }
 
-fn scan_single_release_file(branch_root: &Path, path: &Path) -> Result<(String, u64, String)> {
+fn parse_release_date(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    preceded(
+        preceded(take_until("\nDate: "), tag("\nDate: ")),
+        take_until("\n"),
+    )(input)
+}
+
+fn scan_single_release_file(
+    branch_root: &Path,
+    path: &Path,
+    pipelined_threshold: Option<u64>,
+) -> Result<(String, u64, String)> {
     use std::fs::File as StdFile;
-    use std::io::Seek;
 
-    let mut f = StdFile::open(path)?;
-    let sha256 = sha256sum(&f)?;
+    let f = StdFile::open(path)?;
+    let pipelined = pipelined_threshold.is_some_and(|t| f.metadata().is_ok_and(|m| m.len() > t));
+    let reader = HashedReader::new(f);
+    let digest = if pipelined {
+        reader.finish_pipelined()?
+    } else {
+        reader.finish()?
+    };
     let filename = path.strip_prefix(branch_root)?.to_string_lossy();
-    let length = f.stream_position()?;
 
-    Ok((filename.to_string(), length, sha256))
+    Ok((filename.to_string(), digest.bytes_read, digest.sha256))
+}
+
+/// The patches in `Packages.diff` are listed in its `Index`, only the index goes into Release
+fn is_packages_diff(path: &Path, filename: &str) -> bool {
+    filename != "Index"
+        && path
+            .parent()
+            .is_some_and(|p| p.file_name() == Some(pdiff::DIFF_DIR.as_ref()))
+}
+
+/// Marker file soft-retiring a branch, it holds the (optional) deprecation notice
+pub const DEPRECATION_MARKER: &str = "DEPRECATED";
+
+/// Read the deprecation notice of a branch, `None` if the branch is not deprecated
+fn read_deprecation(branch_root: &Path) -> Result<Option<String>> {
+    match std::fs::read_to_string(branch_root.join(DEPRECATION_MARKER)) {
+        // the notice goes into a single-line field
+        Ok(notice) => Ok(Some(
+            notice.split_whitespace().collect::<Vec<_>>().join(" "),
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Write the deprecation marker into the dists directory of a branch
+pub fn mark_deprecated(branch_root: &Path, notice: &str) -> Result<()> {
+    std::fs::write(
+        branch_root.join(DEPRECATION_MARKER),
+        format!("{}\n", notice.trim()),
+    )?;
+
+    Ok(())
+}
+
+/// Remove the deprecation marker of a branch, returning whether there was one
+pub fn clear_deprecation(branch_root: &Path) -> Result<bool> {
+    match std::fs::remove_file(branch_root.join(DEPRECATION_MARKER)) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn deprecated_description(description: &str, notice: &str) -> String {
+    if notice.is_empty() {
+        format!("[DEPRECATED] {}", description)
+    } else {
+        format!("[DEPRECATED: {}] {}", notice, description)
+    }
+}
+
+/// Release file of a component and architecture (`<component>/binary-<arch>/Release`)
+fn render_component_release(
+    config: &ReleaseConfig,
+    branch: &str,
+    component: &str,
+    arch: &str,
+) -> String {
+    format!(
+        "Archive: {}\nOrigin: {}\nLabel: {}\nComponent: {}\nArchitecture: {}\n",
+        config.branch_suite(branch),
+        config.origin,
+        config.component_label(branch, component),
+        component,
+        arch
+    )
+}
+
+/// Write the Release files of the components, so that the component labels are published.
+/// They are listed in the Release file of the branch like the indices.
+fn write_component_releases(
+    branch_root: &Path,
+    config: &ReleaseConfig,
+    branch: &str,
+    components: &[String],
+    architectures: &[String],
+) -> Result<()> {
+    for component in components {
+        for arch in architectures {
+            let index_dir = branch_root.join(component).join(format!("binary-{}", arch));
+            if !index_dir.is_dir() {
+                continue;
+            }
+            std::fs::write(
+                index_dir.join("Release"),
+                render_component_release(config, branch, component, arch),
+            )?;
+        }
+    }
+
+    Ok(())
 }
 
-fn scan_release_files(branch_root: &Path) -> Result<Vec<(String, u64, String)>> {
+fn scan_release_files(
+    branch_root: &Path,
+    pipelined_threshold: Option<u64>,
+) -> Result<Vec<(String, u64, String)>> {
     let walk = walkdir::WalkDir::new(branch_root).min_depth(1).into_iter();
     let mut files_to_scan = Vec::new();
     for entry in walk {
@@ -105,68 +305,139 @@ fn scan_release_files(branch_root: &Path) -> Result<Vec<(String, u64, String)>>
         if entry.file_type().is_dir()
             || filename.starts_with('.')
             || filename.starts_with("InRelease")
+            || (entry.depth() == 1 && (filename == "Release" || filename == DEPRECATION_MARKER))
+            || is_packages_diff(entry.path(), &filename)
         {
             continue;
         }
         files_to_scan.push(entry.path().to_owned());
     }
+    // keep the listing stable regardless of the directory order on disk
+    files_to_scan.sort();
     let files = files_to_scan
         .par_iter()
-        .filter_map(|p| match scan_single_release_file(branch_root, p) {
-            Ok(item) => Some(item),
-            Err(e) => {
-                error!("Error when scanning {}: {}", p.display(), e);
-                None
-            }
-        })
+        .filter_map(
+            |p| match scan_single_release_file(branch_root, p, pipelined_threshold) {
+                Ok(item) => Some(item),
+                Err(e) => {
+                    error!("Error when scanning {}: {}", p.display(), e);
+                    None
+                }
+            },
+        )
         .collect::<Vec<_>>();
 
     Ok(files)
 }
 
+/// Name of the Packages diffs generated at `timestamp`, e.g. `2024-01-31-1200.00`
+fn pdiff_name(time: time::OffsetDateTime) -> Result<String> {
+    Ok(time.format(format_description!(
+        "[year]-[month]-[day]-[hour][minute].[second]"
+    ))?)
+}
+
+/// Timestamp of the Release file: `SOURCE_DATE_EPOCH` (in seconds since the epoch) if set,
+/// otherwise the current time
+fn release_timestamp(source_date_epoch: Option<&str>) -> Result<time::OffsetDateTime> {
+    let Some(epoch) = source_date_epoch else {
+        return Ok(time::OffsetDateTime::now_utc());
+    };
+    let invalid =
+        |e: &dyn std::fmt::Display| anyhow!("Invalid SOURCE_DATE_EPOCH {:?}: {}", epoch, e);
+    let timestamp = epoch.trim().parse::<i64>().map_err(|e| invalid(&e))?;
+
+    time::OffsetDateTime::from_unix_timestamp(timestamp).map_err(|e| invalid(&e))
+}
+
+/// Expiry date (`Valid-Until`) of a Release file dated `date`, valid for `ttl` days
+fn valid_until(date: time::OffsetDateTime, ttl: u64) -> Result<time::OffsetDateTime> {
+    ttl.checked_mul(24 * 3600)
+        .and_then(|seconds| i64::try_from(seconds).ok())
+        .and_then(|seconds| date.checked_add(time::Duration::seconds(seconds)))
+        .ok_or_else(|| anyhow!("Valid-Until of {} + {} days is out of range", date, ttl))
+}
+
+/// Sort the components: the ones listed in `order` first (in that order), then the others
+/// alphabetically
+fn sort_components(components: &mut [String], order: &[String]) {
+    components.sort_by(|a, b| {
+        let rank = |c: &String| order.iter().position(|o| o == c).unwrap_or(order.len());
+        rank(a).cmp(&rank(b)).then_with(|| a.cmp(b))
+    });
+}
+
 fn create_release_file(
     mirror_root: &Path,
     config: &ReleaseConfig,
     m: &BranchMeta,
-    ttl: u64,
-    cert: &Option<(sequoia_openpgp::Cert, bool)>,
+    cert: &Option<(Vec<sequoia_openpgp::Cert>, bool)>,
 ) -> Result<()> {
     use std::fs::File as StdFile;
 
+    let (architectures, components) = match (m.arch.as_deref(), m.comp.as_deref()) {
+        (Some(arch), Some(comp)) if !arch.is_empty() && !comp.is_empty() => (arch, comp),
+        _ => {
+            warn!(
+                "{} has no known architectures or components, skipping.",
+                m.branch
+            );
+            return Ok(());
+        }
+    };
+    let architectures = architectures
+        .iter()
+        .filter(|arch| !config.excluded_architectures.contains(arch))
+        .cloned()
+        .collect::<Vec<_>>();
     info!("Generating InRelease files for {}", m.branch);
+    set_status(&format!("Generating InRelease files for {}", m.branch));
 
     let branch_root = mirror_root.join("dists").join(&m.branch);
-    let release_files = scan_release_files(&branch_root);
+    let mut components = components.to_vec();
+    if let Some(ref all_component) = config.arch_all_component {
+        if branch_root.join(all_component).is_dir() && !components.contains(all_component) {
+            components.push(all_component.clone());
+        }
+    }
+    sort_components(&mut components, &config.component_order);
+    if !config.component_labels.is_empty() {
+        write_component_releases(&branch_root, config, &m.branch, &components, &architectures)?;
+    }
+    let release_files = scan_release_files(&branch_root, config.pipelined_hash_threshold);
     if let Err(e) = release_files {
         error!("Error when scanning {}: {}", m.branch, e);
         return Err(e);
     }
-    let description = config
-        .descriptions
-        .get(&m.branch)
-        .map_or_else(|| format!("AOSC OS Topic: {}", m.branch), |d| d.to_owned());
-    let system_time = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-    let projected_timestamp = system_time + (ttl * 24 * 3600);
-    let system_time = time::OffsetDateTime::from_unix_timestamp(system_time.try_into().unwrap())?;
-    let projected_timestamp =
-        time::OffsetDateTime::from_unix_timestamp(projected_timestamp.try_into().unwrap())?;
-
-    let rendered = (InReleaseTemplate {
-        origin: config.origin.clone(),
-        label: config.label.clone(),
-        codename: config.codename.clone(),
-        suite: m.branch.clone(),
-        description,
-        date: system_time.format(&Rfc2822)?,
-        valid_until: projected_timestamp.format(&Rfc2822)?,
-        architectures: m.arch.as_ref().unwrap().to_vec(),
-        components: m.comp.as_ref().unwrap().to_vec(),
-        files: release_files.unwrap(),
-    })
-    .render_once();
+    let description = config.branch_description(&m.branch);
+    let description = match read_deprecation(&branch_root)? {
+        Some(notice) => {
+            info!("{} is deprecated.", m.branch);
+            deprecated_description(&description, &notice)
+        }
+        None => description,
+    };
+    let system_time = release_timestamp(std::env::var("SOURCE_DATE_EPOCH").ok().as_deref())?;
+    let projected_timestamp = valid_until(system_time, config.branch_ttl(&m.branch))?;
+
+    let rendered = config.templates.render(
+        "InRelease",
+        InReleaseTemplate {
+            origin: config.origin.clone(),
+            label: config.branch_label(&m.branch).to_string(),
+            codename: config.branch_codename(&m.branch).to_string(),
+            suite: config.branch_suite(&m.branch).to_string(),
+            description,
+            date: system_time.format(&Rfc2822)?,
+            valid_until: projected_timestamp.format(&Rfc2822)?,
+            not_automatic: config.not_automatic.contains(&m.branch),
+            but_automatic_upgrades: config.but_automatic_upgrades.contains(&m.branch),
+            architectures,
+            components,
+            changelogs: config.changelogs.clone(),
+            files: release_files.unwrap(),
+        },
+    );
     if let Err(e) = rendered {
         error!("Failed to generate release: {:?}", e);
         return Ok(());
@@ -176,12 +447,39 @@ fn create_release_file(
         // TODO: don't fail when signing failed
         let signed = if !cert.1 {
             // if the key is not offloaded
-            sign_message(&cert.0, rendered.as_bytes())?
+            sign_message(
+                &cert.0,
+                rendered.as_bytes(),
+                config.armor_comment.as_deref(),
+            )?
         } else {
-            sign_message_agent(&cert.0, rendered.as_bytes())?
+            sign_message_agent(
+                &cert.0,
+                rendered.as_bytes(),
+                config.armor_comment.as_deref(),
+            )?
         };
-        let mut f = StdFile::create(branch_root.join("InRelease"))?;
+        let inrelease_path = branch_root.join("InRelease");
+        let mut f = StdFile::create(&inrelease_path)?;
         f.write_all(&signed)?;
+        if config.verify_after_sign {
+            // read back what actually landed on disk
+            let written = std::fs::read(&inrelease_path)?;
+            match verify_message(&cert.0, &written) {
+                Ok(content) if content == rendered.as_bytes() => (),
+                Ok(_) => {
+                    error!(
+                        "Signed content of {} does not match the generated Release",
+                        inrelease_path.display()
+                    );
+                    return Err(anyhow!("InRelease verification failed for {}", m.branch));
+                }
+                Err(e) => {
+                    error!("Failed to verify {}: {}", inrelease_path.display(), e);
+                    return Err(anyhow!("InRelease verification failed for {}", m.branch));
+                }
+            }
+        }
     } else {
         warn!("Certificate not found or not available. Release file not signed.");
         let mut f = StdFile::create(branch_root.join("Release"))?;
@@ -191,11 +489,22 @@ fn create_release_file(
     Ok(())
 }
 
+/// Number of release files to generate and sign at a time (`None` for no limit).
+/// Signing with an offloaded key is serialized unless configured otherwise, since
+/// gpg-agent may fail under concurrent requests.
+fn signing_threads(configured: Option<usize>, offloaded: bool) -> Option<usize> {
+    match configured {
+        Some(0) => None,
+        Some(n) => Some(n),
+        None if offloaded => Some(1),
+        None => None,
+    }
+}
+
 fn create_release_files(
     mirror_root: &Path,
     config: &ReleaseConfig,
     meta: &[BranchMeta],
-    ttl: u64,
 ) -> Result<()> {
     if let Some(ref extra_dist_files) = &config.extra_dist_files {
         info!(
@@ -223,26 +532,53 @@ fn create_release_files(
     let cert = if let Some(cert) = &config.cert {
         info!("Signing release files using certificate: {}", cert);
         if let Some(cert) = cert.strip_prefix("gpg://") {
-            // (cert, offloaded)
-            Some((load_certificate(cert)?, true))
+            // (certs, offloaded)
+            Some((load_certificates(cert)?, true))
         } else {
-            Some((load_certificate(cert)?, false))
+            Some((load_certificates(cert)?, false))
         }
     } else {
         None
     };
 
-    meta.par_iter().for_each_with(cert, |cert, meta| {
-        if let Err(e) = create_release_file(mirror_root, config, meta, ttl, cert) {
-            warn!("Failed to create release file: {}", e);
+    if let (Some(cert), true) = (&cert, config.publish_public_key) {
+        match export_public_keys(&cert.0, &mirror_root.join("dists")) {
+            Ok(written) => {
+                for path in written {
+                    info!("Public key written to {}", path.display());
+                }
+            }
+            Err(e) => error!("Failed to write the public keys: {}", e),
         }
+    }
+    let offloaded = cert.as_ref().is_some_and(|c| c.1);
+    let threads = signing_threads(config.signing_concurrency, offloaded);
+    if let Some(threads) = threads {
+        info!("Signing at most {} release file(s) at a time.", threads);
+    }
+    let signing_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads.unwrap_or(0))
+        .build()?;
+    let failed = signing_pool.install(|| {
+        meta.par_iter()
+            .map_with(cert, |cert, meta| {
+                create_release_file(mirror_root, config, meta, cert)
+                    .map_err(|e| warn!("Failed to create release file: {}", e))
+                    .is_err()
+            })
+            .filter(|failed| *failed)
+            .count()
     });
+    if config.strict && failed > 0 {
+        return Err(anyhow!("Failed to create {} release file(s)", failed));
+    }
 
     Ok(())
 }
 
-async fn get_branch_metadata(pool: &PgPool) -> Result<Vec<BranchMeta>> {
-    Ok(sqlx::query_as!(BranchMeta, "SELECT branch, array_agg(DISTINCT architecture) AS arch, array_agg(DISTINCT component) AS comp FROM pv_repos GROUP BY branch").fetch_all(pool).await?)
+async fn get_branch_metadata(pool: &PgPool, snapshot: Option<&str>) -> Result<Vec<BranchMeta>> {
+    let mut tx = begin_in_snapshot(pool, snapshot).await?;
+    Ok(sqlx::query_as!(BranchMeta, "SELECT branch, array_agg(DISTINCT architecture) FILTER (WHERE architecture IS NOT NULL) AS arch, array_agg(DISTINCT component) FILTER (WHERE component IS NOT NULL) AS comp FROM pv_repos GROUP BY branch").fetch_all(&mut *tx).await?)
 }
 
 pub async fn render_releases(
@@ -257,78 +593,290 @@ pub async fn render_releases(
         regenerate_set.insert(r);
     }
 
-    let branches = get_branch_metadata(pool).await?;
-    let branches = branches
+    let branches = get_branch_metadata(pool, config.snapshot.as_deref()).await?;
+    let mut branches = branches
         .into_iter()
         .filter(|branch| regenerate_set.contains(&branch.branch))
         .collect::<Vec<_>>();
+    // the branches being bootstrapped, see `list_bootstrap_branches`
+    for branch in config.bootstrap_branches.iter() {
+        if !regenerate_set.contains(branch) || branches.iter().any(|b| &b.branch == branch) {
+            continue;
+        }
+        let comp = match list_dist_components(&mirror_root.join("dists").join(branch)) {
+            Ok(comp) => comp,
+            Err(e) => {
+                // e.g. all of its architectures were filtered out
+                warn!(
+                    "No indices generated for {}, skipping its Release file: {}",
+                    branch, e
+                );
+                continue;
+            }
+        };
+        branches.push(BranchMeta {
+            branch: branch.clone(),
+            arch: Some(config.default_architectures.clone()),
+            comp: Some(comp),
+        });
+    }
     let mirror_root = mirror_root.to_owned();
-    spawn_blocking(move || create_release_files(&mirror_root, &config, &branches, 10)).await??;
+    spawn_blocking(move || create_release_files(&mirror_root, &config, &branches)).await??;
 
     Ok(())
 }
 
-async fn render_contents_in_component_arch(
+/// Query the Contents lines of a component from the file lists
+async fn query_contents_lines(
     pool: &PgPool,
+    snapshot: Option<&str>,
     component: &str,
-    arch: String,
-    component_root: &Path,
-) -> Result<()> {
+    arch: &str,
+    excludes: &[Pattern],
+    excluded_packages: &[String],
+) -> Result<Vec<String>> {
+    let mut tx = begin_in_snapshot(pool, snapshot).await?;
     let lines = sqlx::query!(
-        r#"SELECT (df.path || '/' || df.name) || '   ' || (string_agg(DISTINCT (
-coalesce(dp.section || '/', '') || dp.package), ',')) || chr(10) as p
+        r#"SELECT (df.path || '/' || df.name) AS file,
+(df.path || '/' || df.name) || '   ' || (string_agg(DISTINCT (
+coalesce(dp.section || '/', '') || dp.package) COLLATE "C", ',')) || chr(10) as p
 FROM pv_packages dp
 INNER JOIN pv_package_files df USING (package, version, repo)
 INNER JOIN pv_repos pr ON pr.name=dp.repo
 WHERE pr.path=$1 AND df.ftype<53
 AND pr.architecture IN ($2, 'all') AND dp.debtime IS NOT NULL
+AND NOT (dp.package = ANY($3)) AND dp.filename NOT LIKE '%.udeb'
 GROUP BY df.path, df.name"#,
         component,
-        arch
+        arch,
+        excluded_packages
     )
-    .fetch_all(pool)
+    .fetch_all(&mut *tx)
     .await?;
+    let mut lines = lines;
+    // sorted like the merged fragments
+    lines.sort_unstable_by(|a, b| a.file.cmp(&b.file));
 
-    let content = lines
-        .iter()
-        .flat_map(|line| line.p.as_ref().map(|s| s.to_string()))
-        .collect::<String>();
-    let dist_path_zstd = component_root.join(format!("Contents-{}.zst", arch));
-    let dist_path_gz = component_root.join(format!("Contents-{}.gz", arch));
-    let dist_path_un = component_root.join(format!("Contents-{}", arch));
+    Ok(lines
+        .into_iter()
+        .filter(|line| {
+            !line
+                .file
+                .as_ref()
+                .is_some_and(|f| is_path_excluded(f, excludes))
+        })
+        .filter_map(|line| line.p)
+        .collect())
+}
+
+/// Query the Contents lines of a component from the lines stored at scan time, merged so that
+/// the result is the same as `query_contents_lines`. Returns `None` if any of the packages has
+/// no stored lines
+async fn query_contents_fragments(
+    pool: &PgPool,
+    snapshot: Option<&str>,
+    component: &str,
+    arch: &str,
+    excludes: &[Pattern],
+    excluded_packages: &[String],
+) -> Result<Option<Vec<String>>> {
+    let mut tx = begin_in_snapshot(pool, snapshot).await?;
+    let fragments = sqlx::query!(
+        r#"SELECT pc.contents AS "contents?"
+FROM pv_packages dp
+INNER JOIN pv_repos pr ON pr.name=dp.repo
+LEFT JOIN pv_package_contents pc USING (package, version, repo)
+WHERE pr.path=$1 AND pr.architecture IN ($2, 'all') AND dp.debtime IS NOT NULL
+AND NOT (dp.package = ANY($3)) AND dp.filename NOT LIKE '%.udeb'"#,
+        component,
+        arch,
+        excluded_packages
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let mut contents = Vec::with_capacity(fragments.len());
+    for fragment in fragments {
+        let Some(fragment) = fragment.contents else {
+            return Ok(None);
+        };
+        contents.push(fragment);
+    }
+    let lines = contents.iter().flat_map(|c| c.split_inclusive('\n'));
+
+    Ok(Some(merge_contents_lines(lines, excludes)))
+}
+
+/// Merge the Contents lines of the packages into one line per path listing all the packages
+/// shipping it (`<path>   <section>/<package>,...`), sorted by path
+fn merge_contents_lines<'a>(
+    lines: impl Iterator<Item = &'a str>,
+    excludes: &[Pattern],
+) -> Vec<String> {
+    let mut paths = BTreeMap::<&str, BTreeSet<&str>>::new();
+    for line in lines {
+        let Some((path, package)) = split_contents_line(line) else {
+            continue;
+        };
+        if is_path_excluded(path, excludes) {
+            continue;
+        }
+        paths.entry(path).or_default().insert(package);
+    }
+
+    paths
+        .into_iter()
+        .map(|(path, packages)| {
+            let packages = packages.into_iter().collect::<Vec<_>>();
+            format!("{}   {}\n", path, packages.join(","))
+        })
+        .collect()
+}
+
+/// Split a Contents line (`<path>   <section>/<package>`) into the path and the package
+fn split_contents_line(line: &str) -> Option<(&str, &str)> {
+    line.trim_end_matches('\n').rsplit_once("   ")
+}
+
+/// The header line of the Contents files (empty if not configured)
+fn contents_header_line(header: Option<&str>) -> String {
+    match header {
+        Some(header) => format!("{}\n", header.trim_end_matches('\n')),
+        None => String::new(),
+    }
+}
+
+/// Path of the Contents file (relative to the branch root) from the configured pattern
+fn contents_path(pattern: &str, component: &str, arch: &str) -> String {
+    pattern
+        .replace("{component}", component)
+        .replace("{arch}", arch)
+}
+
+/// Compress the content into the file with multi-threaded zstd
+fn write_zstd_multithread(path: &Path, content: &[u8], level: i32, threads: u32) -> Result<()> {
+    let mut encoder = zstd::stream::write::Encoder::new(std::fs::File::create(path)?, level)?;
+    encoder.multithread(threads)?;
+    encoder.write_all(content)?;
+    encoder.finish()?;
+
+    Ok(())
+}
+
+async fn render_contents_in_component_arch(
+    pool: &PgPool,
+    component: &str,
+    arch: String,
+    component_root: &Path,
+    excludes: &[Pattern],
+    config: &ReleaseConfig,
+    stats: &GenerationStats,
+) -> Result<()> {
+    let fragments = if config.contents_fragments {
+        with_timeout(
+            config.query_timeout,
+            query_contents_fragments(
+                pool,
+                config.snapshot.as_deref(),
+                component,
+                &arch,
+                excludes,
+                &config.excluded_packages,
+            ),
+        )
+        .await?
+    } else {
+        None
+    };
+    let lines = match fragments {
+        Some(lines) => lines,
+        None => {
+            if config.contents_fragments {
+                warn!(
+                    "Some packages in {} ({}) have no stored Contents lines, using the file lists instead.",
+                    component, arch
+                );
+            }
+            with_timeout(
+                config.query_timeout,
+                query_contents_lines(
+                    pool,
+                    config.snapshot.as_deref(),
+                    component,
+                    &arch,
+                    excludes,
+                    &config.excluded_packages,
+                ),
+            )
+            .await?
+        }
+    };
+
+    let mut content = contents_header_line(config.contents_header.as_deref());
+    content.extend(lines.iter().map(|line| line.as_str()));
+    let tier = select_compression_tier(&config.contents_compression, content.len() as u64);
+    let (gzip_level, zstd_level) = match tier {
+        Some(tier) => (
+            Level::Precise(tier.gzip_level as i32),
+            Level::Precise(tier.zstd_level),
+        ),
+        None => (Level::Default, Level::Default),
+    };
+    let zstd_threads = tier.map(|t| (t.zstd_level, t.zstd_threads));
+    let content = Arc::new(content);
+    let branch_root = component_root.parent().unwrap_or(component_root);
+    let component_name = component.rsplit('/').next().unwrap_or(component);
+    let contents_path = contents_path(&config.contents_filename, component_name, &arch);
+    let dist_path_un = branch_root.join(&contents_path);
+    if let Some(parent) = dist_path_un.parent() {
+        create_dir_all(parent).await?;
+    }
+    let dist_path_zstd = branch_root.join(format!("{}.zst", contents_path));
+    let dist_path_gz = branch_root.join(format!("{}.gz", contents_path));
     let dist_path_bin = component_root.join(format!("BinContents-{}", arch));
 
     tokio::try_join!(
         async {
-            let mut f = ZstdEncoder::new(File::create(dist_path_zstd).await?);
-            f.write_all(content.as_bytes()).await?;
-            f.shutdown().await?;
+            match zstd_threads {
+                Some((level, threads)) if threads > 0 => {
+                    let content = content.clone();
+                    let path = dist_path_zstd.clone();
+                    spawn_blocking(move || {
+                        write_zstd_multithread(&path, content.as_bytes(), level, threads)
+                    })
+                    .await??;
+                }
+                _ => {
+                    let mut f =
+                        ZstdEncoder::with_quality(File::create(&dist_path_zstd).await?, zstd_level);
+                    f.write_all(content.as_bytes()).await?;
+                    f.shutdown().await?;
+                }
+            }
+            let size = metadata(&dist_path_zstd).await?.len();
+            stats.contents_zst.fetch_add(size, Ordering::Relaxed);
             Ok::<(), Error>(())
         },
         async {
-            let mut f = GzipEncoder::new(File::create(dist_path_gz).await?);
+            let mut f = GzipEncoder::with_quality(File::create(&dist_path_gz).await?, gzip_level);
             f.write_all(content.as_bytes()).await?;
             f.shutdown().await?;
+            let size = metadata(&dist_path_gz).await?.len();
+            stats.contents_gz.fetch_add(size, Ordering::Relaxed);
             Ok::<(), Error>(())
         },
         async {
             let mut f1 = File::create(dist_path_un).await?;
             f1.write_all(content.as_bytes()).await?;
             f1.shutdown().await?;
+            stats
+                .contents
+                .fetch_add(content.len() as u64, Ordering::Relaxed);
             Ok::<(), Error>(())
         },
         async {
             let bin = lines
                 .into_iter()
-                .filter_map(|s| {
-                    s.p.and_then(|s| {
-                        if s.contains("usr/bin/") {
-                            Some(s)
-                        } else {
-                            None
-                        }
-                    })
-                })
+                .filter(|s| s.contains("usr/bin/"))
                 .collect::<String>();
             let mut f3 = File::create(dist_path_bin).await?;
             f3.write_all(bin.as_bytes()).await?;
@@ -344,22 +892,45 @@ pub async fn render_contents_in_component(
     pool: &PgPool,
     component: &str,
     mirror_root: &Path,
+    arch_filter: Option<&str>,
+    config: &ReleaseConfig,
+    stats: &GenerationStats,
 ) -> Result<()> {
     info!("Generating Contents for {}", component);
+    set_status(&format!("Generating Contents for {}", component));
+
+    let excludes = config
+        .contents_path_excludes
+        .iter()
+        .map(|p| Pattern::new(p))
+        .collect::<Result<Vec<_>, _>>()?;
 
+    let mut tx = begin_in_snapshot(pool, config.snapshot.as_deref()).await?;
     let records = sqlx::query!("SELECT architecture FROM pv_repos WHERE path=$1", component)
-        .fetch_all(pool)
+        .fetch_all(&mut *tx)
         .await?;
+    // release the connection before the queries of each architecture
+    drop(tx);
     let component_root = mirror_root.join("dists").join(component);
     create_dir_all(&component_root).await?;
 
     let mut tasks = Vec::new();
     for record in records {
+        if !is_arch_selected(
+            &record.architecture,
+            arch_filter,
+            &config.excluded_architectures,
+        ) {
+            continue;
+        }
         tasks.push(render_contents_in_component_arch(
             pool,
             component,
             record.architecture,
             &component_root,
+            &excludes,
+            config,
+            stats,
         ));
     }
     let results = futures::future::join_all(tasks).await;
@@ -377,26 +948,122 @@ pub async fn render_contents_in_component(
     Ok(())
 }
 
+/// Installer packages are indexed separately, under `<component>/debian-installer/binary-<arch>`
+const UDEB_INDEX_DIR: &str = "debian-installer";
+
+fn is_udeb(filename: &str) -> bool {
+    filename.ends_with(".udeb")
+}
+
+/// Sort the packages by (package, version) so that identical input always
+/// produces identical `Packages` files
+fn sort_packages(packages: &mut [PackageTemplate]) {
+    packages.sort_unstable_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version)));
+}
+
+/// Remove the repeated (package, version, filename), e.g. an `all` package listed in several
+/// components of a branch
+fn dedup_packages(packages: &mut Vec<PackageTemplate>) {
+    let mut seen = HashSet::new();
+    packages.retain(|p| seen.insert((p.name.clone(), p.version.clone(), p.path.clone())));
+}
+
 async fn render_packages_in_component_arch(
     arch: &str,
     packages: Vec<PackageTemplate>,
     component_root: &Path,
-) -> Result<()> {
+    formats: &[PackagesFormat],
+    templates: &Templates,
+    stats: &GenerationStats,
+) -> Result<u64> {
     let dist_path = component_root.join(format!("binary-{}", arch));
     create_dir_all(&dist_path).await?;
-    let mut package_file = File::create(dist_path.join("Packages")).await?;
-    let mut package_file_xz = XzEncoder::new(File::create(dist_path.join("Packages.xz")).await?);
-    let rendered = spawn_blocking(move || PackagesTemplate { packages }.render_once()).await??;
+    let templates = templates.clone();
+    let rendered =
+        spawn_blocking(move || templates.render("Packages", PackagesTemplate { packages }))
+            .await??;
+
+    tokio::try_join!(
+        async {
+            let mut f = File::create(dist_path.join("Packages")).await?;
+            f.write_all(rendered.as_bytes()).await?;
+            f.shutdown().await?;
+            stats
+                .packages
+                .fetch_add(rendered.len() as u64, Ordering::Relaxed);
+            Ok::<(), Error>(())
+        },
+        async {
+            if formats.contains(&PackagesFormat::Xz) {
+                let path = dist_path.join("Packages.xz");
+                let mut f = XzEncoder::new(File::create(&path).await?);
+                f.write_all(rendered.as_bytes()).await?;
+                // flush compressor cache
+                f.shutdown().await?;
+                let size = metadata(&path).await?.len();
+                stats.packages_xz.fetch_add(size, Ordering::Relaxed);
+            }
+            Ok::<(), Error>(())
+        },
+        async {
+            if formats.contains(&PackagesFormat::Zst) {
+                let path = dist_path.join("Packages.zst");
+                let mut f = ZstdEncoder::new(File::create(&path).await?);
+                f.write_all(rendered.as_bytes()).await?;
+                f.shutdown().await?;
+                let size = metadata(&path).await?.len();
+                stats.packages_zst.fetch_add(size, Ordering::Relaxed);
+            }
+            Ok::<(), Error>(())
+        }
+    )?;
+
+    Ok(rendered.len() as u64)
+}
+
+/// Whether the Packages file grew by more than `percent` percent since the previous generation
+fn is_packages_bloated(previous: u64, current: u64, percent: u64) -> bool {
+    previous > 0 && current.saturating_sub(previous) * 100 > previous * percent
+}
 
-    let results = tokio::join!(
-        package_file.write_all(rendered.as_bytes()),
-        package_file_xz.write_all(rendered.as_bytes())
+/// Compare the size of the newly rendered Packages file with the published one
+async fn report_packages_size(
+    component: &str,
+    arch: &str,
+    size: u64,
+    config: &ReleaseConfig,
+) -> Result<()> {
+    let Some(ref published_root) = config.published_root else {
+        return Ok(());
+    };
+    let path = published_root
+        .join("dists")
+        .join(component)
+        .join(format!("binary-{}", arch))
+        .join("Packages");
+    let previous = match metadata(&path).await {
+        Ok(stat) => stat.len(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            info!(
+                "{} binary-{}: Packages is {} bytes (new)",
+                component, arch, size
+            );
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    info!(
+        "{} binary-{}: Packages is {} bytes (previously {} bytes)",
+        component, arch, size, previous
     );
-    // Raise an error if any
-    results.0?;
-    results.1?;
-    // flush compressor cache
-    package_file_xz.shutdown().await?;
+    if let Some(percent) = config.packages_growth_warning {
+        if is_packages_bloated(previous, size, percent) {
+            warn!(
+                "{} binary-{}: Packages grew from {} to {} bytes, more than {}%!",
+                component, arch, previous, size, percent
+            );
+        }
+    }
 
     Ok(())
 }
@@ -405,27 +1072,118 @@ pub async fn render_packages_in_component(
     pool: &PgPool,
     component: &str,
     mirror_root: &Path,
+    arch_filter: Option<&str>,
+    config: &ReleaseConfig,
+    stats: &GenerationStats,
+) -> Result<()> {
+    info!("Generating Packages for {}", component);
+    set_status(&format!("Generating Packages for {}", component));
+    let mut records = query_packages(pool, Some(component), None, config).await?;
+    if config.arch_all_component.is_some() {
+        // indexed in the pseudo-component of the branch instead
+        records.retain(|p| p.arch.as_deref() != Some("all"));
+    }
+
+    render_packages(records, component, mirror_root, arch_filter, config, stats).await
+}
+
+/// Generate the Packages of the `Architecture: all` packages of every component of the branch,
+/// in the pseudo-component configured with `arch_all_component`
+pub async fn render_arch_all_component(
+    pool: &PgPool,
+    branch: &str,
+    mirror_root: &Path,
+    arch_filter: Option<&str>,
+    config: &ReleaseConfig,
+    stats: &GenerationStats,
 ) -> Result<()> {
+    let Some(ref all_component) = config.arch_all_component else {
+        return Ok(());
+    };
+    let component = format!("{}/{}", branch, all_component);
     info!("Generating Packages for {}", component);
+    set_status(&format!("Generating Packages for {}", component));
+    let mut records = query_packages(pool, None, Some(branch), config).await?;
+    if records.is_empty() {
+        return Ok(());
+    }
+    dedup_packages(&mut records);
+
+    render_packages(records, &component, mirror_root, arch_filter, config, stats).await
+}
+
+/// Remove the `binary-all` indices left in the regular components of the branch from before
+/// `arch_all_component` was enabled, once the pseudo-component has its own
+pub fn prune_arch_all_indices(branch_root: &Path, all_component: &str) -> Result<()> {
+    if !branch_root.join(all_component).join("binary-all").is_dir() {
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(branch_root)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() || entry.file_name() == all_component {
+            continue;
+        }
+        let stale = entry.path().join("binary-all");
+        if stale.is_dir() {
+            info!("Removing the stale {}", stale.display());
+            std::fs::remove_dir_all(&stale)?;
+        }
+    }
+
+    Ok(())
+}
 
-    let records = sqlx::query_as!(
+/// Query the packages of a component (e.g. `stable/main`), or the `Architecture: all` packages
+/// of every component of a branch
+async fn query_packages(
+    pool: &PgPool,
+    component: Option<&str>,
+    arch_all_branch: Option<&str>,
+    config: &ReleaseConfig,
+) -> Result<Vec<PackageTemplate>> {
+    let mut tx = begin_in_snapshot(pool, config.snapshot.as_deref()).await?;
+    let query = sqlx::query_as!(
         PackageTemplate,
         r#"SELECT p.package AS name, p.version, min(p.architecture) arch,
     min(p.filename) path, min(p.size) size, min(p.sha256) sha256,
     min(p.section) section, min(p.installed_size) inst_size,
-    min(p.maintainer) maintainer, min(p.description) description, p.features features,
-    json_agg(array[pd.relationship, pd.value]) dep
+    min(p.maintainer) maintainer, min(p.description) description,
+    min(p.description_body) description_body, p.features features,
+    p.built_using built_using, p.homepage homepage, p.bugs bugs, p.origin origin,
+    (SELECT string_agg(t.tag, ', ' ORDER BY t.tag) FROM pv_package_tags t
+     WHERE t.package=p.package AND t.version=p.version AND t.repo=p.repo) tag,
+    (SELECT json_agg(array[e.field, e.value] ORDER BY e.field) FROM pv_package_extra_fields e
+     WHERE e.package=p.package AND e.version=p.version AND e.repo=p.repo
+     AND lower(e.field) = ANY($2)) extra_fields,
+    json_agg(array[pd.relationship, pd.value] ORDER BY pd.relationship) dep
 FROM pv_packages p INNER JOIN pv_repos r ON p.repo=r.name
 LEFT JOIN pv_package_dependencies pd ON pd.package=p.package
 AND pd.version=p.version AND pd.repo=p.repo
-WHERE r.path=$1 AND p.debtime IS NOT NULL
+WHERE ($1::TEXT IS NULL OR r.path=$1)
+AND ($3::TEXT IS NULL OR (r.branch=$3 AND p.architecture='all'))
+AND p.debtime IS NOT NULL AND NOT (p.package = ANY($4))
 GROUP BY p.package, p.version, p.repo"#,
-        component
+        component,
+        &config.extra_packages_fields[..],
+        arch_all_branch,
+        &config.excluded_packages[..]
     )
-    .fetch_all(pool)
-    .await?;
+    .fetch_all(&mut *tx);
+
+    with_timeout(config.query_timeout, query).await
+}
 
-    let mut grouped_packages: HashMap<String, Vec<PackageTemplate>> = HashMap::new();
+/// Write the Packages files of the packages (grouped by architecture) into the component
+async fn render_packages(
+    records: Vec<PackageTemplate>,
+    component: &str,
+    mirror_root: &Path,
+    arch_filter: Option<&str>,
+    config: &ReleaseConfig,
+    stats: &GenerationStats,
+) -> Result<()> {
+    // BTreeMap keeps the architectures sorted so that the output is reproducible
+    let mut grouped_packages: BTreeMap<String, Vec<PackageTemplate>> = BTreeMap::new();
     for record in records {
         let arch_packages = grouped_packages.get_mut(record.arch.as_ref().unwrap());
         if let Some(arch_packages) = arch_packages {
@@ -435,44 +1193,397 @@ GROUP BY p.package, p.version, p.repo"#,
             grouped_packages.insert(arch, vec![record]);
         }
     }
-
-    let component_root = mirror_root.join("dists").join(component);
-    for (arch, packages) in grouped_packages.into_iter() {
-        render_packages_in_component_arch(&arch, packages, &component_root).await?;
+    let branch = component.split('/').next().unwrap_or(component);
+    if grouped_packages.is_empty() && config.bootstrap_branches.iter().any(|b| b == branch) {
+        // an empty component of a new branch still gets valid indices
+        for arch in config.default_architectures.iter() {
+            grouped_packages.insert(arch.clone(), Vec::new());
+        }
     }
 
+    let component_root = mirror_root.join("dists").join(component);
+    let mut sizes = Vec::new();
+    for (arch, mut packages) in grouped_packages.into_iter() {
+        if !is_arch_selected(&arch, arch_filter, &config.excluded_architectures) {
+            continue;
+        }
+        sort_packages(&mut packages);
+        let (udebs, packages): (Vec<_>, Vec<_>) = packages
+            .into_iter()
+            .partition(|p| p.path.as_deref().is_some_and(is_udeb));
+        let size = render_packages_in_component_arch(
+            &arch,
+            packages,
+            &component_root,
+            &config.packages_formats,
+            &config.templates,
+            stats,
+        )
+        .await?;
+        sizes.push((arch.clone(), size));
+        if !udebs.is_empty() {
+            render_packages_in_component_arch(
+                &arch,
+                udebs,
+                &component_root.join(UDEB_INDEX_DIR),
+                &config.packages_formats,
+                &config.templates,
+                stats,
+            )
+            .await?;
+        }
+    }
+    for (arch, size) in sizes.iter() {
+        report_packages_size(component, arch, *size, config).await?;
+    }
+    if let (Some(keep), Some(published_root)) = (config.packages_diffs, &config.published_root) {
+        let name = pdiff_name(release_timestamp(
+            std::env::var("SOURCE_DATE_EPOCH").ok().as_deref(),
+        )?)?;
+        let published_root = published_root.join("dists").join(component);
+        for (arch, _) in sizes {
+            let dir = format!("binary-{}", arch);
+            let published_dir = published_root.join(&dir);
+            let new_dir = component_root.join(&dir);
+            let name = name.clone();
+            spawn_blocking(move || {
+                pdiff::update_packages_diff(&published_dir, &new_dir, &name, keep)
+            })
+            .await??;
+        }
+    }
+
+    Ok(())
+}
+
+/// Calculate the total size of the files under the specified directory,
+/// and the number of entries (files and directories) in it
+pub fn directory_size(path: &Path) -> Result<(u64, u64)> {
+    let mut size = 0;
+    let mut entries = 0;
+    for entry in walkdir::WalkDir::new(path) {
+        let entry = entry?;
+        entries += 1;
+        if entry.file_type().is_file() {
+            size += entry.metadata()?.len();
+        }
+    }
+
+    Ok((size, entries))
+}
+
+/// Check if the filesystem containing `path` has at least `required` bytes available
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)]
+pub fn check_free_space(path: &Path, required: u64) -> Result<()> {
+    let stat = nix::sys::statvfs::statvfs(path)?;
+    let available = stat.blocks_available() as u64 * stat.fragment_size() as u64;
+    if available < required {
+        return Err(anyhow!(
+            "Insufficient disk space on {}: {} bytes required, but only {} bytes available",
+            path.display(),
+            required,
+            available
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn check_free_space(_path: &Path, _required: u64) -> Result<()> {
+    Ok(())
+}
+
+/// Check if the filesystem containing `path` has at least `required` free inodes,
+/// plus `reserve` inodes that are kept free
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)]
+pub fn check_free_inodes(path: &Path, required: u64, reserve: u64) -> Result<()> {
+    let stat = nix::sys::statvfs::statvfs(path)?;
+    // filesystems without a fixed inode table (e.g. btrfs) report no inodes at all
+    if stat.files() == 0 {
+        return Ok(());
+    }
+    let available = stat.files_available() as u64;
+    if available < required.saturating_add(reserve) {
+        return Err(anyhow!(
+            "Insufficient inodes on {}: {} inodes required (plus {} reserved), but only {} available",
+            path.display(),
+            required,
+            reserve,
+            available
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn check_free_inodes(_path: &Path, _required: u64, _reserve: u64) -> Result<()> {
     Ok(())
 }
 
+/// Check if both paths reside on the same filesystem (so that they can be renamed across)
+#[cfg(unix)]
+pub fn is_same_filesystem(a: &Path, b: &Path) -> Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    Ok(std::fs::metadata(a)?.dev() == std::fs::metadata(b)?.dev())
+}
+
+#[cfg(not(unix))]
+pub fn is_same_filesystem(_a: &Path, _b: &Path) -> Result<bool> {
+    Ok(false)
+}
+
+/// Interval between two progress reports of [move_directory]
+const MOVE_PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Move the files under `from` into `to`, overwriting existing files.
+/// Files are renamed when possible, and copied when crossing filesystems.
+/// `cancel` is checked between files: once set, the move stops and the files not yet moved
+/// are left in `from`.
+pub fn move_directory(from: &Path, to: &Path, cancel: &AtomicBool) -> Result<u64> {
+    let mut files = Vec::new();
+    for entry in walkdir::WalkDir::new(from) {
+        let entry = entry?;
+        let target = to.join(entry.path().strip_prefix(from)?);
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(&target)?;
+            continue;
+        }
+        files.push((entry, target));
+    }
+    let total = files.len();
+    let mut moved = 0;
+    let mut last_report = Instant::now();
+    for (count, (entry, target)) in files.into_iter().enumerate() {
+        if cancel.load(Ordering::SeqCst) {
+            return Err(anyhow!(
+                "Publishing cancelled after moving {}/{} files, the Release files were not updated.",
+                count,
+                total
+            ));
+        }
+        if last_report.elapsed() >= MOVE_PROGRESS_INTERVAL {
+            info!("Publishing: {}/{} files moved ...", count, total);
+            set_status(&format!("Publishing: {}/{} files moved", count, total));
+            last_report = Instant::now();
+        }
+        moved += entry.metadata()?.len();
+        if std::fs::rename(entry.path(), &target).is_err() {
+            std::fs::copy(entry.path(), &target)?;
+        }
+    }
+    info!("Published {} files.", total);
+
+    Ok(moved)
+}
+
+/// Link `dists/<codename>` to `dists/<branch>` for each branch with a codename, so that
+/// the branch can also be reached by its codename. Existing links are updated, but real
+/// directories in the way are left untouched.
+#[cfg(unix)]
+pub fn link_codename_dists(mirror_root: &Path, codenames: &HashMap<String, String>) -> Result<()> {
+    let dists = mirror_root.join("dists");
+    for (branch, codename) in codenames {
+        if branch == codename || !dists.join(branch).is_dir() {
+            continue;
+        }
+        let link = dists.join(codename);
+        match std::fs::symlink_metadata(&link) {
+            Ok(stat) if stat.file_type().is_symlink() => {
+                if std::fs::read_link(&link)? == Path::new(branch) {
+                    continue;
+                }
+                std::fs::remove_file(&link)?;
+            }
+            Ok(_) => {
+                warn!(
+                    "{} already exists and is not a symbolic link, not linking {} to it.",
+                    link.display(),
+                    branch
+                );
+                continue;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+        info!("Linking dists/{} to {} ...", codename, branch);
+        std::os::unix::fs::symlink(branch, &link)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn link_codename_dists(
+    _mirror_root: &Path,
+    _codenames: &HashMap<String, String>,
+) -> Result<()> {
+    Err(anyhow!("Codename links are not supported on this platform"))
+}
+
 /// Check if the branch needs refreshing. TTL is in days.
 async fn need_refresh(inrel_path: &Path) -> Result<bool> {
     let mut f = File::open(inrel_path).await?;
     let mut content = Vec::new();
     f.read_to_end(&mut content).await?;
-    let captured = parse_valid_date(&content).map_err(|e| anyhow!(e.to_string()))?;
-    let captured_str = std::str::from_utf8(captured.1)?;
-    let parsed = time::OffsetDateTime::parse(captured_str, &Rfc2822).map_err(|e| anyhow!(e))?;
-    let parsed_timestamp = parsed.to_offset(offset!(+0)).unix_timestamp();
-    let system_time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
-    let projected_timestamp = system_time + (24 * 3600);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+    is_refresh_due(&content, now as i64)
+}
+
+/// Check if the Release file expires within the refresh margin: one day, or half of its
+/// validity period if shorter (otherwise a branch with a TTL of one day is always due)
+fn is_refresh_due(content: &[u8], now: i64) -> Result<bool> {
+    let parse_field = |captured: &[u8]| -> Result<i64> {
+        let parsed = time::OffsetDateTime::parse(std::str::from_utf8(captured)?, &Rfc2822)
+            .map_err(|e| anyhow!(e))?;
+        Ok(parsed.to_offset(offset!(+0)).unix_timestamp())
+    };
+    let valid_until = parse_valid_date(content).map_err(|e| anyhow!(e.to_string()))?;
+    let valid_until = parse_field(valid_until.1)?;
+    let date = parse_release_date(content).map_err(|e| anyhow!(e.to_string()))?;
+    let date = parse_field(date.1)?;
+    let margin = ((valid_until - date) / 2).min(24 * 3600);
 
-    Ok(projected_timestamp >= parsed_timestamp as u64)
+    Ok(now + margin >= valid_until)
 }
 
-pub async fn need_regenerate(pool: &PgPool, mirror_root: &Path) -> Result<Vec<String>> {
+/// Check that every repository of the branches (with packages) has its indices under `dists`,
+/// and its branch the Release files. Returns the repositories missing any of them
+pub async fn reconcile_dists(
+    pool: &PgPool,
+    mirror_root: &Path,
+    branches: &[String],
+    signed: bool,
+) -> Result<Vec<String>> {
+    let records = sqlx::query!(
+        "SELECT DISTINCT r.path, r.branch FROM pv_repos r
+WHERE r.branch = ANY($1) AND EXISTS (SELECT 1 FROM pv_packages p WHERE p.repo = r.name)
+ORDER BY r.path",
+        branches
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(find_missing_dists(
+        &mirror_root.join("dists"),
+        records.iter().map(|r| (r.path.as_str(), r.branch.as_str())),
+        signed,
+    ))
+}
+
+fn find_missing_dists<'a>(
+    dists: &Path,
+    repos: impl Iterator<Item = (&'a str, &'a str)>,
+    signed: bool,
+) -> Vec<String> {
+    let mut missing = Vec::new();
+    for (path, branch) in repos {
+        let branch_root = dists.join(branch);
+        let problem = if !dists.join(path).is_dir() {
+            "no indices"
+        } else if !branch_root.join("Release").is_file() {
+            "no Release"
+        } else if signed && !branch_root.join("InRelease").is_file() {
+            "no InRelease"
+        } else {
+            continue;
+        };
+        missing.push(format!("{}: {}", path, problem));
+    }
+
+    missing
+}
+
+/// List the branches in the pool without any packages in the database yet (e.g. a new topic),
+/// and whose Release files are missing or about to expire, unless `force` is set. Their
+/// indices are generated empty, for the default architectures
+pub async fn list_bootstrap_branches(
+    topics: &[PathBuf],
+    known: &[String],
+    mirror_root: &Path,
+    force: bool,
+) -> Vec<String> {
+    let mut candidates = topics
+        .iter()
+        .filter_map(|topic| topic.iter().next()?.to_str())
+        .filter(|branch| !known.iter().any(|k| k == branch))
+        .map(|branch| branch.to_string())
+        .collect::<Vec<_>>();
+    candidates.sort_unstable();
+    candidates.dedup();
+
+    let mut branches = Vec::new();
+    for branch in candidates {
+        let branch_root = mirror_root.join("dists").join(&branch);
+        let mut release_path = branch_root.join("InRelease");
+        if !release_path.exists() {
+            // unsigned generations only have the Release file
+            release_path = branch_root.join("Release");
+        }
+        if force || need_refresh(&release_path).await.unwrap_or(true) {
+            branches.push(branch);
+        }
+    }
+
+    branches
+}
+
+/// List the components generated under the branch root
+fn list_dist_components(branch_root: &Path) -> Result<Vec<String>> {
+    let mut components = Vec::new();
+    for entry in std::fs::read_dir(branch_root)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            components.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+
+    Ok(components)
+}
+
+/// List the branches that need regenerating. When `force` is set, all the branches are returned.
+/// Branches without any packages are never returned, they are left for the GC to remove.
+pub async fn need_regenerate(
+    pool: &PgPool,
+    mirror_root: &Path,
+    force: bool,
+) -> Result<Vec<String>> {
     let dist_path = mirror_root.join("dists");
     let mut needs_regenerate = Vec::new();
     let records = sqlx::query!(
-        "SELECT branch, coalesce(extract(epoch FROM max(mtime)), 0)::bigint AS modified FROM pv_repos GROUP BY branch"
+        "SELECT r.branch, coalesce(extract(epoch FROM max(r.mtime)), 0)::bigint AS modified, count(p.package) AS packages
+FROM pv_repos r LEFT JOIN pv_packages p ON p.repo = r.name GROUP BY r.branch"
     )
     .fetch_all(pool)
     .await?;
     for record in records {
+        if record.packages.unwrap_or(0) < 1 {
+            warn!(
+                "{} has no packages, skipping. Run the GC to remove it.",
+                record.branch
+            );
+            continue;
+        }
+        if force {
+            needs_regenerate.push(record.branch);
+            continue;
+        }
         let inrelease_path = dist_path.join(&record.branch).join("InRelease");
         let inrelease_info = metadata(&inrelease_path).await;
         if let Ok(metadata) = inrelease_info {
             let mtime = mtime(&metadata).unwrap_or(0);
-            if let Some(modified) = record.modified {
+            // the branch was deprecated (or its notice changed) after the last generation
+            let marker = dist_path.join(&record.branch).join(DEPRECATION_MARKER);
+            let marked = match tokio::fs::metadata(&marker).await {
+                Ok(marker) => crate::scan::mtime(&marker).unwrap_or(0),
+                Err(_) => 0,
+            };
+            if let (Some(modified), true) = (record.modified, marked <= mtime) {
                 if mtime >= modified as u64 && !need_refresh(&inrelease_path).await.unwrap_or(true)
                 {
                     continue;
@@ -494,6 +1605,51 @@ fn test_date_parsing() {
     assert_eq!(parsed, expected);
 }
 
+#[test]
+fn test_arch_filter() {
+    assert!(is_arch_selected("amd64", None, &[]));
+    assert!(is_arch_selected("amd64", Some("amd64"), &[]));
+    assert!(is_arch_selected("all", Some("riscv64"), &[]));
+    assert!(!is_arch_selected("amd64", Some("riscv64"), &[]));
+    let excluded = ["loongson3".to_string()];
+    assert!(!is_arch_selected("loongson3", None, &excluded));
+    assert!(is_arch_selected("amd64", None, &excluded));
+}
+
+#[test]
+fn test_free_space_check() {
+    let tempdir = tempfile::tempdir().unwrap();
+    std::fs::write(tempdir.path().join("Packages"), b"1234567890").unwrap();
+    std::fs::create_dir(tempdir.path().join("binary-amd64")).unwrap();
+    std::fs::write(tempdir.path().join("binary-amd64/Packages"), b"12345").unwrap();
+    assert_eq!(directory_size(tempdir.path()).unwrap(), (15, 4));
+    assert!(check_free_space(tempdir.path(), 15).is_ok());
+    assert!(check_free_inodes(tempdir.path(), 4, 0).is_ok());
+    #[cfg(unix)]
+    {
+        assert!(check_free_space(tempdir.path(), u64::MAX).is_err());
+        let has_inodes = nix::sys::statvfs::statvfs(tempdir.path()).unwrap().files() > 0;
+        assert_eq!(
+            check_free_inodes(tempdir.path(), 4, u64::MAX).is_err(),
+            has_inodes
+        );
+    }
+}
+
+#[test]
+fn test_contents_excludes() {
+    let excludes = vec![Pattern::new("usr/share/fonts/*/fonts.cache-*").unwrap()];
+    assert!(is_path_excluded(
+        "usr/share/fonts/TTF/fonts.cache-9",
+        &excludes
+    ));
+    assert!(!is_path_excluded(
+        "usr/share/fonts/TTF/DejaVuSans.ttf",
+        &excludes
+    ));
+    assert!(!is_path_excluded("usr/bin/fc-cache", &[]));
+}
+
 #[test]
 fn test_inrel_parsing() {
     let test_data = r#"Origin: AOSC
@@ -506,6 +1662,122 @@ Valid-Until: Sat, 24 Jul 2021 10:54:24 +0000
 Architectures: amd64 arm64 loongson3 ppc64el"#;
     let captured = parse_valid_date(test_data.as_bytes()).unwrap();
     assert_eq!(captured.1, &b"Sat, 24 Jul 2021 10:54:24 +0000"[..]);
+    let captured = parse_release_date(test_data.as_bytes()).unwrap();
+    assert_eq!(captured.1, &b"Wed, 14 Jul 2021 10:54:24 +0000"[..]);
+}
+
+#[test]
+fn test_is_refresh_due() {
+    let release = |date: &str, valid_until: &str| {
+        format!(
+            "Origin: AOSC\nDate: {}\nValid-Until: {}\nSHA256:\n",
+            date, valid_until
+        )
+    };
+    // Wed, 14 Jul 2021 10:54:24 +0000
+    let date = 1626260064;
+    let weekly = release(
+        "Wed, 14 Jul 2021 10:54:24 +0000",
+        "Wed, 21 Jul 2021 10:54:24 +0000",
+    );
+    assert!(!is_refresh_due(weekly.as_bytes(), date).unwrap());
+    assert!(!is_refresh_due(weekly.as_bytes(), date + 5 * 86400).unwrap());
+    assert!(is_refresh_due(weekly.as_bytes(), date + 6 * 86400).unwrap());
+    // a TTL of one day is refreshed in its second half only
+    let daily = release(
+        "Wed, 14 Jul 2021 10:54:24 +0000",
+        "Thu, 15 Jul 2021 10:54:24 +0000",
+    );
+    assert!(!is_refresh_due(daily.as_bytes(), date).unwrap());
+    assert!(!is_refresh_due(daily.as_bytes(), date + 3600).unwrap());
+    assert!(is_refresh_due(daily.as_bytes(), date + 12 * 3600).unwrap());
+    assert!(is_refresh_due(b"Origin: AOSC\n", date).is_err());
+}
+
+/// Package in the default architecture, with only its name and version set
+#[cfg(test)]
+fn sample_package(name: &str, version: &str) -> PackageTemplate {
+    PackageTemplate {
+        name: name.to_string(),
+        version: version.to_string(),
+        section: None,
+        arch: Some("amd64".to_string()),
+        inst_size: None,
+        maintainer: None,
+        path: None,
+        size: None,
+        sha256: None,
+        description: None,
+        description_body: None,
+        dep: None,
+        features: None,
+        built_using: None,
+        homepage: None,
+        bugs: None,
+        origin: None,
+        tag: None,
+        extra_fields: None,
+    }
+}
+
+#[test]
+fn test_package_sorting() {
+    let mut packages = vec![
+        sample_package("zsync", "1"),
+        sample_package("a", "2"),
+        sample_package("a", "1"),
+    ];
+    sort_packages(&mut packages);
+    let sorted = packages
+        .iter()
+        .map(|p| (p.name.as_str(), p.version.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(sorted, vec![("a", "1"), ("a", "2"), ("zsync", "1")]);
+}
+
+#[test]
+fn test_dedup_packages() {
+    let in_pool = |name: &str, version: &str, path: &str| PackageTemplate {
+        path: Some(path.to_string()),
+        ..sample_package(name, version)
+    };
+    let mut packages = vec![
+        in_pool("a", "1", "pool/stable/main/a/a_1_noarch.deb"),
+        in_pool("a", "1", "pool/stable/main/a/a_1_noarch.deb"),
+        in_pool("a", "2", "pool/stable/main/a/a_2_noarch.deb"),
+        in_pool("a", "1", "pool/stable/contrib/a/a_1_noarch.deb"),
+    ];
+    dedup_packages(&mut packages);
+    let paths = packages
+        .iter()
+        .map(|p| p.path.as_deref().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        paths,
+        [
+            "pool/stable/main/a/a_1_noarch.deb",
+            "pool/stable/main/a/a_2_noarch.deb",
+            "pool/stable/contrib/a/a_1_noarch.deb"
+        ]
+    );
+}
+
+#[test]
+fn test_prune_arch_all_indices() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let branch_root = tempdir.path();
+    for dir in ["main/binary-all", "main/binary-amd64", "contrib/binary-all"] {
+        std::fs::create_dir_all(branch_root.join(dir)).unwrap();
+    }
+    // nothing is removed until the pseudo-component is generated
+    prune_arch_all_indices(branch_root, "all").unwrap();
+    assert!(branch_root.join("main/binary-all").is_dir());
+    std::fs::create_dir_all(branch_root.join("all/binary-all")).unwrap();
+    prune_arch_all_indices(branch_root, "all").unwrap();
+    assert!(!branch_root.join("main/binary-all").exists());
+    assert!(!branch_root.join("contrib/binary-all").exists());
+    assert!(branch_root.join("main/binary-amd64").is_dir());
+    assert!(branch_root.join("all/binary-all").is_dir());
 }
 
 #[test]
@@ -522,10 +1794,18 @@ fn test_package_generate() {
         size: Some(10),
         sha256: Some("sha256".to_string()),
         description: Some("description".to_string()),
+        description_body: None,
         dep: None,
         features: Some("core".to_string()),
+        built_using: None,
+        homepage: None,
+        bugs: None,
+        origin: None,
+        tag: None,
+        extra_fields: None,
     };
     let mut test_package_2 = test_package.clone();
+    let mut test_package_3 = test_package.clone();
     let rendered = PackagesTemplate {
         packages: vec![test_package],
     }
@@ -547,7 +1827,13 @@ X-AOSC-Features: core
 
 "#
     );
+    test_package_2.description_body = Some("Extended description.\n.\nMore.".to_string());
     test_package_2.dep = Some(json!([["Depends", "test (=1)"]]));
+    test_package_2.built_using = Some("gcc (= 13.2.0-1)".to_string());
+    test_package_2.homepage = Some("https://example.org/".to_string());
+    test_package_2.origin = Some("AOSC".to_string());
+    test_package_2.tag = Some("role::program, use::editing".to_string());
+    test_package_2.extra_fields = Some(json!([["Multi-Arch", "same"], ["X-Vendor", "a\n b"]]));
     let rendered = PackagesTemplate {
         packages: vec![test_package_2],
     }
@@ -565,9 +1851,473 @@ Filename: path
 Size: 10
 SHA256: sha256
 Description: description
+ Extended description.
+ .
+ More.
 Depends: test (=1)
+Built-Using: gcc (= 13.2.0-1)
+Homepage: https://example.org/
+Origin: AOSC
+Tag: role::program, use::editing
 X-AOSC-Features: core
+Multi-Arch: same
+X-Vendor: a
+ b
 
 "#
     );
+    // malformed values from the database are skipped instead of panicking
+    test_package_3.dep = Some(json!([["Depends"], "Depends: x", ["Depends", "x", "y"]]));
+    test_package_3.extra_fields = Some(json!({"Multi-Arch": "same"}));
+    let rendered = PackagesTemplate {
+        packages: vec![test_package_3],
+    }
+    .render_once()
+    .unwrap();
+    assert!(rendered.ends_with("Description: description\nX-AOSC-Features: core\n\n"));
+}
+
+#[test]
+fn test_move_directory() {
+    let from = tempfile::tempdir().unwrap();
+    let to = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(from.path().join("stable/main")).unwrap();
+    std::fs::write(from.path().join("stable/main/Packages"), b"new").unwrap();
+    std::fs::create_dir_all(to.path().join("stable/main")).unwrap();
+    std::fs::write(to.path().join("stable/main/Packages"), b"old").unwrap();
+    std::fs::write(to.path().join("stable/main/Contents-all"), b"old").unwrap();
+    // nothing is moved once cancelled
+    assert!(move_directory(from.path(), to.path(), &AtomicBool::new(true)).is_err());
+    assert_eq!(
+        std::fs::read(to.path().join("stable/main/Packages")).unwrap(),
+        b"old"
+    );
+    assert_eq!(
+        move_directory(from.path(), to.path(), &AtomicBool::new(false)).unwrap(),
+        3
+    );
+    assert_eq!(
+        std::fs::read(to.path().join("stable/main/Packages")).unwrap(),
+        b"new"
+    );
+    // existing files not in the source are left untouched
+    assert!(to.path().join("stable/main/Contents-all").exists());
+    assert!(is_same_filesystem(from.path(), to.path()).unwrap());
+}
+
+#[test]
+fn test_generation_stats() {
+    let stats = GenerationStats::default();
+    assert!(stats.summary().is_empty());
+    stats.packages.fetch_add(1000, Ordering::Relaxed);
+    stats.packages_xz.fetch_add(250, Ordering::Relaxed);
+    assert_eq!(
+        stats.summary(),
+        vec![
+            "Packages: 1000 bytes".to_string(),
+            "Packages.xz: 250 bytes (ratio 25.00%)".to_string()
+        ]
+    );
+}
+
+#[test]
+fn test_release_incomplete_branch() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let meta = BranchMeta {
+        branch: "stable".to_string(),
+        arch: None,
+        comp: Some(vec!["main".to_string()]),
+    };
+    // the branch is skipped instead of panicking
+    create_release_file(tempdir.path(), &ReleaseConfig::default(), &meta, &None).unwrap();
+    assert!(!tempdir.path().join("dists/stable/Release").exists());
+}
+
+#[test]
+fn test_deprecation() {
+    let dir = tempfile::tempdir().unwrap();
+    assert_eq!(read_deprecation(dir.path()).unwrap(), None);
+    assert!(!clear_deprecation(dir.path()).unwrap());
+    mark_deprecated(dir.path(), "use\n stable  instead ").unwrap();
+    let notice = read_deprecation(dir.path()).unwrap().unwrap();
+    assert_eq!(notice, "use stable instead");
+    assert_eq!(
+        deprecated_description("AOSC OS Topic: foo", &notice),
+        "[DEPRECATED: use stable instead] AOSC OS Topic: foo"
+    );
+    assert_eq!(
+        deprecated_description("AOSC OS Topic: foo", ""),
+        "[DEPRECATED] AOSC OS Topic: foo"
+    );
+    assert!(clear_deprecation(dir.path()).unwrap());
+    assert_eq!(read_deprecation(dir.path()).unwrap(), None);
+}
+
+#[test]
+fn test_pdiff_name() {
+    assert_eq!(
+        pdiff_name(time::OffsetDateTime::from_unix_timestamp(1700000000).unwrap()).unwrap(),
+        "2023-11-14-2213.20"
+    );
+    assert!(is_packages_diff(
+        Path::new("main/binary-amd64/Packages.diff/2023-11-14-2213.20.gz"),
+        "2023-11-14-2213.20.gz"
+    ));
+    assert!(!is_packages_diff(
+        Path::new("main/binary-amd64/Packages.diff/Index"),
+        "Index"
+    ));
+}
+
+#[test]
+fn test_release_timestamp() {
+    let date = release_timestamp(Some("1700000000")).unwrap();
+    assert_eq!(date.unix_timestamp(), 1700000000);
+    assert!(release_timestamp(Some("yesterday")).is_err());
+    assert!(release_timestamp(Some("-1")).is_ok());
+    assert!(release_timestamp(Some("18446744073709551615")).is_err());
+    assert!(release_timestamp(Some("9223372036854775807")).is_err());
+    assert!(release_timestamp(None).unwrap().unix_timestamp() > 1700000000);
+    assert_eq!(
+        valid_until(date, 14).unwrap().unix_timestamp(),
+        1700000000 + 14 * 24 * 3600
+    );
+    assert!(valid_until(date, u64::MAX).is_err());
+    let last = release_timestamp(Some("253402300799")).unwrap();
+    assert!(valid_until(last, 1).is_err());
+}
+
+/// Release of the `main` component of `stable` for amd64, without any files
+#[cfg(test)]
+fn sample_release() -> InReleaseTemplate {
+    InReleaseTemplate {
+        origin: "AOSC".to_string(),
+        label: "AOSC OS".to_string(),
+        codename: "Hotfix".to_string(),
+        suite: "stable".to_string(),
+        description: "Stable".to_string(),
+        date: String::new(),
+        valid_until: String::new(),
+        not_automatic: false,
+        but_automatic_upgrades: false,
+        architectures: vec!["amd64".to_string()],
+        components: vec!["main".to_string()],
+        changelogs: None,
+        files: vec![],
+    }
+}
+
+#[test]
+fn test_template_override() {
+    let tempdir = tempfile::tempdir().unwrap();
+    std::fs::write(
+        tempdir.path().join("InRelease.tera"),
+        "Suite: {{ suite }}\nX-Mirror: example\nSHA256:\n{% for f in files %} {{ f.2 }} {{ f.1 }} {{ f.0 }}\n{% endfor %}",
+    )
+    .unwrap();
+    let templates = Templates::load(tempdir.path().to_str()).unwrap();
+    let release = InReleaseTemplate {
+        files: vec![(
+            "main/binary-amd64/Packages".to_string(),
+            10,
+            "abcd".to_string(),
+        )],
+        ..sample_release()
+    };
+    assert_eq!(
+        templates.render("InRelease", release).unwrap(),
+        "Suite: stable\nX-Mirror: example\nSHA256:\n abcd 10 main/binary-amd64/Packages\n"
+    );
+    // no override, the compiled-in template is used
+    let packages = templates
+        .render("Packages", PackagesTemplate { packages: vec![] })
+        .unwrap();
+    assert_eq!(packages, "");
+    assert!(Templates::load(Some("/nonexistent")).is_err());
+}
+
+#[test]
+fn test_release_automatic_flags() {
+    let release = |not_automatic, but_automatic_upgrades| {
+        InReleaseTemplate {
+            not_automatic,
+            but_automatic_upgrades,
+            ..sample_release()
+        }
+        .render_once()
+        .unwrap()
+    };
+    assert!(release(false, false).contains("Valid-Until: \nArchitectures: amd64\n"));
+    assert!(
+        release(true, false).contains("Valid-Until: \nNotAutomatic: yes\nArchitectures: amd64\n")
+    );
+    assert!(release(true, true).contains(
+        "Valid-Until: \nNotAutomatic: yes\nButAutomaticUpgrades: yes\nArchitectures: amd64\n"
+    ));
+}
+
+#[test]
+fn test_release_changelogs() {
+    let release = |changelogs: Option<&str>| {
+        InReleaseTemplate {
+            origin: "AOSC".to_string(),
+            label: "AOSC OS".to_string(),
+            codename: "Hotfix".to_string(),
+            suite: "stable".to_string(),
+            description: "Stable".to_string(),
+            date: String::new(),
+            valid_until: String::new(),
+            not_automatic: false,
+            but_automatic_upgrades: false,
+            architectures: vec!["amd64".to_string()],
+            components: vec!["main".to_string()],
+            changelogs: changelogs.map(str::to_string),
+            files: vec![],
+        }
+        .render_once()
+        .unwrap()
+    };
+    assert!(release(None).ends_with("Components: main\nSHA256:"));
+    assert!(release(Some("https://repo.aosc.io/changelogs/@CHANGEPATH@_changelog")).ends_with(
+        "Components: main\nChangelogs: https://repo.aosc.io/changelogs/@CHANGEPATH@_changelog\nSHA256:"
+    ));
+}
+
+#[test]
+fn test_component_release() {
+    let config = ReleaseConfig {
+        origin: "AOSC".to_string(),
+        label: "AOSC OS".to_string(),
+        component_labels: HashMap::from([("contrib".to_string(), "AOSC Contrib".to_string())]),
+        ..Default::default()
+    };
+    assert_eq!(
+        render_component_release(&config, "stable", "contrib", "amd64"),
+        "Archive: stable\nOrigin: AOSC\nLabel: AOSC Contrib\nComponent: contrib\nArchitecture: amd64\n"
+    );
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(dir.path().join("main/binary-amd64")).unwrap();
+    write_component_releases(
+        dir.path(),
+        &config,
+        "stable",
+        &["main".to_string(), "contrib".to_string()],
+        &["amd64".to_string()],
+    )
+    .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("main/binary-amd64/Release")).unwrap(),
+        "Archive: stable\nOrigin: AOSC\nLabel: AOSC OS\nComponent: main\nArchitecture: amd64\n"
+    );
+    // components without indices are skipped
+    assert!(!dir.path().join("contrib").exists());
+}
+
+#[test]
+fn test_split_contents_line() {
+    assert_eq!(
+        split_contents_line("usr/bin/zsync   net/zsync\n"),
+        Some(("usr/bin/zsync", "net/zsync"))
+    );
+    assert_eq!(
+        split_contents_line("usr/share/My   Files/a   misc/foo\n"),
+        Some(("usr/share/My   Files/a", "misc/foo"))
+    );
+    assert_eq!(split_contents_line("garbage\n"), None);
+}
+
+#[test]
+fn test_contents_header_line() {
+    assert_eq!(contents_header_line(None), "");
+    assert_eq!(
+        contents_header_line(Some("FILE    LOCATION")),
+        "FILE    LOCATION\n"
+    );
+    assert_eq!(
+        contents_header_line(Some("FILE    LOCATION\n")),
+        "FILE    LOCATION\n"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_link_codename_dists() {
+    let root = tempfile::tempdir().unwrap();
+    let dists = root.path().join("dists");
+    std::fs::create_dir_all(dists.join("stable")).unwrap();
+    std::fs::create_dir_all(dists.join("testing")).unwrap();
+    std::fs::create_dir_all(dists.join("occupied")).unwrap();
+    std::os::unix::fs::symlink("testing", dists.join("surprise")).unwrap();
+    let codenames = HashMap::from([
+        ("stable".to_string(), "surprise".to_string()),
+        ("testing".to_string(), "occupied".to_string()),
+        ("missing".to_string(), "nowhere".to_string()),
+    ]);
+    link_codename_dists(root.path(), &codenames).unwrap();
+    // stale links are repointed
+    assert_eq!(
+        std::fs::read_link(dists.join("surprise")).unwrap(),
+        Path::new("stable")
+    );
+    // real directories are left alone
+    assert!(!std::fs::symlink_metadata(dists.join("occupied"))
+        .unwrap()
+        .file_type()
+        .is_symlink());
+    assert!(!dists.join("nowhere").exists());
+}
+
+#[test]
+fn test_is_packages_bloated() {
+    assert!(!is_packages_bloated(1000, 1500, 50));
+    assert!(is_packages_bloated(1000, 1501, 50));
+    assert!(!is_packages_bloated(1000, 100, 50));
+    // nothing to compare against
+    assert!(!is_packages_bloated(0, 1000, 50));
+}
+
+#[test]
+fn test_signing_threads() {
+    assert_eq!(signing_threads(None, false), None);
+    assert_eq!(signing_threads(None, true), Some(1));
+    assert_eq!(signing_threads(Some(4), true), Some(4));
+    assert_eq!(signing_threads(Some(0), true), None);
+}
+
+#[test]
+fn test_contents_path() {
+    use crate::config::DEFAULT_CONTENTS_FILENAME;
+
+    assert_eq!(
+        contents_path(DEFAULT_CONTENTS_FILENAME, "main", "amd64"),
+        "main/Contents-amd64"
+    );
+    assert_eq!(
+        contents_path("Contents-{component}-{arch}", "bsp-sunxi", "arm64"),
+        "Contents-bsp-sunxi-arm64"
+    );
+}
+
+#[test]
+fn test_write_zstd_multithread() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("Contents-amd64.zst");
+    let content = (0..100000)
+        .map(|i| {
+            format!(
+                "usr/share/doc/package-{}/copyright   doc/package-{}\n",
+                i, i
+            )
+        })
+        .collect::<String>();
+    write_zstd_multithread(&path, content.as_bytes(), 3, 4).unwrap();
+    let decoded = zstd::decode_all(std::fs::File::open(&path).unwrap()).unwrap();
+    assert_eq!(decoded, content.as_bytes());
+}
+
+#[test]
+fn test_find_missing_dists() {
+    let dir = tempfile::tempdir().unwrap();
+    let dists = dir.path();
+    std::fs::create_dir_all(dists.join("stable/main")).unwrap();
+    std::fs::create_dir_all(dists.join("stable/contrib")).unwrap();
+    std::fs::write(dists.join("stable/Release"), "").unwrap();
+    std::fs::create_dir_all(dists.join("topic/main")).unwrap();
+    let repos = [
+        ("stable/main", "stable"),
+        ("stable/contrib", "stable"),
+        ("stable/non-free", "stable"),
+        ("topic/main", "topic"),
+    ];
+    assert_eq!(
+        find_missing_dists(dists, repos.into_iter(), false),
+        ["stable/non-free: no indices", "topic/main: no Release"]
+    );
+    assert_eq!(
+        find_missing_dists(dists, repos.into_iter(), true),
+        [
+            "stable/main: no InRelease",
+            "stable/contrib: no InRelease",
+            "stable/non-free: no indices",
+            "topic/main: no Release"
+        ]
+    );
+}
+
+#[test]
+fn test_sort_components() {
+    let sorted = |components: &[&str], order: &[&str]| {
+        let mut components = components.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+        let order = order.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+        sort_components(&mut components, &order);
+        components
+    };
+    assert_eq!(
+        sorted(&["non-free", "contrib", "main", "bsp"], &["main"]),
+        ["main", "bsp", "contrib", "non-free"]
+    );
+    assert_eq!(
+        sorted(
+            &["non-free", "contrib", "main"],
+            &["main", "non-free", "extra"]
+        ),
+        ["main", "non-free", "contrib"]
+    );
+    assert_eq!(sorted(&["b", "a"], &[]), ["a", "b"]);
+}
+
+#[tokio::test]
+async fn test_list_bootstrap_branches() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let mirror_root = tempdir.path();
+    let write_release = |branch: &str, name: &str, valid_until: &str| {
+        let branch_root = mirror_root.join("dists").join(branch);
+        std::fs::create_dir_all(&branch_root).unwrap();
+        let content = format!(
+            "Origin: AOSC\nDate: Wed, 14 Jul 2021 10:54:24 +0000\nValid-Until: {}\nSHA256:\n",
+            valid_until
+        );
+        std::fs::write(branch_root.join(name), content).unwrap();
+    };
+    write_release("published", "InRelease", "Fri, 01 Jan 2100 00:00:00 +0000");
+    write_release("unsigned", "Release", "Fri, 01 Jan 2100 00:00:00 +0000");
+    write_release("expiring", "InRelease", "Sat, 24 Jul 2021 10:54:24 +0000");
+    let topics = [
+        PathBuf::from("stable/main"),
+        PathBuf::from("new-topic/main"),
+        PathBuf::from("new-topic/bsp"),
+        PathBuf::from("published/main"),
+        PathBuf::from("unsigned/main"),
+        PathBuf::from("expiring/main"),
+    ];
+    let known = ["stable".to_string()];
+    assert_eq!(
+        list_bootstrap_branches(&topics, &known, mirror_root, false).await,
+        ["expiring", "new-topic"]
+    );
+    assert_eq!(
+        list_bootstrap_branches(&topics, &known, mirror_root, true).await,
+        ["expiring", "new-topic", "published", "unsigned"]
+    );
+}
+
+#[test]
+fn test_merge_contents_lines() {
+    let excludes = [Pattern::new("usr/share/doc/*").unwrap()];
+    let fragments = [
+        "usr/lib/libfoo.so.1   libs/libfoo\nusr/share/doc/foo/README   libs/libfoo\n",
+        "usr/bin/foo   utils/foo\nusr/lib/libfoo.so.1   libs/foo-compat\n",
+        // the same package in the arch and the `all` repos, or in several versions
+        "usr/bin/foo   utils/foo\n",
+        "usr/share/My   Files/a   misc/bar\n",
+    ];
+    let lines = fragments.iter().flat_map(|f| f.split_inclusive('\n'));
+    assert_eq!(
+        merge_contents_lines(lines, &excludes),
+        [
+            "usr/bin/foo   utils/foo\n",
+            "usr/lib/libfoo.so.1   libs/foo-compat,libs/libfoo\n",
+            "usr/share/My   Files/a   misc/bar\n",
+        ]
+    );
 }
@@ -1,15 +1,25 @@
 use anyhow::Result;
-use redis::{Commands, Connection};
+use log::warn;
+use redis::{Commands, Connection, ConnectionAddr, ConnectionInfo, IntoConnectionInfo};
 use serde::Serialize;
+use std::time::Duration;
 
+/// Current schema version of [`PVMessage`], bump this when changing the message format
+pub const PV_MESSAGE_VERSION: u8 = 1;
+
+/// Package change notification published to the `p-vector-publish` channel,
+/// see `docs/ipc.md` for the schema
 #[derive(Serialize, Debug)]
 pub struct PVMessage {
+    version: u8,
     comp: String,
     pkg: String,
     arch: String,
     method: u8,
     from_ver: Option<String>,
     to_ver: Option<String>,
+    sha256: Option<String>,
+    size: Option<u64>,
 }
 
 impl PVMessage {
@@ -22,26 +32,155 @@ impl PVMessage {
         to_ver: Option<String>,
     ) -> Self {
         PVMessage {
+            version: PV_MESSAGE_VERSION,
             comp,
             pkg,
             arch,
             method,
             from_ver,
             to_ver,
+            sha256: None,
+            size: None,
+        }
+    }
+
+    /// Attach the checksum and size of the new package file
+    pub fn with_file_info(mut self, sha256: String, size: u64) -> Self {
+        self.sha256 = Some(sha256);
+        self.size = Some(size);
+        self
+    }
+}
+
+/// Parse the Redis address (`redis://`, `rediss://` for TLS, IPv6 literals in brackets).
+/// `insecure` skips certificate verification for TLS connections.
+fn connection_info(ipc_address: &str, insecure: bool) -> Result<ConnectionInfo> {
+    let mut info = ipc_address.into_connection_info()?;
+    if insecure {
+        match info.addr {
+            ConnectionAddr::TcpTls {
+                insecure: ref mut skip_verify,
+                ..
+            } => *skip_verify = true,
+            _ => warn!(
+                "Skipping certificate verification only applies to TLS (rediss://) connections."
+            ),
         }
     }
+
+    Ok(info)
 }
 
-pub fn redis_connect(ipc_address: &str) -> Result<Connection> {
-    let client = redis::Client::open(ipc_address)?;
+pub fn redis_connect(ipc_address: &str, insecure: bool) -> Result<Connection> {
+    let client = redis::Client::open(connection_info(ipc_address, insecure)?)?;
     let con = client.get_connection()?;
 
     Ok(con)
 }
 
-pub fn publish_pv_messages(messages: &[PVMessage], conn: &mut Connection) -> Result<()> {
-    let serialized = serde_json::to_string(&messages)?;
-    conn.publish::<_, _, ()>("p-vector-publish", serialized)?;
+/// Serialize the messages into the payload published to the channel
+pub fn serialize_pv_messages(messages: &[PVMessage]) -> Result<String> {
+    Ok(serde_json::to_string(&messages)?)
+}
+
+/// Delay before the `attempt`-th retry (starting from 1): 1, 2, 4, ... seconds, at most 60 seconds
+fn retry_delay(attempt: u32) -> Duration {
+    Duration::from_secs(1u64 << attempt.saturating_sub(1).min(6)).min(Duration::from_secs(60))
+}
+
+/// Publish the payloads in order, reconnecting with exponential backoff when publishing fails.
+/// Returns the number of payloads published, which is less than the number of payloads
+/// when all the retries failed.
+pub fn publish_payloads(
+    ipc_address: &str,
+    insecure: bool,
+    payloads: &[String],
+    max_retries: u32,
+) -> usize {
+    let mut published = 0;
+    let mut attempt = 0;
+    while published < payloads.len() {
+        if attempt > 0 {
+            let delay = retry_delay(attempt);
+            warn!(
+                "Retrying in {} seconds ({}/{}) ...",
+                delay.as_secs(),
+                attempt,
+                max_retries
+            );
+            std::thread::sleep(delay);
+        }
+        let result = redis_connect(ipc_address, insecure).and_then(|mut conn| {
+            // sleep 1 second so that the client is ready
+            std::thread::sleep(Duration::from_secs(1));
+            for payload in &payloads[published..] {
+                conn.publish::<_, _, ()>("p-vector-publish", payload)?;
+                published += 1;
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            warn!("Failed to publish changes: {}", e);
+            if attempt >= max_retries {
+                break;
+            }
+            attempt += 1;
+        }
+    }
+
+    published
+}
+
+#[test]
+fn test_message_version() {
+    let message = PVMessage::new(
+        "stable-main".to_string(),
+        "bash".to_string(),
+        "amd64".to_string(),
+        b'+',
+        None,
+        Some("5.2.15".to_string()),
+    );
+    assert_eq!(
+        serde_json::to_string(&message).unwrap(),
+        r#"{"version":1,"comp":"stable-main","pkg":"bash","arch":"amd64","method":43,"from_ver":null,"to_ver":"5.2.15","sha256":null,"size":null}"#
+    );
+}
+
+#[test]
+fn test_message_file_info() {
+    let message = PVMessage::new(
+        "stable-main".to_string(),
+        "bash".to_string(),
+        "amd64".to_string(),
+        b'^',
+        Some("5.2.15".to_string()),
+        Some("5.2.21".to_string()),
+    )
+    .with_file_info("abcd".to_string(), 1024);
+    let serialized = serde_json::to_string(&message).unwrap();
+    assert!(serialized.ends_with(r#""sha256":"abcd","size":1024}"#));
+}
+
+#[test]
+fn test_connection_info() {
+    let info = connection_info("rediss://[::1]:6380/", false).unwrap();
+    assert!(matches!(
+        info.addr,
+        ConnectionAddr::TcpTls { ref host, port: 6380, insecure: false, .. } if host == "::1"
+    ));
+    let info = connection_info("rediss://redis.aosc.io/", true).unwrap();
+    assert!(matches!(
+        info.addr,
+        ConnectionAddr::TcpTls { insecure: true, .. }
+    ));
+    let info = connection_info("redis://127.0.0.1/", true).unwrap();
+    assert!(matches!(info.addr, ConnectionAddr::Tcp(..)));
+}
 
-    Ok(())
+#[test]
+fn test_retry_delay() {
+    assert_eq!(retry_delay(1), Duration::from_secs(1));
+    assert_eq!(retry_delay(3), Duration::from_secs(4));
+    assert_eq!(retry_delay(100), Duration::from_secs(60));
 }
@@ -1,34 +1,40 @@
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, HashMap, HashSet},
     path::{Path, PathBuf},
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use futures::future::Either;
-use log::{error, info};
+use log::{error, info, warn};
 use sqlx::PgPool;
 use time::macros::format_description;
-use tokio::{
-    task::{block_in_place, spawn_blocking},
-    time::sleep,
-};
-use walkdir::DirEntry;
+use tokio::task::{block_in_place, spawn_blocking};
 
 #[cfg(feature = "systemd")]
 use sd_notify::NotifyState;
 
 use crate::scan::collect_removed_packages;
 
+mod audit;
 mod cli;
 mod config;
 mod db;
+mod depends;
+mod export;
 mod gc;
 mod generate;
 mod ipc;
 mod parser;
+mod pdiff;
+mod report;
 mod scan;
 mod sign;
+mod status;
+mod template;
 
 macro_rules! log_error {
     ($i:expr, $stage:expr) => {
@@ -48,17 +54,20 @@ async fn list_all_packages(pool: &PgPool, components: &[PathBuf]) -> Result<Vec<
     Ok(results)
 }
 
-fn get_changed_packages<'a>(discovered: &'a [DirEntry], scanned: &[PathBuf]) -> Vec<&'a Path> {
+fn get_changed_packages<'a>(
+    discovered: &'a [scan::PackageEntry],
+    scanned: &[String],
+) -> Vec<&'a scan::PackageEntry> {
     let mut scanned_cache = HashSet::new();
     let mut changed = Vec::new();
     for entry in scanned {
-        scanned_cache.insert(entry.as_path());
+        scanned_cache.insert(entry.as_str());
     }
-    for directory in discovered {
-        if scanned_cache.contains(directory.path()) {
+    for entry in discovered {
+        if scanned_cache.contains(entry.filename.as_str()) {
             continue;
         }
-        changed.push(directory.path());
+        changed.push(entry);
     }
 
     changed
@@ -69,36 +78,71 @@ async fn main() -> Result<()> {
     let args: cli::PVector = argh::from_env();
     env_logger::init();
 
-    let config = config::parse_config(args.config.as_str())?;
+    let mut config = config::parse_config(args.config.as_str())?;
+    if let Some(ref database_url) = args.database_url {
+        config.config.db_pgconn = database_url.clone();
+    }
     config::lint_config(&config);
+    if let cli::PVectorCommand::PrintConfig(ref print_args) = args.command {
+        // no database connection needed
+        return print_config(&config, print_args.json);
+    }
 
     info!("Connecting to database...");
-    let pool = db::connect_database(&config.config.db_pgconn).await?;
+    let pool =
+        db::connect_database(&config.config.db_pgconn, config.config.statement_timeout).await?;
     info!("Running any pending migrations...");
     db::run_migrate(&pool).await?;
+    db::check_functions(&pool).await?;
 
     #[cfg(feature = "systemd")]
     {
-        sd_notify::notify(true, &[NotifyState::Ready]).ok();
+        sd_notify::notify(false, &[NotifyState::Ready]).ok();
     }
 
     match args.command {
-        cli::PVectorCommand::Scan(_) => scan_action(config, &pool).await?,
-        cli::PVectorCommand::Release(_) => release_action(&config, &pool).await?,
+        cli::PVectorCommand::Scan(args) => scan_action(config, &pool, &args).await?,
+        cli::PVectorCommand::Release(args) => release_action(&config, &pool, &args).await?,
         cli::PVectorCommand::Maintenance(_) => maintenance_action(&pool).await?,
         cli::PVectorCommand::Reset(_) => reset_action(&pool).await?,
-        cli::PVectorCommand::GC(_) => gc_action(&config, &pool).await?,
+        cli::PVectorCommand::GC(gc_args) => gc_action(&config, &pool, &gc_args).await?,
         cli::PVectorCommand::Full(_) => full_action(config, &pool).await?,
-        cli::PVectorCommand::GenKey(_) => generate_key(args.config.as_str()).await?,
+        cli::PVectorCommand::GenKey(gen_args) => {
+            let templates = template::Templates::load(config.config.template_dir.as_deref())?;
+            generate_key(args.config.as_str(), gen_args.algo, &templates).await?
+        }
+        cli::PVectorCommand::Export(export_args) => {
+            export_action(&config, &pool, &export_args).await?
+        }
+        cli::PVectorCommand::Status(status_args) => status_action(&pool, &status_args).await?,
+        cli::PVectorCommand::PrintConfig(_) => unreachable!(),
+        cli::PVectorCommand::SoBreaks(so_breaks_args) => {
+            so_breaks_action(&config, &pool, &so_breaks_args).await?
+        }
+        cli::PVectorCommand::ElfIssues(elf_issues_args) => {
+            elf_issues_action(&pool, &elf_issues_args).await?
+        }
+        cli::PVectorCommand::Sodep(sodep_args) => sodep_action(&pool, &sodep_args).await?,
+        cli::PVectorCommand::Audit(audit_args) => audit_action(&config, &pool, &audit_args).await?,
+        cli::PVectorCommand::Import(import_args) => {
+            import_action(&config, &pool, &import_args).await?
+        }
+        cli::PVectorCommand::Deprecate(deprecate_args) => {
+            deprecate_action(&config, &pool, &deprecate_args).await?
+        }
     }
 
     Ok(())
 }
 
 async fn full_action(config: config::Config, pool: &PgPool) -> Result<()> {
-    scan_action(config.clone(), pool).await?;
-    let gc_result = gc_action(&config, pool).await;
-    let stage2_results = tokio::join!(maintenance_action(pool), release_action(&config, pool));
+    scan_action(config.clone(), pool, &cli::PVectorScan::default()).await?;
+    let gc_result = gc_action(&config, pool, &cli::PVectorGC::default()).await;
+    let release_args = cli::PVectorRelease::default();
+    let stage2_results = tokio::join!(
+        maintenance_action(pool),
+        release_action(&config, pool, &release_args)
+    );
     log_error!(gc_result, "garbage collecting");
     log_error!(stage2_results.0, "database maintenance");
     log_error!(stage2_results.1, "generating release files");
@@ -114,21 +158,90 @@ async fn maintenance_action(pool: &PgPool) -> Result<()> {
     Ok(())
 }
 
-async fn gc_action(config: &config::Config, pool: &PgPool) -> Result<()> {
-    let mirror_root = Path::new(&config.config.path);
-    gc::run_gc(pool, mirror_root).await?;
+async fn gc_action(config: &config::Config, pool: &PgPool, args: &cli::PVectorGC) -> Result<()> {
+    let mirror_root = config.config.local_mirror_root()?;
+    gc::run_gc(
+        pool,
+        mirror_root,
+        args.branch.as_deref(),
+        args.component.as_deref(),
+    )
+    .await?;
 
     Ok(())
 }
 
-async fn release_action(config: &config::Config, pool: &PgPool) -> Result<()> {
-    let mirror_root = Path::new(&config.config.path);
-    let pool_path = Path::new(&config.config.path).join("pool");
-    let topics = spawn_blocking(move || scan::discover_topics_components(pool_path)).await??;
+async fn release_action(
+    config: &config::Config,
+    pool: &PgPool,
+    args: &cli::PVectorRelease,
+) -> Result<()> {
+    let mirror_root = match args.output_dir {
+        Some(ref output_dir) => {
+            info!("Writing generated dists to {}", output_dir);
+            std::fs::create_dir_all(output_dir)?;
+            Path::new(output_dir)
+        }
+        None => config.config.local_mirror_root()?,
+    };
+    if let Some(ref branch) = args.branch {
+        return release_branch_action(config, pool, args, mirror_root, branch).await;
+    }
+    let source = scan::open_package_source(&config.config)?;
+    let topics = block_in_place(|| source.list_components())?;
     info!("{} topics discovered.", topics.len());
-    let needs_regenerate = generate::need_regenerate(pool, mirror_root).await?;
+    let mut needs_regenerate = generate::need_regenerate(pool, mirror_root, args.force).await?;
+    let mut bootstrap_branches = Vec::new();
+    if !config.config.default_architectures.is_empty() {
+        let known = db::list_branches(pool).await?;
+        bootstrap_branches =
+            generate::list_bootstrap_branches(&topics, &known, mirror_root, args.force).await;
+        for branch in bootstrap_branches.iter() {
+            info!(
+                "{} has no packages yet, generating empty indices for the default architectures.",
+                branch
+            );
+            needs_regenerate.push(branch.clone());
+        }
+    }
+    if let Some(ref arch) = args.arch {
+        info!("Only generating indices for {} (and all).", arch);
+    }
+    if !args.exclude_arch.is_empty() {
+        info!(
+            "Not generating indices for {}.",
+            args.exclude_arch.join(", ")
+        );
+    }
+    if args.contents_only {
+        info!("Only generating Contents files.");
+    }
+    if !args.exclude_package.is_empty() {
+        info!(
+            "Leaving {} out of the indices.",
+            args.exclude_package.join(", ")
+        );
+    }
+    let mut release_config = config::convert_branch_description_config(config);
+    if args.no_sign {
+        info!("Signing disabled, generating unsigned Release files.");
+        release_config.cert = None;
+    }
+    release_config.strict = args.strict;
+    release_config.excluded_architectures = args.exclude_arch.clone();
+    release_config.excluded_packages = args.exclude_package.clone();
+    release_config.published_root = Some(mirror_root.to_owned());
+    release_config.bootstrap_branches = bootstrap_branches;
+    release_config.templates = template::Templates::load(config.config.template_dir.as_deref())?;
+    // every generation query sees the same database state, even if a scan runs meanwhile
+    let snapshot = db::export_snapshot(pool).await?;
+    release_config.snapshot = Some(snapshot.id.clone());
+    let stats = generate::GenerationStats::default();
     let mut tasks = Vec::new();
-    let tempdir = tempfile::tempdir()?;
+    // generate on the same filesystem as the mirror so that publishing is a cheap rename
+    let tmp_root = config.config.generation_tmp_dir();
+    std::fs::create_dir_all(&tmp_root)?;
+    let tempdir = tempfile::tempdir_in(&tmp_root)?;
     let tempdir_path = tempdir.path().to_owned();
     for topic in topics {
         let mut skip = true;
@@ -146,38 +259,486 @@ async fn release_action(config: &config::Config, pool: &PgPool) -> Result<()> {
         let name_clone = name.clone();
         let tempdir_path = tempdir_path.clone();
         let tempdir_path_clone = tempdir_path.clone();
-        tasks.push(Either::Left(async move {
-            generate::render_packages_in_component(pool, &name, &tempdir_path).await
-        }));
+        let arch = args.arch.as_deref();
+        let release_config = &release_config;
+        let stats = &stats;
+        if !args.contents_only {
+            tasks.push(Either::Left(async move {
+                generate::render_packages_in_component(
+                    pool,
+                    &name,
+                    &tempdir_path,
+                    arch,
+                    release_config,
+                    stats,
+                )
+                .await
+            }));
+        }
         tasks.push(Either::Right(async move {
-            generate::render_contents_in_component(pool, &name_clone, &tempdir_path_clone).await
+            generate::render_contents_in_component(
+                pool,
+                &name_clone,
+                &tempdir_path_clone,
+                arch,
+                release_config,
+                stats,
+            )
+            .await
         }));
     }
-    let results = futures::future::join_all(tasks).await;
-    for result in results {
+    let mut arch_all_tasks = Vec::new();
+    if release_config.arch_all_component.is_some() && !args.contents_only {
+        for branch in needs_regenerate.iter() {
+            let tempdir_path = &tempdir_path;
+            let arch = args.arch.as_deref();
+            let release_config = &release_config;
+            let stats = &stats;
+            arch_all_tasks.push(generate::render_arch_all_component(
+                pool,
+                branch,
+                tempdir_path,
+                arch,
+                release_config,
+                stats,
+            ));
+        }
+    }
+    let (results, arch_all_results) = futures::future::join(
+        futures::future::join_all(tasks),
+        futures::future::join_all(arch_all_tasks),
+    )
+    .await;
+    for result in results.into_iter().chain(arch_all_results) {
         log_error!(result, "generating manifest");
     }
-    let release_config = config::convert_branch_description_config(config);
     let mirror_root = mirror_root.to_owned();
     let mirror_root_clone = mirror_root.clone();
-    spawn_blocking(move || {
+    let min_free_inodes = config.config.min_free_inodes;
+    let packages_diffs = release_config.packages_diffs.is_some();
+    // the Release files list what is left, so not pruned without updating them
+    let arch_all_component = release_config
+        .arch_all_component
+        .clone()
+        .filter(|_| !args.contents_only);
+    let regenerated = needs_regenerate.clone();
+    let cancel = Arc::new(AtomicBool::new(false));
+    let cancel_clone = cancel.clone();
+    tokio::spawn(watch_termination(cancel.clone()));
+    let published = spawn_blocking(move || -> Result<u64> {
         let new_dists = tempdir_path.join("dists");
         if !new_dists.exists() {
             info!("No new dists generated.");
             return Ok(0);
         }
-        fs_extra::dir::move_dir(
-            tempdir_path.join("dists"),
-            &mirror_root,
-            &fs_extra::dir::CopyOptions {
-                overwrite: true,
-                ..Default::default()
-            },
-        )
+        if !generate::is_same_filesystem(&new_dists, &mirror_root)? {
+            // make sure the copy won't fail halfway and leave a corrupted tree behind
+            let (required, entries) = generate::directory_size(&new_dists)?;
+            generate::check_free_space(&mirror_root, required)?;
+            generate::check_free_inodes(&mirror_root, entries, min_free_inodes)?;
+        }
+        let moved =
+            generate::move_directory(&new_dists, &mirror_root.join("dists"), &cancel_clone)?;
+        if packages_diffs {
+            pdiff::prune_packages_diffs(&mirror_root.join("dists"))?;
+        }
+        if let Some(ref all_component) = arch_all_component {
+            for branch in regenerated.iter() {
+                let branch_root = mirror_root.join("dists").join(branch);
+                generate::prune_arch_all_indices(&branch_root, all_component)?;
+            }
+        }
+
+        Ok(moved)
     })
-    .await??;
-    generate::render_releases(pool, &mirror_root_clone, release_config, &needs_regenerate).await?;
+    .await;
+    // past the publish, any signal terminates right away
+    cancel.store(true, Ordering::SeqCst);
+    published??;
+    let codenames = release_config.codenames.clone();
+    let signed = release_config.cert.is_some();
+    if args.contents_only {
+        warn!("Release files were not updated, the Contents checksums in them may be stale.");
+    } else {
+        generate::render_releases(pool, &mirror_root_clone, release_config, &needs_regenerate)
+            .await?;
+    }
+    drop(snapshot);
+    if config.config.codename_dist_links {
+        block_in_place(|| generate::link_codename_dists(&mirror_root_clone, &codenames))?;
+    }
+    for line in stats.summary() {
+        info!("{}", line);
+    }
+    if !args.contents_only {
+        let missing =
+            generate::reconcile_dists(pool, &mirror_root_clone, &needs_regenerate, signed).await?;
+        if !missing.is_empty() {
+            error!(
+                "{} repositories were not fully generated, their branches are broken:",
+                missing.len()
+            );
+            for repo in missing.iter() {
+                error!("  {}", repo);
+            }
+            if args.strict {
+                return Err(anyhow!(
+                    "{} repositories were not fully generated",
+                    missing.len()
+                ));
+            }
+        }
+    }
     info!("Generation finished.");
+    if args.validate && !args.contents_only {
+        validate_action(&mirror_root_clone, source.as_ref(), &needs_regenerate)?;
+    }
+
+    Ok(())
+}
+
+/// Check the indices of the regenerated branches against the pool
+fn validate_action(
+    mirror_root: &Path,
+    source: &dyn scan::PackageSource,
+    branches: &[String],
+) -> Result<()> {
+    let mut problems = Vec::new();
+    for branch in branches {
+        let branch_root = mirror_root.join("dists").join(branch);
+        if !branch_root.is_dir() {
+            continue;
+        }
+        info!("Validating the indices of {} ...", branch);
+        problems.extend(block_in_place(|| {
+            audit::validate_indices(&branch_root, source)
+        })?);
+    }
+    if !problems.is_empty() {
+        for problem in problems.iter() {
+            error!("{}", problem);
+        }
+        return Err(anyhow!(
+            "{} problems found in the generated indices",
+            problems.len()
+        ));
+    }
+    info!("The generated indices are consistent with the pool.");
+
+    Ok(())
+}
+
+/// Wait for SIGINT (or SIGTERM on Unix)
+async fn wait_termination() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => (),
+                    _ = sigterm.recv() => (),
+                }
+            }
+            Err(e) => {
+                warn!("Unable to listen for SIGTERM: {}", e);
+                tokio::signal::ctrl_c().await.ok();
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.ok();
+}
+
+/// Ask the publish to stop between two files on the first signal. Once the signal handlers
+/// are installed the default action is gone, so a second signal (or one arriving after the
+/// publish, when `cancel` is already set) terminates the process.
+async fn watch_termination(cancel: Arc<AtomicBool>) {
+    loop {
+        wait_termination().await;
+        if cancel.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        warn!(
+            "Stopping the publish after the current file, signal again to terminate immediately."
+        );
+    }
+}
+
+/// Regenerate the Release files of a single branch, without touching its indices
+async fn release_branch_action(
+    config: &config::Config,
+    pool: &PgPool,
+    args: &cli::PVectorRelease,
+    mirror_root: &Path,
+    branch: &str,
+) -> Result<()> {
+    if args.contents_only
+        || args.arch.is_some()
+        || !args.exclude_arch.is_empty()
+        || !args.exclude_package.is_empty()
+    {
+        return Err(anyhow!(
+            "--branch only regenerates the Release files and can't be used with --contents-only, --arch, --exclude-arch or --exclude-package"
+        ));
+    }
+    if !db::list_branches(pool).await?.iter().any(|b| b == branch) {
+        return Err(anyhow!("Branch {} is not known to the database", branch));
+    }
+    if !mirror_root.join("dists").join(branch).is_dir() {
+        return Err(anyhow!(
+            "{} has no dists under {}, run a full release first",
+            branch,
+            mirror_root.display()
+        ));
+    }
+    info!("Only regenerating the Release files of {}.", branch);
+    let mut release_config = config::convert_branch_description_config(config);
+    if args.no_sign {
+        info!("Signing disabled, generating unsigned Release files.");
+        release_config.cert = None;
+    }
+    release_config.strict = true;
+    release_config.templates = template::Templates::load(config.config.template_dir.as_deref())?;
+    generate::render_releases(pool, mirror_root, release_config, &[branch.to_string()]).await?;
+    info!("Generation finished.");
+
+    Ok(())
+}
+
+/// Write (or remove) the deprecation marker of a branch and regenerate its Release files
+async fn deprecate_action(
+    config: &config::Config,
+    pool: &PgPool,
+    args: &cli::PVectorDeprecate,
+) -> Result<()> {
+    let mirror_root = config.config.local_mirror_root()?;
+    let branch_root = mirror_root.join("dists").join(&args.branch);
+    if !branch_root.is_dir() {
+        return Err(anyhow!(
+            "{} has no dists under {}, run a release first",
+            args.branch,
+            mirror_root.display()
+        ));
+    }
+    if args.undo {
+        if !generate::clear_deprecation(&branch_root)? {
+            info!("{} is not deprecated.", args.branch);
+            return Ok(());
+        }
+        info!("{} is no longer deprecated.", args.branch);
+    } else {
+        generate::mark_deprecated(&branch_root, args.notice.as_deref().unwrap_or_default())?;
+        info!("{} is now deprecated.", args.branch);
+    }
+    let release_args = cli::PVectorRelease {
+        branch: Some(args.branch.clone()),
+        no_sign: args.no_sign,
+        ..Default::default()
+    };
+
+    release_branch_action(config, pool, &release_args, mirror_root, &args.branch).await
+}
+
+async fn export_action(
+    config: &config::Config,
+    pool: &PgPool,
+    args: &cli::PVectorExport,
+) -> Result<()> {
+    let packages =
+        export::export_packages(pool, &config.config, args.maintainer.as_deref()).await?;
+    info!("Exporting {} packages ...", packages.len());
+    serde_json::to_writer_pretty(std::io::stdout().lock(), &packages)?;
+    println!();
+
+    Ok(())
+}
+
+async fn status_action(pool: &PgPool, args: &cli::PVectorStatus) -> Result<()> {
+    let maintainer = args.maintainer.as_deref();
+    if let Some(maintainer) = maintainer {
+        println!("Packages maintained by {}", maintainer);
+    }
+    let scanner_versions = db::list_scanner_versions(pool, maintainer).await?;
+    println!("Packages by scanner version:");
+    for v in scanner_versions {
+        let last_scanned = v
+            .last_scanned
+            .map(|t| t.to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        println!(
+            "  {}: {} (last scanned: {})",
+            v.scanner_version.as_deref().unwrap_or("unknown"),
+            v.packages.unwrap_or(0),
+            last_scanned
+        );
+    }
+    let duplicates = db::list_duplicates(pool, maintainer).await?;
+    println!("Duplicated packages: {}", duplicates.len());
+    for d in duplicates {
+        println!("  {} ({} {})", d.filename, d.package, d.version);
+    }
+    // the maintainer of a package that failed to scan is unknown
+    if maintainer.is_none() {
+        let scan_errors = db::list_scan_errors(pool).await?;
+        println!("Packages failed to scan: {}", scan_errors.len());
+        for e in scan_errors {
+            println!("  {} (last seen: {})", e.filename, e.last_seen);
+            println!("    {}", e.error);
+        }
+    }
+
+    Ok(())
+}
+
+async fn import_action(
+    config: &config::Config,
+    pool: &PgPool,
+    args: &cli::PVectorImport,
+) -> Result<()> {
+    if args.index.is_empty() {
+        return Err(anyhow!("No index to import specified."));
+    }
+    let mut packages = Vec::new();
+    for index in args.index.iter() {
+        let content = tokio::fs::read(index).await?;
+        let listed = block_in_place(|| scan::read_packages_index(&content))
+            .map_err(|e| anyhow!("Failed to read {}: {}", index, e))?;
+        info!("{}: {} packages listed.", index, listed.len());
+        packages.extend(listed);
+    }
+    let (packages, version_failures) = scan::check_package_versions(pool, packages).await?;
+    if !version_failures.is_empty() {
+        // each of them is logged by the check
+        warn!("{} packages not imported.", version_failures.len());
+    }
+    let primary_component = &config.config.primary_component;
+    scan::update_changed_repos(pool, &packages, primary_component).await?;
+    scan::save_packages_to_db(
+        pool,
+        &packages,
+        primary_component,
+        &config.config.extra_packages_fields,
+        false,
+        config.config.commit_batch_size,
+    )
+    .await?;
+    info!("Import finished, the imported packages will be replaced as their files get scanned.");
+
+    Ok(())
+}
+
+async fn audit_action(
+    config: &config::Config,
+    pool: &PgPool,
+    args: &cli::PVectorAudit,
+) -> Result<()> {
+    let index = args.index.as_deref().map(Path::new);
+    let mirror_root = match index {
+        // the published indices are not read
+        Some(_) => Path::new(&config.config.path),
+        None => config.config.local_mirror_root()?,
+    };
+    let report = audit::audit_component(pool, mirror_root, &args.component, index).await?;
+    println!("Only in the index: {}", report.only_in_index.len());
+    for p in report.only_in_index.iter() {
+        println!("  {}", p);
+    }
+    println!("Only in the database: {}", report.only_in_database.len());
+    for p in report.only_in_database.iter() {
+        println!("  {}", p);
+    }
+    if !report.is_consistent() {
+        return Err(anyhow!(
+            "{} is inconsistent with the database",
+            args.component
+        ));
+    }
+
+    Ok(())
+}
+
+async fn so_breaks_action(
+    config: &config::Config,
+    pool: &PgPool,
+    args: &cli::PVectorSoBreaks,
+) -> Result<()> {
+    let branches = match args.branch {
+        Some(ref branch) => vec![branch.clone()],
+        None => db::list_branches(pool).await?,
+    };
+    let breaks = report::list_so_breaks(
+        pool,
+        args.branch.as_deref(),
+        &config.config.primary_component,
+        args.maintainer.as_deref(),
+    )
+    .await?;
+    info!(
+        "{} packages require missing shared libraries.",
+        breaks.len()
+    );
+    let reports = report::format_so_breaks(&branches, &breaks);
+
+    output_reports(reports, args.output_dir.as_deref())
+}
+
+async fn elf_issues_action(pool: &PgPool, args: &cli::PVectorElfIssues) -> Result<()> {
+    let branches = match args.branch {
+        Some(ref branch) => vec![branch.clone()],
+        None => db::list_branches(pool).await?,
+    };
+    let issues =
+        report::list_elf_issues(pool, args.branch.as_deref(), args.maintainer.as_deref()).await?;
+    info!(
+        "{} non-standard interpreters or library search paths found.",
+        issues.len()
+    );
+    let reports = report::format_elf_issues(&branches, &issues);
+
+    output_reports(reports, args.output_dir.as_deref())
+}
+
+async fn sodep_action(pool: &PgPool, args: &cli::PVectorSodep) -> Result<()> {
+    if args.provides == args.requires {
+        bail!("Specify either --provides or --requires.");
+    }
+    let sonames = report::list_sonames(pool, args.branch.as_deref(), args.requires).await?;
+    info!("Listing {} shared libraries ...", sonames.len());
+    serde_json::to_writer_pretty(std::io::stdout().lock(), &sonames)?;
+    println!();
+
+    Ok(())
+}
+
+/// Write the per-branch reports to `<output_dir>/<branch>.txt`, or print them
+fn output_reports(reports: BTreeMap<String, String>, output_dir: Option<&str>) -> Result<()> {
+    match output_dir {
+        Some(output_dir) => {
+            let output_dir = Path::new(output_dir);
+            std::fs::create_dir_all(output_dir)?;
+            for (branch, report) in reports {
+                std::fs::write(output_dir.join(format!("{}.txt", branch)), report)?;
+            }
+        }
+        None => {
+            for report in reports.values() {
+                print!("{}", report);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn print_config(config: &config::Config, json: bool) -> Result<()> {
+    let effective = config::effective_config(config);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&effective)?);
+    } else {
+        print!("{}", toml::to_string(&effective)?);
+    }
 
     Ok(())
 }
@@ -200,7 +761,11 @@ fn ask_for_key_info() -> Result<String> {
     Ok(format!("{} <{}>", name, email))
 }
 
-async fn generate_key(config: &str) -> Result<()> {
+async fn generate_key(
+    config: &str,
+    algo: sign::KeyAlgorithm,
+    templates: &template::Templates,
+) -> Result<()> {
     use secrecy::ExposeSecret;
     use time::OffsetDateTime;
     use tokio::fs::{create_dir_all, File};
@@ -210,7 +775,7 @@ async fn generate_key(config: &str) -> Result<()> {
     let path =
         Path::new(&std::env::var("HOME").unwrap_or_else(|_| ".".to_string())).join("pv-keys");
     create_dir_all(&path).await?;
-    let cert = spawn_blocking(move || sign::generate_certificate(&userid)).await??;
+    let cert = spawn_blocking(move || sign::generate_certificate(&userid, algo)).await??;
     let priv_path = path.join(format!("{}.key", cert.id));
     let pub_path = path.join(format!("{}.pub", cert.id));
     let mut p_file = File::create(&priv_path).await?;
@@ -230,40 +795,42 @@ async fn generate_key(config: &str) -> Result<()> {
         priv_path.display().to_string(),
         expiry_format,
         config,
+        templates,
     )?;
     println!("\n{}", inst);
 
     Ok(())
 }
 
-async fn collect_package_changes(
-    pool: &PgPool,
-    packages: &[scan::PackageMeta],
-    removed: &[PathBuf],
-) -> Result<(Vec<ipc::PVMessage>, Vec<ipc::PVMessage>)> {
-    let result = tokio::try_join!(
-        scan::what_changed(pool, packages),
-        db::get_removed_packages_message(pool, removed)
-    )?;
-
-    Ok(result)
-}
-
-async fn scan_action(config: config::Config, pool: &PgPool) -> Result<()> {
-    let pool_path = Path::new(&config.config.path).join("pool");
-    let pool_path_clone = pool_path.clone();
-    let mirror_root = config.config.path.clone();
-    let mirror_root_path = Path::new(&mirror_root).to_owned();
-    let topics = spawn_blocking(move || scan::discover_topics_components(pool_path)).await??;
+async fn scan_action(config: config::Config, pool: &PgPool, args: &cli::PVectorScan) -> Result<()> {
+    let source: Arc<dyn scan::PackageSource> = scan::open_package_source(&config.config)?.into();
+    if args.refresh_metadata {
+        if args.from_list.is_some() {
+            return Err(anyhow!("--refresh-metadata can't be used with --from-list"));
+        }
+        return refresh_metadata(pool, source.as_ref()).await;
+    }
+    if let Some(ref list) = args.from_list {
+        return scan_from_list(config, pool, args, source.as_ref(), Path::new(list)).await;
+    }
+    let source_clone = source.clone();
+    let topics = spawn_blocking(move || source_clone.list_components()).await??;
     info!("{} topics discovered.", topics.len());
-    let files = spawn_blocking(move || scan::collect_all_packages(pool_path_clone)).await??;
+    let ignored_extensions = config.config.scan_ignore_extensions.clone();
+    let scan_udeb = config.config.scan_udeb;
+    let source_clone = source.clone();
+    let files = spawn_blocking(move || source_clone.list_packages(&ignored_extensions, scan_udeb))
+        .await??;
     info!("{} deb files discovered.", files.len());
+    resume_scan_queue(&config, pool, args, source.as_ref(), &files).await?;
     info!("Collecting packages information from database ...");
-    let db_packages = list_all_packages(pool, &topics).await?;
+    let mut db_packages = list_all_packages(pool, &topics).await?;
     info!("Database knows {} packages.", db_packages.len());
+    // imported packages stay until their files show up in the pool and get scanned
+    db_packages.retain(|p| !p.imported.unwrap_or(false));
     info!("Pre-scanning packages to determine which packages are different ...");
     let (delete, scanned, needs_update) =
-        block_in_place(move || scan::validate_packages(mirror_root, &db_packages))?;
+        block_in_place(|| scan::validate_packages(source.as_ref(), &db_packages))?;
     let changed = get_changed_packages(&files, &scanned);
     info!(
         "{} up to date, {} deleted, {} changed.",
@@ -273,56 +840,335 @@ async fn scan_action(config: config::Config, pool: &PgPool) -> Result<()> {
     );
     if !needs_update.is_empty() {
         info!("{} packages needs metadata refresh.", needs_update.len());
-        scan::update_unchanged_packages(pool, needs_update, &mirror_root_path).await?;
+        scan::update_unchanged_packages(pool, needs_update).await?;
     }
     if delete.is_empty() && changed.is_empty() {
+        scan::update_scan_errors(pool, &[], None).await?;
         info!("Nothing to scan.");
         return Ok(());
     }
-    #[cfg(feature = "systemd")]
-    {
-        let changed_number = delete.len() + changed.len();
-        let message = format!("Processing {} packages ...", changed_number);
-        sd_notify::notify(true, &[NotifyState::Status(&message)]).ok();
+    // persist the work, so that an interrupted scan can be resumed by the next run
+    db::set_scan_queue(pool, &changed).await?;
+    let deleted = collect_removed_packages(delete);
+    scan_and_save(
+        config,
+        pool,
+        args,
+        source.as_ref(),
+        &changed,
+        &deleted,
+        None,
+    )
+    .await
+}
+
+/// Scan the packages left in the queue by an interrupted scan (as found in the pool listing),
+/// before looking for changes
+async fn resume_scan_queue(
+    config: &config::Config,
+    pool: &PgPool,
+    args: &cli::PVectorScan,
+    source: &dyn scan::PackageSource,
+    files: &[scan::PackageEntry],
+) -> Result<()> {
+    let queued = db::list_scan_queue(pool).await?;
+    if queued.is_empty() {
+        return Ok(());
     }
+    info!(
+        "Resuming an interrupted scan, {} packages queued ...",
+        queued.len()
+    );
+    // packages gone in the meantime are left to the fresh scan
+    let listed = files
+        .iter()
+        .map(|f| (f.filename.as_str(), f))
+        .collect::<HashMap<_, _>>();
+    let entries = queued
+        .iter()
+        .filter_map(|filename| listed.get(filename.as_str()).copied())
+        .collect::<Vec<_>>();
+    scan_and_save(
+        config.clone(),
+        pool,
+        args,
+        source,
+        &entries,
+        &[],
+        Some(&queued),
+    )
+    .await?;
+    db::remove_from_scan_queue(pool, &queued).await?;
+
+    Ok(())
+}
+
+/// Refresh the file metadata of the known packages, skipping the content scan entirely
+async fn refresh_metadata(pool: &PgPool, source: &dyn scan::PackageSource) -> Result<()> {
+    let topics = block_in_place(|| source.list_components())?;
+    let mut db_packages = list_all_packages(pool, &topics).await?;
+    db_packages.retain(|p| !p.imported.unwrap_or(false));
+    info!("Refreshing metadata of {} packages ...", db_packages.len());
+    block_in_place(|| source.preload())?;
+    let needs_update = block_in_place(|| scan::collect_stale_metadata(source, &db_packages));
+    info!("{} packages needs metadata refresh.", needs_update.len());
+    scan::update_unchanged_packages(pool, needs_update).await?;
+
+    Ok(())
+}
+
+/// Scan only the packages listed in the file, skipping the pool walk and the pre-scan
+async fn scan_from_list(
+    config: config::Config,
+    pool: &PgPool,
+    args: &cli::PVectorScan,
+    source: &dyn scan::PackageSource,
+    list: &Path,
+) -> Result<()> {
+    let list = if list == Path::new("-") {
+        std::io::read_to_string(std::io::stdin())?
+    } else {
+        tokio::fs::read_to_string(list).await?
+    };
+    let mut changed = Vec::new();
+    let mut deleted = Vec::new();
+    let mut listed = Vec::new();
+    for path in scan::split_package_list(&list) {
+        let filename = format!("pool/{}", path);
+        match block_in_place(|| source.stat(&filename))? {
+            Some(entry) => changed.push(entry),
+            None => deleted.push(PathBuf::from(&filename)),
+        }
+        listed.push(filename);
+    }
+    info!(
+        "{} packages listed: {} changed, {} deleted.",
+        listed.len(),
+        changed.len(),
+        deleted.len()
+    );
+    if listed.is_empty() {
+        info!("Nothing to scan.");
+        return Ok(());
+    }
+    let changed = changed.iter().collect::<Vec<_>>();
+
+    scan_and_save(
+        config,
+        pool,
+        args,
+        source,
+        &changed,
+        &deleted,
+        Some(&listed),
+    )
+    .await
+}
+
+async fn scan_and_save(
+    config: config::Config,
+    pool: &PgPool,
+    args: &cli::PVectorScan,
+    source: &dyn scan::PackageSource,
+    changed: &[&scan::PackageEntry],
+    deleted: &[PathBuf],
+    scanned: Option<&[String]>,
+) -> Result<()> {
+    status::set_status(&format!(
+        "Processing {} packages ...",
+        deleted.len() + changed.len()
+    ));
     info!("Starting scanner ...");
-    let mirror_root = mirror_root_path.clone();
-    let packages =
-        block_in_place(move || scan::scan_packages_advanced(&changed, &mirror_root_path));
-    info!("Scan finished.");
-    let deleted = collect_removed_packages(delete, &mirror_root);
+    let strict = args.strict;
+    let primary_component = config.config.primary_component.clone();
+    let extra_fields = config.config.extra_packages_fields.clone();
+    let commit_batch_size = config.config.commit_batch_size;
+    let scan_elf = config.config.scan_elf;
+    if !scan_elf {
+        info!("ELF scanning is disabled, not collecting the shared library dependencies.");
+    }
+    let keyring = if config.config.require_package_signatures {
+        let path = config.config.package_keyring.as_deref().ok_or_else(|| {
+            anyhow!("require_package_signatures is set, but no package_keyring is configured")
+        })?;
+        let keyring = sign::load_keyring(path)?;
+        info!(
+            "Only accepting packages signed by the {} keys in {}.",
+            keyring.len(),
+            path
+        );
+        Some(keyring)
+    } else {
+        None
+    };
+    let (excluded, changed): (Vec<&scan::PackageEntry>, Vec<&scan::PackageEntry>) = changed
+        .iter()
+        .partition(|entry| scan::is_package_excluded(&entry.filename, &args.exclude_package));
+    let excluded = excluded
+        .iter()
+        .map(|entry| {
+            info!("Skipping excluded package {}", entry.filename);
+            entry.filename.clone()
+        })
+        .collect::<Vec<_>>();
+    // scanned again by the next scans without the filter
+    db::remove_from_scan_queue(pool, &excluded).await?;
+    let notify = config.config.change_notifier.is_some();
+    let max_retries = args.max_retries.unwrap_or(DEFAULT_IPC_RETRIES);
     // IPC operations
     // TODO: Move these to somewhere else maybe?
-    ipc_publish(config, pool, &packages, &deleted).await?;
+    let removed = if notify {
+        db::get_removed_packages_message(pool, deleted).await?
+    } else {
+        Vec::new()
+    };
     info!("Deleting {} packages from database ...", deleted.len());
-    db::remove_packages_by_path(pool, &deleted).await?;
-    info!("Saving changes to database ...");
-    scan::update_changed_repos(pool, &packages).await?;
-    scan::save_packages_to_db(pool, &packages).await?;
+    db::remove_packages_by_path(pool, deleted).await?;
+    ipc_publish(&config, pool, Vec::new(), removed, max_retries).await?;
+    // each chunk is saved (and dequeued) before the next one is scanned, so that an interrupted
+    // scan only loses the chunk in progress
+    let chunk_size = commit_batch_size
+        .filter(|size| *size > 0)
+        .unwrap_or(changed.len())
+        .max(1);
+    let mut failures = Vec::new();
+    let mut done = 0;
+    for chunk in changed.chunks(chunk_size) {
+        if chunk.len() < changed.len() {
+            info!(
+                "Scanning packages {}-{} of {} ...",
+                done + 1,
+                done + chunk.len(),
+                changed.len()
+            );
+        }
+        let (packages, mut chunk_failures) = block_in_place(|| {
+            scan::scan_packages_advanced(source, chunk, strict, keyring.as_deref(), scan_elf)
+        });
+        let (packages, version_failures) = scan::check_package_versions(pool, packages).await?;
+        chunk_failures.extend(version_failures);
+        // compared against the database, so collected before saving the changes
+        let changes = if notify {
+            info!("Collecting changed packages ...");
+            scan::what_changed(pool, &packages, &primary_component).await?
+        } else {
+            Vec::new()
+        };
+        info!("Saving changes to database ...");
+        scan::update_changed_repos(pool, &packages, &primary_component).await?;
+        let save_failures = scan::save_packages_to_db(
+            pool,
+            &packages,
+            &primary_component,
+            &extra_fields,
+            args.keep_going,
+            commit_batch_size,
+        )
+        .await?;
+        if !save_failures.is_empty() {
+            warn!("{} packages failed to save:", save_failures.len());
+            for failure in save_failures.iter() {
+                warn!("{}: {}", failure.filename, failure.error);
+            }
+        }
+        // the packages failing to save are not announced
+        let changes = changes
+            .into_iter()
+            .filter(|(filename, _)| !save_failures.iter().any(|f| &f.filename == filename))
+            .map(|(_, message)| message)
+            .collect::<Vec<_>>();
+        ipc_publish(&config, pool, changes, Vec::new(), max_retries).await?;
+        chunk_failures.extend(save_failures);
+        // failed packages are retried by the next scan anyway
+        let failed = chunk_failures
+            .iter()
+            .map(|f| f.filename.clone())
+            .collect::<Vec<_>>();
+        db::remove_from_scan_queue(pool, &failed).await?;
+        failures.extend(chunk_failures);
+        done += chunk.len();
+    }
+    info!("Scan finished.");
+    if !failures.is_empty() {
+        warn!("{} packages failed to scan or save.", failures.len());
+    }
+    // the stored errors of the packages not scanned (e.g. the excluded ones) are kept
+    let mut scanned = match scanned {
+        Some(scanned) => scanned.to_vec(),
+        None => changed
+            .iter()
+            .map(|entry| entry.filename.clone())
+            .chain(
+                deleted
+                    .iter()
+                    .map(|path| path.to_string_lossy().to_string()),
+            )
+            .collect(),
+    };
+    scanned.retain(|filename| !excluded.contains(filename));
+    scan::update_scan_errors(pool, &failures, Some(&scanned)).await?;
     info!("Saving completed.");
+    if args.fail_on_error && !failures.is_empty() {
+        error!("{} packages failed to scan or save:", failures.len());
+        for failure in failures.iter() {
+            error!("  {}", failure.filename);
+        }
+        return Err(anyhow!(
+            "{} packages failed to scan or save",
+            failures.len()
+        ));
+    }
 
     Ok(())
 }
 
+/// Default number of retries when publishing the change notifications
+const DEFAULT_IPC_RETRIES: u32 = 3;
+
 async fn ipc_publish(
-    config: config::Config,
+    config: &config::Config,
     pool: &PgPool,
-    packages: &[scan::PackageMeta],
-    deleted: &[PathBuf],
+    changed: Vec<ipc::PVMessage>,
+    removed: Vec<ipc::PVMessage>,
+    max_retries: u32,
 ) -> Result<()> {
     if let Some(ref ipc_address) = config.config.change_notifier {
-        let mut socket = ipc::redis_connect(ipc_address)?;
-        // sleep 1 second so that the client is ready
-        sleep(Duration::from_secs(1)).await;
-        info!("Collecting changed packages ...");
-        let (changed, removed) = collect_package_changes(pool, packages, deleted).await?;
+        // undelivered notifications from the previous runs go first
+        let pending = db::list_pending_notifications(pool).await?;
+        if !pending.is_empty() {
+            info!("{} undelivered notifications queued.", pending.len());
+        }
+        let mut payloads = pending
+            .iter()
+            .map(|p| p.payload.clone())
+            .collect::<Vec<_>>();
+        for messages in [removed, changed] {
+            if !messages.is_empty() {
+                payloads.push(ipc::serialize_pv_messages(&messages)?);
+            }
+        }
         info!("Publishing changes to {} ...", ipc_address);
-        spawn_blocking(move || -> Result<()> {
-            ipc::publish_pv_messages(&removed, &mut socket)?;
-            ipc::publish_pv_messages(&changed, &mut socket)?;
-            Ok(())
+        let insecure = config.config.change_notifier_insecure;
+        let to_publish = payloads.clone();
+        let ipc_address = ipc_address.clone();
+        let published = spawn_blocking(move || {
+            ipc::publish_payloads(&ipc_address, insecure, &to_publish, max_retries)
         })
-        .await??;
+        .await?;
+        let delivered = pending
+            .iter()
+            .take(published)
+            .map(|p| p.id)
+            .collect::<Vec<_>>();
+        db::remove_pending_notifications(pool, &delivered).await?;
+        let undelivered = &payloads[published.max(pending.len())..];
+        if !undelivered.is_empty() {
+            warn!(
+                "Failed to publish {} notifications, queueing them for the next run.",
+                undelivered.len()
+            );
+            db::add_pending_notifications(pool, undelivered).await?;
+        }
     }
 
     Ok(())
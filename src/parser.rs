@@ -2,10 +2,10 @@ use std::collections::HashMap;
 
 use nom::{
     bytes::complete::{tag, take_until},
-    character::complete::{char, space0},
-    combinator::{map, verify},
-    multi::many1,
-    sequence::{separated_pair, terminated, tuple},
+    character::complete::{char, one_of, space0},
+    combinator::{map, recognize, verify},
+    multi::{many0, many1},
+    sequence::{pair, preceded, separated_pair, terminated, tuple},
     IResult,
 };
 
@@ -30,13 +30,27 @@ fn single_line(input: &[u8]) -> IResult<&[u8], &[u8]> {
     take_until("\n")(input)
 }
 
+/// A continuation line of a multi-line field (starting with a space or a tab)
+#[inline]
+fn continuation_line(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    preceded(tuple((char('\n'), one_of(" \t"))), single_line)(input)
+}
+
+/// A field value, including its continuation lines (kept as they are, newlines and indentation included)
+#[inline]
+fn field_value(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    recognize(pair(single_line, many0(continuation_line)))(input)
+}
+
 #[inline]
 fn key_value(input: &[u8]) -> IResult<&[u8], (&[u8], &[u8])> {
-    separated_pair(key_name, separator, single_line)(input)
+    separated_pair(key_name, separator, field_value)(input)
 }
 
+type KeyValuePairs<'a> = Vec<(&'a [u8], &'a [u8])>;
+
 #[inline]
-fn single_package(input: &[u8]) -> IResult<&[u8], Vec<(&[u8], &[u8])>> {
+fn single_package(input: &[u8]) -> IResult<&[u8], KeyValuePairs<'_>> {
     many1(terminated(key_value, tag("\n")))(input)
 }
 
@@ -52,6 +66,13 @@ pub fn single_package_map(input: &[u8]) -> IResult<&[u8], HashMap<&[u8], &[u8]>>
     Ok((ret, map))
 }
 
+pub type PackageMap<'a> = HashMap<&'a [u8], &'a [u8]>;
+
+/// Parse a stream of packages (e.g. a `Packages` index) separated by blank lines
+pub fn parse_packages_stream(input: &[u8]) -> IResult<&[u8], Vec<PackageMap<'_>>> {
+    many0(terminated(single_package_map, many0(tag("\n"))))(input)
+}
+
 #[test]
 fn test_key_name() {
     let test = &b"name: value"[..];
@@ -98,6 +119,20 @@ fn test_key_value() {
     );
 }
 
+#[test]
+fn test_multi_line_value() {
+    let test = &b"Description: synopsis\n body\n .\n\tmore\nPackage: zsync\n"[..];
+    assert_eq!(
+        key_value(test),
+        Ok((
+            &b"\nPackage: zsync\n"[..],
+            (&b"Description"[..], &b"synopsis\n body\n .\n\tmore"[..])
+        ))
+    );
+    let (_, map) = single_package_map(test).unwrap();
+    assert_eq!(map[&b"Package"[..]], b"zsync");
+}
+
 #[test]
 fn test_package() {
     let test = &b"Package: zsync\nVersion: 0.6.2-1\nSection: net\nArchitecture: amd64\nInstalled-Size: 256\n\n"[..];
@@ -115,3 +150,13 @@ fn test_package() {
         ))
     );
 }
+
+#[test]
+fn test_packages_stream() {
+    let test = &b"Package: zsync\nVersion: 0.6.2-1\n\nPackage: zstd\nVersion: 1.5.6\n\n"[..];
+    let (rest, packages) = parse_packages_stream(test).unwrap();
+    assert!(rest.is_empty());
+    assert_eq!(packages.len(), 2);
+    assert_eq!(packages[1][&b"Package"[..]], b"zstd");
+    assert_eq!(packages[1][&b"Version"[..]], b"1.5.6");
+}
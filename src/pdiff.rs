@@ -0,0 +1,330 @@
+//! Incremental Packages indices (pdiff), see
+//! <https://wiki.debian.org/DebianRepository/Format#Diffs>
+
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::Result;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{info, warn};
+use similar::{DiffOp, TextDiff};
+
+use crate::scan::sha256sum;
+
+/// Directory of the diffs, next to the Packages file
+pub const DIFF_DIR: &str = "Packages.diff";
+
+/// A patch in the diff index
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DiffEntry {
+    /// Name of the patch (without the `.gz` extension)
+    name: String,
+    /// SHA256 and size of the Packages file the patch applies to
+    history: (String, u64),
+    /// SHA256 and size of the patch
+    patch: (String, u64),
+    /// SHA256 and size of the compressed patch
+    download: (String, u64),
+}
+
+/// The `Packages.diff/Index` file
+#[derive(Debug, Default, PartialEq, Eq)]
+struct DiffIndex {
+    /// SHA256 and size of the current Packages file
+    current: Option<(String, u64)>,
+    entries: Vec<DiffEntry>,
+}
+
+impl DiffIndex {
+    fn parse(content: &str) -> DiffIndex {
+        let mut index = DiffIndex::default();
+        let mut history = Vec::new();
+        let mut patches = Vec::new();
+        let mut downloads = Vec::new();
+        let mut section = None;
+        for line in content.lines() {
+            if let Some(item) = line.strip_prefix(' ') {
+                let fields = item.split_whitespace().collect::<Vec<_>>();
+                let [hash, size, name] = fields[..] else {
+                    continue;
+                };
+                let Ok(size) = size.parse::<u64>() else {
+                    continue;
+                };
+                let item = (name.to_string(), (hash.to_string(), size));
+                match section {
+                    Some("SHA256-History") => history.push(item),
+                    Some("SHA256-Patches") => patches.push(item),
+                    Some("SHA256-Download") => downloads.push(item),
+                    _ => (),
+                }
+                continue;
+            }
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            section = Some(key);
+            if key == "SHA256-Current" {
+                let mut fields = value.split_whitespace();
+                if let (Some(hash), Some(Ok(size))) =
+                    (fields.next(), fields.next().map(|s| s.parse()))
+                {
+                    index.current = Some((hash.to_string(), size));
+                }
+            }
+        }
+        for (name, history) in history {
+            let patch = patches.iter().find(|p| p.0 == name);
+            let download_name = format!("{}.gz", name);
+            let download = downloads.iter().find(|p| p.0 == download_name);
+            if let (Some(patch), Some(download)) = (patch, download) {
+                index.entries.push(DiffEntry {
+                    name,
+                    history,
+                    patch: patch.1.clone(),
+                    download: download.1.clone(),
+                });
+            }
+        }
+
+        index
+    }
+
+    fn render(&self) -> String {
+        let mut output = String::new();
+        if let Some((ref hash, size)) = self.current {
+            output.push_str(&format!("SHA256-Current: {} {}\n", hash, size));
+        }
+        output.push_str("SHA256-History:\n");
+        for e in self.entries.iter() {
+            output.push_str(&format!(" {} {} {}\n", e.history.0, e.history.1, e.name));
+        }
+        output.push_str("SHA256-Patches:\n");
+        for e in self.entries.iter() {
+            output.push_str(&format!(" {} {} {}\n", e.patch.0, e.patch.1, e.name));
+        }
+        output.push_str("SHA256-Download:\n");
+        for e in self.entries.iter() {
+            output.push_str(&format!(
+                " {} {} {}.gz\n",
+                e.download.0, e.download.1, e.name
+            ));
+        }
+
+        output
+    }
+}
+
+/// Render an ed script turning `old` into `new`, as applied by APT's `rred`.
+/// Returns `None` if the new text can't be represented (a line consisting of a single `.`).
+fn ed_script(old: &str, new: &str) -> Option<String> {
+    let diff = TextDiff::from_lines(old, new);
+    let new_lines = diff.new_slices();
+    let mut commands = Vec::new();
+    for op in diff.ops() {
+        let (command, inserted) = match *op {
+            DiffOp::Equal { .. } => continue,
+            DiffOp::Delete {
+                old_index, old_len, ..
+            } => (format!("{}d\n", line_range(old_index, old_len)), None),
+            DiffOp::Insert {
+                old_index,
+                new_index,
+                new_len,
+            } => (
+                format!("{}a\n", old_index),
+                Some(&new_lines[new_index..new_index + new_len]),
+            ),
+            DiffOp::Replace {
+                old_index,
+                old_len,
+                new_index,
+                new_len,
+            } => (
+                format!("{}c\n", line_range(old_index, old_len)),
+                Some(&new_lines[new_index..new_index + new_len]),
+            ),
+        };
+        let mut command = command;
+        if let Some(lines) = inserted {
+            for line in lines {
+                if line.trim_end_matches('\n') == "." {
+                    return None;
+                }
+                command.push_str(line);
+                if !line.ends_with('\n') {
+                    command.push('\n');
+                }
+            }
+            command.push_str(".\n");
+        }
+        commands.push(command);
+    }
+    // the commands are applied from the bottom up, so that the line numbers stay valid
+    commands.reverse();
+
+    Some(commands.concat())
+}
+
+/// 1-based line range of the ed commands
+fn line_range(index: usize, len: usize) -> String {
+    if len == 1 {
+        format!("{}", index + 1)
+    } else {
+        format!("{},{}", index + 1, index + len)
+    }
+}
+
+/// Write the diff from the published Packages file (in `published_dir`) to the new one
+/// (in `new_dir`) as `Packages.diff/<name>.gz`, and the diff index listing up to `keep`
+/// patches. The patches of the previous runs are left in the published directory, see
+/// [prune_packages_diffs].
+pub fn update_packages_diff(
+    published_dir: &Path,
+    new_dir: &Path,
+    name: &str,
+    keep: usize,
+) -> Result<()> {
+    let new = std::fs::read_to_string(new_dir.join("Packages"))?;
+    let current = (sha256sum(new.as_bytes())?, new.len() as u64);
+    let diff_dir = new_dir.join(DIFF_DIR);
+    std::fs::create_dir_all(&diff_dir)?;
+    let mut index = match std::fs::read_to_string(published_dir.join(DIFF_DIR).join("Index")) {
+        Ok(content) => DiffIndex::parse(&content),
+        Err(_) => DiffIndex::default(),
+    };
+    let old = match std::fs::read_to_string(published_dir.join("Packages")) {
+        Ok(old) => Some(old),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    if let Some(old) = old {
+        let previous = (sha256sum(old.as_bytes())?, old.len() as u64);
+        if index.current.as_ref() != Some(&previous) {
+            // the history does not lead to the published Packages file, start over
+            index.entries.clear();
+        }
+        if previous != current {
+            if index.entries.iter().any(|e| e.name == name) {
+                warn!(
+                    "{}: a diff named {} already exists, discarding the diff history.",
+                    new_dir.display(),
+                    name
+                );
+                index.entries.clear();
+            } else if let Some(script) = ed_script(&old, &new) {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+                encoder.write_all(script.as_bytes())?;
+                let compressed = encoder.finish()?;
+                std::fs::write(diff_dir.join(format!("{}.gz", name)), &compressed)?;
+                index.entries.push(DiffEntry {
+                    name: name.to_string(),
+                    history: previous,
+                    patch: (sha256sum(script.as_bytes())?, script.len() as u64),
+                    download: (sha256sum(&compressed[..])?, compressed.len() as u64),
+                });
+            } else {
+                warn!(
+                    "{}: the changes can't be expressed as a diff, discarding the diff history.",
+                    new_dir.display()
+                );
+                index.entries.clear();
+            }
+        }
+    }
+    if index.entries.len() > keep {
+        index.entries.drain(..index.entries.len() - keep);
+    }
+    index.current = Some(current);
+    std::fs::write(diff_dir.join("Index"), index.render())?;
+
+    Ok(())
+}
+
+/// Remove the patches no longer listed in the diff indices under the dists directory
+pub fn prune_packages_diffs(dists: &Path) -> Result<()> {
+    for entry in walkdir::WalkDir::new(dists) {
+        let entry = entry?;
+        if !entry.file_type().is_dir() || entry.file_name() != DIFF_DIR {
+            continue;
+        }
+        let Ok(content) = std::fs::read_to_string(entry.path().join("Index")) else {
+            continue;
+        };
+        let index = DiffIndex::parse(&content);
+        for file in std::fs::read_dir(entry.path())? {
+            let file = file?;
+            let name = file.file_name();
+            let name = name.to_string_lossy();
+            let listed = name == "Index"
+                || name
+                    .strip_suffix(".gz")
+                    .is_some_and(|n| index.entries.iter().any(|e| e.name == n));
+            if !listed {
+                info!("Deleting old diff {} ...", file.path().display());
+                std::fs::remove_file(file.path())?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_ed_script() {
+    let old = "a\nb\nc\nd\n";
+    assert_eq!(ed_script(old, old).unwrap(), "");
+    assert_eq!(ed_script(old, "a\nc\nd\n").unwrap(), "2d\n");
+    assert_eq!(ed_script(old, "a\nb\nc\nd\ne\n").unwrap(), "4a\ne\n.\n");
+    assert_eq!(
+        ed_script(old, "x\nb\nc\ny\nz\n").unwrap(),
+        "4c\ny\nz\n.\n1c\nx\n.\n"
+    );
+    assert!(ed_script(old, "a\n.\n").is_none());
+}
+
+#[test]
+fn test_diff_index() {
+    let index = DiffIndex {
+        current: Some(("cc".to_string(), 30)),
+        entries: vec![DiffEntry {
+            name: "2026-10-16-0000.00".to_string(),
+            history: ("aa".to_string(), 10),
+            patch: ("bb".to_string(), 20),
+            download: ("dd".to_string(), 15),
+        }],
+    };
+    let rendered = index.render();
+    assert_eq!(
+        rendered,
+        "SHA256-Current: cc 30
+SHA256-History:
+ aa 10 2026-10-16-0000.00
+SHA256-Patches:
+ bb 20 2026-10-16-0000.00
+SHA256-Download:
+ dd 15 2026-10-16-0000.00.gz
+"
+    );
+    assert_eq!(DiffIndex::parse(&rendered), index);
+}
+
+#[test]
+fn test_update_packages_diff() {
+    let published = tempfile::tempdir().unwrap();
+    let new = tempfile::tempdir().unwrap();
+    std::fs::write(published.path().join("Packages"), "Package: a\n\n").unwrap();
+    std::fs::write(new.path().join("Packages"), "Package: a\n\nPackage: b\n\n").unwrap();
+    update_packages_diff(published.path(), new.path(), "first", 2).unwrap();
+    let index = std::fs::read_to_string(new.path().join(DIFF_DIR).join("Index")).unwrap();
+    let index = DiffIndex::parse(&index);
+    assert_eq!(index.entries.len(), 1);
+    assert_eq!(index.entries[0].name, "first");
+    assert!(new.path().join(DIFF_DIR).join("first.gz").is_file());
+    // unrelated files are pruned, listed patches are kept
+    std::fs::write(new.path().join(DIFF_DIR).join("stale.gz"), "").unwrap();
+    prune_packages_diffs(new.path()).unwrap();
+    assert!(new.path().join(DIFF_DIR).join("first.gz").is_file());
+    assert!(!new.path().join(DIFF_DIR).join("stale.gz").exists());
+}
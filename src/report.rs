@@ -0,0 +1,425 @@
+//! Reports generated from the database
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+
+use anyhow::Result;
+use serde::Serialize;
+use sqlx::PgPool;
+
+/// A package requiring a shared library that no package provides
+#[derive(Debug)]
+pub struct SoBreak {
+    pub branch: String,
+    pub package: String,
+    pub version: String,
+    pub architecture: String,
+    pub soname: String,
+}
+
+/// List the (latest versions of the) packages requiring a shared library that is not provided
+/// by any package visible to them: in the same component or the primary component of the same
+/// branch, or of a branch at the same or a lower testing level (e.g. `stable` for topic branches).
+/// The matching rules follow `v_so_breaks`.
+pub async fn list_so_breaks(
+    pool: &PgPool,
+    branch: Option<&str>,
+    primary_component: &str,
+    maintainer: Option<&str>,
+) -> Result<Vec<SoBreak>> {
+    let records = sqlx::query!(
+        r#"WITH latest AS (
+    SELECT DISTINCT ON (repo, package) package, version, repo, maintainer FROM pv_packages
+    WHERE debtime IS NOT NULL ORDER BY repo, package, _vercomp DESC
+)
+SELECT DISTINCT rd.branch, sd.package AS "package!", sd.version AS "version!",
+    rd.architecture, sd.name || coalesce(sd.ver, '') AS "soname!"
+FROM pv_package_sodep sd
+JOIN latest USING (package, version, repo)
+JOIN pv_repos rd ON rd.name = sd.repo
+WHERE sd.depends = 1 AND sd.name IS NOT NULL AND ($1::TEXT IS NULL OR rd.branch = $1)
+AND ($3::TEXT IS NULL OR strpos(lower(latest.maintainer), lower($3)) > 0)
+AND NOT EXISTS (
+    SELECT 1 FROM pv_package_sodep sp
+    JOIN latest lp ON lp.package = sp.package AND lp.version = sp.version AND lp.repo = sp.repo
+    JOIN pv_repos rp ON rp.name = sp.repo
+    WHERE sp.depends = 0 AND sp.name = sd.name
+    AND (sd.ver IS NULL OR sp.ver = sd.ver OR sp.ver LIKE sd.ver || '.%')
+    AND (rp.architecture = rd.architecture OR rp.architecture = 'all')
+    AND (rp.component = rd.component OR rp.component = $2)
+    AND (rp.branch = rd.branch OR rp.testing <= rd.testing)
+)
+ORDER BY rd.branch, 2, rd.architecture, 5"#,
+        branch,
+        primary_component,
+        maintainer
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(records
+        .into_iter()
+        .map(|r| SoBreak {
+            branch: r.branch,
+            package: r.package,
+            version: r.version,
+            architecture: r.architecture,
+            soname: r.soname,
+        })
+        .collect())
+}
+
+/// Format the reports for each of the branches, branches without any breakage get an empty report
+pub fn format_so_breaks(branches: &[String], breaks: &[SoBreak]) -> BTreeMap<String, String> {
+    let mut reports = branches
+        .iter()
+        .map(|b| {
+            (
+                b.clone(),
+                format!("# Packages in {} requiring missing shared libraries\n", b),
+            )
+        })
+        .collect::<BTreeMap<_, _>>();
+    for b in breaks {
+        let report = reports.entry(b.branch.clone()).or_insert_with(|| {
+            format!(
+                "# Packages in {} requiring missing shared libraries\n",
+                b.branch
+            )
+        });
+        writeln!(
+            report,
+            "{} {} ({}): {}",
+            b.package, b.version, b.architecture, b.soname
+        )
+        .ok();
+    }
+
+    reports
+}
+
+/// A shared library provided or required by some packages of a branch
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Soname {
+    pub branch: String,
+    /// Library name without the version suffix (e.g. `libc.so`)
+    pub name: String,
+    /// Version suffix (e.g. `.6`)
+    pub version: Option<String>,
+    pub packages: Vec<SonamePackage>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct SonamePackage {
+    pub package: String,
+    pub version: String,
+    pub architecture: String,
+}
+
+struct SonameRecord {
+    branch: String,
+    name: String,
+    ver: Option<String>,
+    package: String,
+    version: String,
+    architecture: String,
+}
+
+/// List the shared libraries provided (or required, when `requires` is set) by the latest
+/// versions of the packages, with the packages providing (or requiring) them
+pub async fn list_sonames(
+    pool: &PgPool,
+    branch: Option<&str>,
+    requires: bool,
+) -> Result<Vec<Soname>> {
+    let records = sqlx::query_as!(
+        SonameRecord,
+        r#"WITH latest AS (
+    SELECT DISTINCT ON (repo, package) package, version, repo FROM pv_packages
+    WHERE debtime IS NOT NULL ORDER BY repo, package, _vercomp DESC
+)
+SELECT DISTINCT r.branch, sd.name AS "name!", sd.ver, sd.package AS "package!",
+    sd.version AS "version!", r.architecture
+FROM pv_package_sodep sd
+JOIN latest USING (package, version, repo)
+JOIN pv_repos r ON r.name = sd.repo
+WHERE sd.depends = $1 AND sd.name IS NOT NULL AND ($2::TEXT IS NULL OR r.branch = $2)
+ORDER BY r.branch, 2, sd.ver NULLS FIRST, 4, r.architecture"#,
+        requires as i32,
+        branch
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(group_sonames(records))
+}
+
+/// Group the (sorted) records by branch and shared library
+fn group_sonames(records: Vec<SonameRecord>) -> Vec<Soname> {
+    let mut sonames: Vec<Soname> = Vec::new();
+    for r in records {
+        let package = SonamePackage {
+            package: r.package,
+            version: r.version,
+            architecture: r.architecture,
+        };
+        match sonames.last_mut() {
+            Some(last)
+                if last.branch == r.branch && last.name == r.name && last.version == r.ver =>
+            {
+                last.packages.push(package)
+            }
+            _ => sonames.push(Soname {
+                branch: r.branch,
+                name: r.name,
+                version: r.ver,
+                packages: vec![package],
+            }),
+        }
+    }
+
+    sonames
+}
+
+/// An ELF file with a non-standard interpreter or library search path
+#[derive(Debug)]
+pub struct ElfIssue {
+    pub branch: String,
+    pub package: String,
+    pub version: String,
+    pub architecture: String,
+    pub path: String,
+    pub issue: String,
+}
+
+/// Directories where a library search path is writable by other users or only exists on the
+/// build machine
+const INSECURE_SEARCH_PATHS: &[&str] =
+    &["/tmp", "/var/tmp", "/dev/shm", "/home", "/root", "/build"];
+
+/// Check the interpreter and the library search paths of an ELF file.
+/// The interpreter must be a dynamic linker (`ld*`) in one of the library directories, and
+/// every search path entry must be absolute (or relative to `$ORIGIN`) and outside of the
+/// temporary, home and build directories.
+fn check_elf(interpreter: Option<&str>, rpath: Option<&str>, runpath: Option<&str>) -> Vec<String> {
+    let mut issues = Vec::new();
+    if let Some(interpreter) = interpreter {
+        let standard = ["/lib/", "/lib64/", "/usr/lib/", "/usr/lib64/"]
+            .iter()
+            .filter_map(|dir| interpreter.strip_prefix(dir))
+            .any(|name| name.starts_with("ld") && !name.contains('/'));
+        if !standard {
+            issues.push(format!("non-standard interpreter {}", interpreter));
+        }
+    }
+    for (tag, paths) in [("RPATH", rpath), ("RUNPATH", runpath)] {
+        for entry in paths.into_iter().flat_map(|p| p.split(':')) {
+            if entry.starts_with("$ORIGIN") || entry.starts_with("${ORIGIN}") {
+                continue;
+            }
+            if entry.is_empty() {
+                issues.push(format!("empty {} entry (the working directory)", tag));
+            } else if !entry.starts_with('/') {
+                issues.push(format!("relative {} entry {}", tag, entry));
+            } else if INSECURE_SEARCH_PATHS
+                .iter()
+                .any(|dir| Path::new(entry).starts_with(dir))
+            {
+                issues.push(format!("insecure {} entry {}", tag, entry));
+            }
+        }
+    }
+
+    issues
+}
+
+/// List the ELF files in the (latest versions of the) packages with a non-standard interpreter
+/// or library search path
+pub async fn list_elf_issues(
+    pool: &PgPool,
+    branch: Option<&str>,
+    maintainer: Option<&str>,
+) -> Result<Vec<ElfIssue>> {
+    let records = sqlx::query!(
+        r#"WITH latest AS (
+    SELECT DISTINCT ON (repo, package) package, version, repo, maintainer FROM pv_packages
+    WHERE debtime IS NOT NULL ORDER BY repo, package, _vercomp DESC
+)
+SELECT rd.branch, e.package, e.version, rd.architecture, e.path, e.interpreter, e.rpath, e.runpath
+FROM pv_package_elf e
+JOIN latest USING (package, version, repo)
+JOIN pv_repos rd ON rd.name = e.repo
+WHERE ($1::TEXT IS NULL OR rd.branch = $1)
+AND ($2::TEXT IS NULL OR strpos(lower(latest.maintainer), lower($2)) > 0)
+ORDER BY rd.branch, e.package, rd.architecture, e.path"#,
+        branch,
+        maintainer
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(records
+        .into_iter()
+        .flat_map(|r| {
+            check_elf(
+                r.interpreter.as_deref(),
+                r.rpath.as_deref(),
+                r.runpath.as_deref(),
+            )
+            .into_iter()
+            .map(move |issue| ElfIssue {
+                branch: r.branch.clone(),
+                package: r.package.clone(),
+                version: r.version.clone(),
+                architecture: r.architecture.clone(),
+                path: r.path.clone(),
+                issue,
+            })
+        })
+        .collect())
+}
+
+/// Format the reports for each of the branches, branches without any issue get an empty report
+pub fn format_elf_issues(branches: &[String], issues: &[ElfIssue]) -> BTreeMap<String, String> {
+    let header = |branch: &str| {
+        format!(
+            "# ELF files in {} with non-standard interpreters or library search paths\n",
+            branch
+        )
+    };
+    let mut reports = branches
+        .iter()
+        .map(|b| (b.clone(), header(b)))
+        .collect::<BTreeMap<_, _>>();
+    for i in issues {
+        let report = reports
+            .entry(i.branch.clone())
+            .or_insert_with(|| header(&i.branch));
+        writeln!(
+            report,
+            "{} {} ({}): /{}: {}",
+            i.package, i.version, i.architecture, i.path, i.issue
+        )
+        .ok();
+    }
+
+    reports
+}
+
+#[test]
+fn test_format_so_breaks() {
+    let so_break = |branch: &str, package: &str, soname: &str| SoBreak {
+        branch: branch.to_string(),
+        package: package.to_string(),
+        version: "1.0".to_string(),
+        architecture: "amd64".to_string(),
+        soname: soname.to_string(),
+    };
+    let reports = format_so_breaks(
+        &["stable".to_string(), "icu-76".to_string()],
+        &[
+            so_break("icu-76", "boost", "libicuuc.so.74"),
+            so_break("icu-76", "libxml2", "libicuuc.so.74"),
+        ],
+    );
+    assert_eq!(
+        reports["stable"],
+        "# Packages in stable requiring missing shared libraries\n"
+    );
+    assert_eq!(
+        reports["icu-76"],
+        "# Packages in icu-76 requiring missing shared libraries\n\
+boost 1.0 (amd64): libicuuc.so.74\n\
+libxml2 1.0 (amd64): libicuuc.so.74\n"
+    );
+}
+
+#[test]
+fn test_check_elf() {
+    assert!(check_elf(Some("/lib64/ld-linux-x86-64.so.2"), None, None).is_empty());
+    assert!(check_elf(Some("/lib/ld-musl-aarch64.so.1"), None, None).is_empty());
+    assert!(check_elf(Some("/usr/lib/ld-linux-loongarch-lp64d.so.1"), None, None).is_empty());
+    assert_eq!(
+        check_elf(Some("/opt/toolchain/lib/ld-linux-x86-64.so.2"), None, None),
+        ["non-standard interpreter /opt/toolchain/lib/ld-linux-x86-64.so.2"]
+    );
+    assert_eq!(
+        check_elf(Some("/lib64/foo/ld.so"), None, None),
+        ["non-standard interpreter /lib64/foo/ld.so"]
+    );
+    assert!(check_elf(None, Some("$ORIGIN/../lib:/usr/lib/qt5"), Some("${ORIGIN}")).is_empty());
+    assert_eq!(
+        check_elf(
+            None,
+            Some("/usr/lib::lib"),
+            Some("/tmp/build/lib:/home/builder/x:/tmpfoo")
+        ),
+        [
+            "empty RPATH entry (the working directory)",
+            "relative RPATH entry lib",
+            "insecure RUNPATH entry /tmp/build/lib",
+            "insecure RUNPATH entry /home/builder/x",
+        ]
+    );
+}
+
+#[test]
+fn test_format_elf_issues() {
+    let reports = format_elf_issues(
+        &["stable".to_string()],
+        &[ElfIssue {
+            branch: "stable".to_string(),
+            package: "foo".to_string(),
+            version: "1.0".to_string(),
+            architecture: "amd64".to_string(),
+            path: "usr/bin/foo".to_string(),
+            issue: "relative RPATH entry lib".to_string(),
+        }],
+    );
+    assert_eq!(
+        reports["stable"],
+        "# ELF files in stable with non-standard interpreters or library search paths\n\
+foo 1.0 (amd64): /usr/bin/foo: relative RPATH entry lib\n"
+    );
+}
+
+#[test]
+fn test_group_sonames() {
+    let record = |branch: &str, name: &str, ver: Option<&str>, package: &str| SonameRecord {
+        branch: branch.to_string(),
+        name: name.to_string(),
+        ver: ver.map(|v| v.to_string()),
+        package: package.to_string(),
+        version: "1.0".to_string(),
+        architecture: "amd64".to_string(),
+    };
+    let sonames = group_sonames(vec![
+        record("stable", "libfoo.so", None, "foo-plugins"),
+        record("stable", "libfoo.so", Some(".1"), "bar"),
+        record("stable", "libfoo.so", Some(".1"), "foo"),
+        record("topic", "libfoo.so", Some(".1"), "foo"),
+    ]);
+    let summary = sonames
+        .iter()
+        .map(|s| {
+            (
+                s.branch.as_str(),
+                s.version.as_deref(),
+                s.packages
+                    .iter()
+                    .map(|p| p.package.as_str())
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        summary,
+        [
+            ("stable", None, vec!["foo-plugins"]),
+            ("stable", Some(".1"), vec!["bar", "foo"]),
+            ("topic", Some(".1"), vec!["foo"]),
+        ]
+    );
+    assert!(group_sonames(Vec::new()).is_empty());
+}
@@ -1,37 +1,60 @@
 //! Advanced database-based scanning.
 
-use anyhow::{anyhow, Result};
 use crossbeam_queue::SegQueue;
 use log::{error, info, warn};
 use rayon::prelude::*;
 use sqlx::{PgPool, Postgres, Transaction};
+use std::path::Component;
 use std::{
     collections::{HashMap, HashSet},
-    fs::File,
     io::Read,
     path::{Path, PathBuf},
 };
-use std::{fs::Metadata, path::Component};
 
 use crate::db;
 use crate::ipc::PVMessage;
-use crate::scan::{determine_format, open_compressed_control, ArArchive, TarArchive};
+use crate::scan::{
+    determine_format, open_compressed_control, ArArchive, ScanError, ScanFailure, ScanResult,
+    TarArchive,
+};
 
-use super::{mtime, read_compressed, HashedReader, TarFormat};
+use super::{read_compressed, HashedReader, PackageEntry, PackageSource, TarFormat};
 
 macro_rules! must_have {
     ($map:ident, $name:expr) => {{
         let value = $map
             .remove($name.as_bytes())
-            .ok_or_else(|| anyhow!("Missing `{}` field", $name))?;
-        std::str::from_utf8(value)?.to_string()
+            .ok_or_else(|| ScanError::MissingField($name.to_string()))?;
+        std::str::from_utf8(value)
+            .map_err(|_| ScanError::MalformedControl(format!("`{}` is not valid UTF-8", $name)))?
+            .to_string()
+    }};
+}
+
+/// Like `must_have!`, but transcodes values that are not valid UTF-8 from Latin-1 (with a warning)
+/// instead of failing, for the free-form fields of legacy packages
+macro_rules! must_have_text {
+    ($map:ident, $name:expr, $filename:expr) => {{
+        let value = $map
+            .remove($name.as_bytes())
+            .ok_or_else(|| ScanError::MissingField($name.to_string()))?;
+        match std::str::from_utf8(value) {
+            Ok(value) => value.to_string(),
+            Err(_) => {
+                warn!(
+                    "{}: `{}` is not valid UTF-8, decoding it as Latin-1.",
+                    $filename, $name
+                );
+                decode_latin1(value)
+            }
+        }
     }};
 }
 
 /// ELF magic number
 const ELF_MAGIC: &[u8] = &[0x7f, 0x45, 0x4c, 0x46];
 /// Deb relationships
-const PKG_RELATION: &[&str] = &[
+pub(crate) const PKG_RELATION: &[&str] = &[
     "Depends",
     "Pre-Depends",
     "Recommends",
@@ -43,6 +66,16 @@ const PKG_RELATION: &[&str] = &[
     "Replaces",
 ];
 
+/// Split the value of the `Tag` field (e.g. `role::program, use::editing`) into tags
+fn split_tags(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|tag| tag.trim())
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| tag.to_string())
+        .collect()
+}
+
 #[derive(Debug)]
 struct DebMeta {
     /// PKGNAME (Package)
@@ -51,8 +84,10 @@ struct DebMeta {
     version: String,
     /// PKGSEC (Section)
     section: String,
-    /// PKGDES (Description)
+    /// PKGDES (Description), the synopsis only
     desc: String,
+    /// Extended description, the continuation lines without their leading space
+    desc_body: Option<String>,
     /// Architecture
     arch: String,
     /// Installed-Size
@@ -61,6 +96,16 @@ struct DebMeta {
     maintainer: String,
     /// Features
     features: Option<String>,
+    /// Built-Using
+    built_using: Option<String>,
+    /// Homepage
+    homepage: Option<String>,
+    /// Bugs
+    bugs: Option<String>,
+    /// Origin
+    origin: Option<String>,
+    /// Tag (debtags)
+    tags: Vec<String>,
     // Utility fields
     /// control.tar last modified time
     debtime: u64,
@@ -84,8 +129,20 @@ pub struct PackageMeta {
     mtime: u64,
     /// Files contained in this package
     contents: PackageContents,
+    /// Imported from an existing index, without the file contents
+    imported: bool,
 }
 
+impl PackageMeta {
+    /// Check if the `Section` prefix (e.g. `non-free` in `non-free/games`)
+    /// agrees with the component this package is filed under.
+    /// Sections without a prefix are not checked.
+    pub fn lint_section(&self) -> ScanResult<()> {
+        check_section_component(&self.deb.section, &self.repo.1)
+    }
+}
+
+#[allow(dead_code)]
 #[derive(Debug)]
 struct PackageFile {
     path: PathBuf,
@@ -99,13 +156,33 @@ struct PackageFile {
     gname: Option<Vec<u8>>,
 }
 
+/// Interpreter and library search paths of an ELF file, for the files having any of them
+#[derive(Debug)]
+struct ElfFile {
+    path: String,
+    interpreter: Option<String>,
+    rpath: Option<String>,
+    runpath: Option<String>,
+}
+
 #[derive(Debug)]
 struct PackageContents {
     files: Vec<PackageFile>,
     so_provides: HashSet<String>,
     so_requires: HashSet<String>,
+    elf: Vec<ElfFile>,
 }
 
+/// Dynamic linking information of an ELF file
+#[derive(Debug, Default)]
+struct ElfInfo<'a> {
+    needed: Vec<&'a str>,
+    interpreter: Option<&'a str>,
+    rpath: Option<&'a str>,
+    runpath: Option<&'a str>,
+}
+
+#[allow(dead_code)]
 #[derive(Debug)]
 struct RepositoryMeta {
     name: String,
@@ -116,8 +193,63 @@ struct RepositoryMeta {
     architecture: String,
 }
 
-fn open_compressed_data<R: Read>(reader: R, format: &TarFormat) -> Result<PackageContents> {
-    read_compressed(format, reader, collect_files)
+fn open_compressed_data<R: Read>(
+    reader: R,
+    format: &TarFormat,
+    with_elf: bool,
+) -> ScanResult<PackageContents> {
+    read_compressed(format, reader, |reader| collect_files(reader, with_elf))
+}
+
+/// Decode ISO-8859-1 (Latin-1) text, every byte maps to the code point of the same value
+fn decode_latin1(value: &[u8]) -> String {
+    value.iter().map(|&b| b as char).collect()
+}
+
+/// Split a (multi-line) `Description` into the synopsis and the extended description.
+/// The leading space of each line of the extended description is removed, and ` .`
+/// (an empty line) becomes `.`, so that the lines can be reassembled by indenting them again.
+fn split_description(description: &str) -> (String, Option<String>) {
+    let Some((synopsis, body)) = description.split_once('\n') else {
+        return (description.trim_end().to_string(), None);
+    };
+    let body = body
+        .lines()
+        .map(|line| line.strip_prefix([' ', '\t']).unwrap_or(line).trim_end())
+        .collect::<Vec<_>>()
+        .join("\n");
+    let body = if body.trim().is_empty() {
+        None
+    } else {
+        Some(body)
+    };
+
+    (synopsis.trim_end().to_string(), body)
+}
+
+/// Pick the fields listed in `wanted` (compared case-insensitively) out of the left-over fields,
+/// in the order they are listed. The relationship fields are rendered on their own and skipped.
+fn select_extra_fields(
+    extra: &HashMap<Vec<u8>, Vec<u8>>,
+    wanted: &[String],
+) -> Vec<(String, String)> {
+    let mut fields = Vec::new();
+    for name in wanted {
+        if PKG_RELATION.iter().any(|r| r.eq_ignore_ascii_case(name)) {
+            continue;
+        }
+        let found = extra
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name.as_bytes()));
+        if let Some((key, value)) = found {
+            fields.push((
+                String::from_utf8_lossy(key).to_string(),
+                String::from_utf8_lossy(value).to_string(),
+            ));
+        }
+    }
+
+    fields
 }
 
 /// Collect left-over fields from the hashmap
@@ -130,61 +262,65 @@ fn collect_left_over_fields(map: HashMap<&[u8], &[u8]>) -> HashMap<Vec<u8>, Vec<
     new_map
 }
 
-fn sha256sum_validate<P: AsRef<Path>>(file: P, expected: &str) -> Result<bool> {
-    let f = File::open(file)?;
-    let hash = super::sha256sum(f)?;
+fn sha256sum_validate(
+    source: &dyn PackageSource,
+    filename: &str,
+    expected: &str,
+) -> ScanResult<bool> {
+    let hash = super::sha256sum(source.open(filename)?)?;
 
     Ok(hash == expected)
 }
 
-pub fn collect_removed_packages(removed: SegQueue<PathBuf>, mirror_root: &Path) -> Vec<PathBuf> {
+pub fn collect_removed_packages(removed: SegQueue<String>) -> Vec<PathBuf> {
     let mut removed_packages = Vec::with_capacity(removed.len());
     while let Some(package) = removed.pop() {
-        removed_packages.push(package.strip_prefix(mirror_root).unwrap().to_path_buf());
+        removed_packages.push(PathBuf::from(package));
     }
 
     removed_packages
 }
 
+/// (removed, already scanned, needs metadata update), as paths relative to the mirror root
+type ValidationResult = (SegQueue<String>, Vec<String>, SegQueue<(String, u64)>);
+
 /// Validate if the records in the database are up to date with the packages
-pub fn validate_packages<P: AsRef<Path>>(
-    root: P,
+pub fn validate_packages(
+    source: &dyn PackageSource,
     packages: &[db::PVPackage],
-) -> Result<(SegQueue<PathBuf>, Vec<PathBuf>, SegQueue<(PathBuf, u64)>)> {
-    let pool_root = root.as_ref();
+) -> ScanResult<ValidationResult> {
+    source.preload()?;
     let to_remove = SegQueue::new();
     let needs_update = SegQueue::new();
     let already_scanned = packages
         .par_iter()
         .filter_map(|p| {
-            let path = pool_root.join(p.filename.as_ref().unwrap());
-            if !path.exists() {
-                to_remove.push(path);
-                return None;
-            }
-            let stat = path.metadata();
-            if let Err(e) = stat {
-                warn!("Problem stat() on {}: {}", path.display(), e);
-                return None;
-            }
-            let stat = stat.unwrap();
-            if stat.is_file() {
-                let size = p.size.unwrap();
-                let mtime = super::mtime(&stat).unwrap_or(0);
-                if size.is_negative() || stat.len() != (size as u64) {
-                    // ^ ... what?
+            let filename = p.filename.clone().unwrap();
+            let stat = match source.stat(&filename) {
+                Ok(Some(stat)) => stat,
+                Ok(None) => {
+                    to_remove.push(filename);
                     return None;
                 }
-                if mtime == p.mtime.unwrap_or(0) as u64 {
-                    // mark as already scanned
-                    return Some(path);
-                } else if sha256sum_validate(&path, p.sha256.as_ref().unwrap()).unwrap_or(false) {
-                    needs_update.push((path.clone(), mtime));
-                    return Some(path);
+                Err(e) => {
+                    warn!("Problem stat() on {}: {}", filename, e);
+                    return None;
                 }
+            };
+            let size = p.size.unwrap();
+            if size.is_negative() || stat.size != (size as u64) {
+                // ^ ... what?
                 return None;
             }
-            to_remove.push(path);
+            if stat.mtime == p.mtime.unwrap_or(0) as u64 {
+                // mark as already scanned
+                return Some(filename);
+            } else if sha256sum_validate(source, &filename, p.sha256.as_ref().unwrap())
+                .unwrap_or(false)
+            {
+                needs_update.push((filename.clone(), stat.mtime));
+                return Some(filename);
+            }
 
             None
         })
@@ -193,19 +329,68 @@ pub fn validate_packages<P: AsRef<Path>>(
     Ok((to_remove, already_scanned, needs_update))
 }
 
+/// Collect the known packages whose mtime differs from the one in the database, without
+/// reading their contents. Packages that are gone or whose size changed are skipped with
+/// a warning, they need a regular scan.
+pub fn collect_stale_metadata(
+    source: &dyn PackageSource,
+    packages: &[db::PVPackage],
+) -> SegQueue<(String, u64)> {
+    let needs_update = SegQueue::new();
+    packages.par_iter().for_each(|p| {
+        let filename = p.filename.as_deref().unwrap();
+        let stat = match source.stat(filename) {
+            Ok(Some(stat)) => stat,
+            Ok(None) => {
+                warn!(
+                    "{} no longer exists, run a regular scan to remove it.",
+                    filename
+                );
+                return;
+            }
+            Err(e) => {
+                warn!("Problem stat() on {}: {}", filename, e);
+                return;
+            }
+        };
+        if Some(stat.size as i64) != p.size {
+            warn!("{} has changed, run a regular scan to rescan it.", filename);
+            return;
+        }
+        if stat.mtime != p.mtime.unwrap_or(0) as u64 {
+            needs_update.push((filename.to_string(), stat.mtime));
+        }
+    });
+
+    needs_update
+}
+
+fn check_section_component(section: &str, component: &str) -> ScanResult<()> {
+    match section.split_once('/') {
+        Some((prefix, _)) if prefix != component => Err(ScanError::SectionMismatch {
+            section: section.to_string(),
+            component: component.to_string(),
+        }),
+        _ => Ok(()),
+    }
+}
+
 #[inline]
-fn get_repo_key_name(repo: &(String, String), arch: &str) -> String {
-    if repo.1 == "main" {
+fn get_repo_key_name(repo: &(String, String), arch: &str, primary_component: &str) -> String {
+    if repo.1 == primary_component {
         arch.to_string()
     } else {
         format!("{}-{}", repo.1, arch)
     }
 }
 
-fn collect_changed_repos(packages: &[PackageMeta]) -> HashMap<String, RepositoryMeta> {
+fn collect_changed_repos(
+    packages: &[PackageMeta],
+    primary_component: &str,
+) -> HashMap<String, RepositoryMeta> {
     let mut repos = HashMap::new();
     for p in packages {
-        let key = get_repo_key_name(&p.repo, &p.deb.arch);
+        let key = get_repo_key_name(&p.repo, &p.deb.arch, primary_component);
         let path = format!("{}/{}", p.repo.0, p.repo.1);
         let name = format!("{}/{}", key, p.repo.0);
         repos.insert(
@@ -226,39 +411,107 @@ fn collect_changed_repos(packages: &[PackageMeta]) -> HashMap<String, Repository
 
 pub async fn update_unchanged_packages(
     pool: &PgPool,
-    packages: SegQueue<(PathBuf, u64)>,
-    mirror_root: &Path,
-) -> Result<()> {
-    while let Some(package) = packages.pop() {
-        if let Ok(path) = package.0.strip_prefix(mirror_root) {
-            info!("Updating {} ...", path.display());
-            if let Some(path) = path.to_str() {
-                sqlx::query!(
-                    "UPDATE pv_packages SET mtime = $1 WHERE filename = $2",
-                    package.1 as i64,
-                    path
-                )
-                .execute(pool)
-                .await?;
-            } else {
-                warn!("{} contains invalid characters!", path.display());
-            }
-        } else {
-            warn!(
-                "{} is not in the package pool directory!",
-                package.0.display()
-            );
-        }
+    packages: SegQueue<(String, u64)>,
+) -> ScanResult<()> {
+    while let Some((path, mtime)) = packages.pop() {
+        info!("Updating {} ...", path);
+        sqlx::query!(
+            "UPDATE pv_packages SET mtime = $1 WHERE filename = $2",
+            mtime as i64,
+            path
+        )
+        .execute(pool)
+        .await?;
     }
 
     Ok(())
 }
 
-/// Get what and how packages changed (needs to be run before `save_packages_to_db`)
-pub async fn what_changed(pool: &PgPool, packages: &[PackageMeta]) -> Result<Vec<PVMessage>> {
+/// Check if all the versions can be compared by the database, the error raised by
+/// `comparable_dpkgver` (if any) is returned as is
+async fn are_versions_comparable(
+    pool: &PgPool,
+    versions: &[String],
+) -> ScanResult<Result<bool, String>> {
+    let result = sqlx::query_scalar!(
+        r#"SELECT bool_and(comparable_dpkgver(v) IS NOT NULL) AS "valid!" FROM unnest($1::TEXT[]) v"#,
+        versions
+    )
+    .fetch_one(pool)
+    .await;
+    match result {
+        Ok(valid) => Ok(Ok(valid)),
+        Err(sqlx::Error::Database(e)) => Ok(Err(e.message().to_string())),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Check that the versions of the packages can be compared by the database (`comparable_dpkgver`),
+/// the packages with an unsupported version are returned as failures instead of failing the
+/// whole save later on
+pub async fn check_package_versions(
+    pool: &PgPool,
+    packages: Vec<PackageMeta>,
+) -> ScanResult<(Vec<PackageMeta>, Vec<ScanFailure>)> {
+    let mut versions = packages
+        .iter()
+        .map(|p| p.deb.version.clone())
+        .collect::<Vec<_>>();
+    versions.sort_unstable();
+    versions.dedup();
+    // bisect the versions, only the halves with an offending version are checked again
+    let mut invalid = HashMap::new();
+    let mut pending = vec![&versions[..]];
+    while let Some(batch) = pending.pop() {
+        if batch.is_empty() {
+            continue;
+        }
+        let reason = match are_versions_comparable(pool, batch).await? {
+            Ok(true) => continue,
+            Ok(false) => "cannot be compared".to_string(),
+            Err(message) => message,
+        };
+        if let [version] = batch {
+            invalid.insert(version.clone(), reason);
+            continue;
+        }
+        let (left, right) = batch.split_at(batch.len() / 2);
+        pending.push(left);
+        pending.push(right);
+    }
+    if invalid.is_empty() {
+        return Ok((packages, Vec::new()));
+    }
+    let mut failures = Vec::new();
+    let packages = packages
+        .into_iter()
+        .filter(|p| {
+            let Some(reason) = invalid.get(&p.deb.version) else {
+                return true;
+            };
+            let error = format!("Unsupported version {}: {}", p.deb.version, reason);
+            error!("{}: {}", p.filename, error);
+            failures.push(ScanFailure {
+                filename: p.filename.clone(),
+                error,
+            });
+            false
+        })
+        .collect();
+
+    Ok((packages, failures))
+}
+
+/// Get what and how packages changed (needs to be run before `save_packages_to_db`), along with
+/// the file names of the packages
+pub async fn what_changed(
+    pool: &PgPool,
+    packages: &[PackageMeta],
+    primary_component: &str,
+) -> ScanResult<Vec<(String, PVMessage)>> {
     let mut messages = Vec::with_capacity(packages.len());
     for p in packages {
-        let key = get_repo_key_name(&p.repo, &p.deb.arch);
+        let key = get_repo_key_name(&p.repo, &p.deb.arch, primary_component);
         let repo = format!("{}/{}", key, p.repo.0);
         let record = sqlx::query!(
             r#"SELECT comparable_dpkgver($1) > _vercomp AS newer, version, filename FROM pv_packages 
@@ -272,13 +525,17 @@ WHERE package=$2 AND repo=$3 AND _vercomp=
         .await?;
         // not found: new package
         if record.is_none() {
-            messages.push(PVMessage::new(
-                format!("{}-{}", p.repo.0, p.repo.1),
-                p.deb.name.clone(),
-                p.deb.arch.clone(),
-                b'+',
-                None,
-                Some(p.deb.version.clone()),
+            messages.push((
+                p.filename.clone(),
+                PVMessage::new(
+                    format!("{}-{}", p.repo.0, p.repo.1),
+                    p.deb.name.clone(),
+                    p.deb.arch.clone(),
+                    b'+',
+                    None,
+                    Some(p.deb.version.clone()),
+                )
+                .with_file_info(p.sha256.clone(), p.size),
             ));
             continue;
         }
@@ -291,21 +548,29 @@ WHERE package=$2 AND repo=$3 AND _vercomp=
             // not a new package, version is not newer: older package
             continue;
         };
-        messages.push(PVMessage::new(
-            format!("{}-{}", p.repo.0, p.repo.1),
-            p.deb.name.clone(),
-            p.deb.arch.clone(),
-            method,
-            Some(record.version),
-            Some(p.deb.version.clone()),
+        messages.push((
+            p.filename.clone(),
+            PVMessage::new(
+                format!("{}-{}", p.repo.0, p.repo.1),
+                p.deb.name.clone(),
+                p.deb.arch.clone(),
+                method,
+                Some(record.version),
+                Some(p.deb.version.clone()),
+            )
+            .with_file_info(p.sha256.clone(), p.size),
         ));
     }
 
     Ok(messages)
 }
 
-pub async fn update_changed_repos(pool: &PgPool, packages: &[PackageMeta]) -> Result<()> {
-    let changed_repos = collect_changed_repos(packages);
+pub async fn update_changed_repos(
+    pool: &PgPool,
+    packages: &[PackageMeta],
+    primary_component: &str,
+) -> ScanResult<()> {
+    let changed_repos = collect_changed_repos(packages, primary_component);
     let mut tx = pool.begin().await?;
     for (_, repo) in changed_repos {
         sqlx::query!(
@@ -326,10 +591,112 @@ ON CONFLICT (name) DO UPDATE SET mtime=now()",
     Ok(())
 }
 
-pub async fn save_packages_to_db(pool: &PgPool, packages: &[PackageMeta]) -> Result<()> {
+/// Save the scanned packages to the database in a single transaction, or in a transaction
+/// per `batch_size` packages (if given and non-zero), so that the batches already committed
+/// survive a failure.
+///
+/// When `keep_going` is set, each package is saved in its own savepoint so that
+/// a package failing to save does not roll back the others. The packages that
+/// failed to save are returned.
+pub async fn save_packages_to_db(
+    pool: &PgPool,
+    packages: &[PackageMeta],
+    primary_component: &str,
+    extra_fields: &[String],
+    keep_going: bool,
+    batch_size: Option<usize>,
+) -> ScanResult<Vec<ScanFailure>> {
+    let mut failures = Vec::new();
+    let batch_size = batch_size
+        .filter(|size| *size > 0)
+        .unwrap_or(packages.len())
+        .max(1);
+    let mut saved = 0;
+    let progress = crate::status::Progress::new("Saved", packages.len());
+    for batch in packages.chunks(batch_size) {
+        let mut tx = pool.begin().await?;
+        if keep_going {
+            // the deferred foreign keys would only fail at the commit, rolling back the batch
+            sqlx::query!("SET CONSTRAINTS ALL IMMEDIATE")
+                .execute(&mut *tx)
+                .await?;
+        }
+        for pkg in batch {
+            if !keep_going {
+                save_package_to_db(&mut tx, pkg, primary_component, extra_fields).await?;
+                dequeue_package(&mut tx, &pkg.filename).await?;
+                progress.inc();
+                continue;
+            }
+            let mut savepoint = sqlx::Connection::begin(&mut *tx).await?;
+            match save_package_to_db(&mut savepoint, pkg, primary_component, extra_fields).await {
+                Ok(()) => {
+                    dequeue_package(&mut savepoint, &pkg.filename).await?;
+                    savepoint.commit().await?
+                }
+                Err(err) => {
+                    error!("Failed to save {}: {}", pkg.filename, err);
+                    savepoint.rollback().await?;
+                    failures.push(ScanFailure {
+                        filename: pkg.filename.clone(),
+                        error: err.to_string(),
+                    });
+                }
+            }
+            progress.inc();
+        }
+        tx.commit().await?;
+        saved += batch.len();
+        if batch.len() < packages.len() {
+            info!("Committed {}/{} packages.", saved, packages.len());
+        }
+    }
+
+    Ok(failures)
+}
+
+/// Remove a saved package from the scan queue, along with the package itself
+async fn dequeue_package(tx: &mut Transaction<'_, Postgres>, filename: &str) -> ScanResult<()> {
+    sqlx::query!("DELETE FROM pv_scan_queue WHERE filename = $1", filename)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
+
+/// Record the packages that failed to scan.
+///
+/// Packages that failed to scan never make it into the database, so they are
+/// re-scanned on every run. This means `failures` is the complete list of the
+/// problematic packages and any other existing records are cleared.
+/// When only some of the packages were scanned (`scanned` is given), only the
+/// records of those packages are cleared.
+pub async fn update_scan_errors(
+    pool: &PgPool,
+    failures: &[ScanFailure],
+    scanned: Option<&[String]>,
+) -> ScanResult<()> {
+    let filenames = failures
+        .iter()
+        .map(|f| f.filename.clone())
+        .collect::<Vec<_>>();
     let mut tx = pool.begin().await?;
-    for pkg in packages {
-        save_package_to_db(&mut tx, pkg).await?;
+    sqlx::query!(
+        "DELETE FROM pv_scan_errors WHERE NOT (filename = ANY($1)) AND ($2::TEXT[] IS NULL OR filename = ANY($2))",
+        &filenames,
+        scanned
+    )
+    .execute(&mut *tx)
+    .await?;
+    for failure in failures {
+        sqlx::query!(
+            "INSERT INTO pv_scan_errors VALUES ($1, $2, now())
+ON CONFLICT (filename) DO UPDATE SET error = $2, last_seen = now()",
+            failure.filename,
+            failure.error
+        )
+        .execute(&mut *tx)
+        .await?;
     }
     tx.commit().await?;
 
@@ -364,24 +731,74 @@ fn normalize_path(path: &str) -> &str {
         .unwrap_or_else(|| path.strip_prefix('/').unwrap_or(path))
 }
 
+/// Render the lines of a package in the Contents files (`<path>   <section>/<package>`),
+/// directories and special files are not listed
+fn render_contents_fragment(section: &str, package: &str, files: &[PackageFile]) -> String {
+    let mut paths = files
+        .iter()
+        .filter(|f| f.type_ < b'5')
+        .filter_map(|f| {
+            let path = f
+                .path
+                .parent()
+                .and_then(|p| p.to_str())
+                .map(normalize_path)?;
+            let filename = f.path.file_name().and_then(|p| p.to_str())?;
+            Some(format!("{}/{}", path, filename))
+        })
+        .collect::<Vec<_>>();
+    paths.sort_unstable();
+    paths
+        .into_iter()
+        .map(|path| format!("{}   {}/{}\n", path, section, package))
+        .collect()
+}
+
 async fn save_package_to_db(
     pool: &mut Transaction<'_, Postgres>,
     package: &PackageMeta,
-) -> Result<()> {
+    primary_component: &str,
+    extra_fields: &[String],
+) -> ScanResult<()> {
     let meta = &package.deb;
     let contents = &package.contents;
     let repo = format!(
         "{}/{}",
-        get_repo_key_name(&package.repo, &meta.arch),
+        get_repo_key_name(&package.repo, &meta.arch, primary_component),
         package.repo.0
     );
+    if package.imported {
+        // never replace what is already known, the index may be older than the database
+        let known = sqlx::query!(
+            "SELECT 1 AS known FROM pv_packages WHERE package=$1 AND version=$2 AND repo=$3",
+            meta.name,
+            meta.version,
+            repo
+        )
+        .fetch_optional(&mut **pool)
+        .await?;
+        if known.is_some() {
+            info!("{} is already known, skipping.", package.filename);
+            return Ok(());
+        }
+    } else {
+        // the scanned package supersedes the imported entry
+        sqlx::query!(
+            "DELETE FROM pv_packages WHERE package=$1 AND version=$2 AND repo=$3 AND imported",
+            meta.name,
+            meta.version,
+            repo
+        )
+        .execute(&mut **pool)
+        .await?;
+    }
     let result = sqlx::query!(
-        r#"INSERT INTO pv_packages VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, comparable_dpkgver($2), $14)
+        r#"INSERT INTO pv_packages VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, comparable_dpkgver($2), $14, $15, $16, $17, $18, $19, now(), $20, $21)
 ON CONFLICT (package, version, repo)
-DO UPDATE SET filename=$5,size=$6,sha256=$7,mtime=$8,debtime=$9,section=$10,installed_size=$11,maintainer=$12,description=$13,features=$14
+DO UPDATE SET filename=$5,size=$6,sha256=$7,mtime=$8,debtime=$9,section=$10,installed_size=$11,maintainer=$12,description=$13,features=$14,built_using=$15,homepage=$16,bugs=$17,origin=$18,scanner_version=$19,scanned_at=now(),description_body=$20,imported=$21
 RETURNING (xmax = 0) AS new"#,
         meta.name, meta.version, repo, meta.arch, package.filename, package.size as i64, package.sha256, package.mtime as i32, meta.debtime as i32, meta.section, meta.inst_size.parse::<i64>().unwrap_or(0),
-        meta.maintainer, meta.desc, meta.features,
+        meta.maintainer, meta.desc, meta.features, meta.built_using, meta.homepage, meta.bugs, meta.origin, env!("CARGO_PKG_VERSION"), meta.desc_body, package.imported,
     ).fetch_one(&mut **pool).await?;
     if !result.new.unwrap_or(false) {
         warn!("{} is a duplicate!", package.filename);
@@ -410,19 +827,68 @@ DELETE FROM pv_package_duplicate WHERE package=$1 AND version=$2 AND repo=$3"#,
                     .ok()
                     .and_then(|x| if x.is_empty() { None } else { Some(x) });
             if let Some(value) = value {
+                let parsed = match crate::depends::parse_relationship(value) {
+                    Ok(clauses) => Some(serde_json::to_value(clauses)?),
+                    Err(err) => {
+                        warn!("{}: unable to parse {}: {}", package.filename, dep, err);
+                        None
+                    }
+                };
                 sqlx::query!(
-                    "INSERT INTO pv_package_dependencies VALUES($1, $2, $3, $4, $5) ON CONFLICT ON CONSTRAINT pv_package_dependencies_pkey DO UPDATE SET value = $5",
+                    "INSERT INTO pv_package_dependencies VALUES($1, $2, $3, $4, $5, $6) ON CONFLICT ON CONSTRAINT pv_package_dependencies_pkey DO UPDATE SET value = $5, parsed = $6",
                     meta.name,
                     meta.version,
                     repo,
                     dep,
-                    value
+                    value,
+                    parsed
                 )
                 .execute(&mut **pool)
                 .await?;
             }
         }
     }
+    // update tags
+    sqlx::query!(
+        "DELETE FROM pv_package_tags WHERE package=$1 AND version=$2 AND repo=$3",
+        meta.name,
+        meta.version,
+        repo
+    )
+    .execute(&mut **pool)
+    .await?;
+    for tag in &meta.tags {
+        sqlx::query!(
+            "INSERT INTO pv_package_tags VALUES ($1, $2, $3, $4) ON CONFLICT DO NOTHING",
+            meta.name,
+            meta.version,
+            repo,
+            tag
+        )
+        .execute(&mut **pool)
+        .await?;
+    }
+    // update the extra fields
+    sqlx::query!(
+        "DELETE FROM pv_package_extra_fields WHERE package=$1 AND version=$2 AND repo=$3",
+        meta.name,
+        meta.version,
+        repo
+    )
+    .execute(&mut **pool)
+    .await?;
+    for (field, value) in select_extra_fields(&meta.extra, extra_fields) {
+        sqlx::query!(
+            "INSERT INTO pv_package_extra_fields VALUES ($1, $2, $3, $4, $5) ON CONFLICT DO NOTHING",
+            meta.name,
+            meta.version,
+            repo,
+            field,
+            value
+        )
+        .execute(&mut **pool)
+        .await?;
+    }
     // update so information
     for so in &contents.so_requires {
         let (so_name, so_version) = split_so_name(so);
@@ -450,6 +916,39 @@ DELETE FROM pv_package_duplicate WHERE package=$1 AND version=$2 AND repo=$3"#,
         .execute(&mut **pool)
         .await?;
     }
+    // update the interpreter and library search paths of the ELF files
+    sqlx::query!(
+        "DELETE FROM pv_package_elf WHERE package=$1 AND version=$2 AND repo=$3",
+        meta.name,
+        meta.version,
+        repo
+    )
+    .execute(&mut **pool)
+    .await?;
+    for elf in &contents.elf {
+        sqlx::query!(
+            "INSERT INTO pv_package_elf VALUES ($1, $2, $3, $4, $5, $6, $7) ON CONFLICT DO NOTHING",
+            meta.name,
+            meta.version,
+            repo,
+            elf.path,
+            elf.interpreter,
+            elf.rpath,
+            elf.runpath
+        )
+        .execute(&mut **pool)
+        .await?;
+    }
+    // update the Contents lines
+    sqlx::query!(
+        "INSERT INTO pv_package_contents VALUES ($1, $2, $3, $4) ON CONFLICT (package, version, repo) DO UPDATE SET contents = $4",
+        meta.name,
+        meta.version,
+        repo,
+        render_contents_fragment(&meta.section, &meta.name, &contents.files)
+    )
+    .execute(&mut **pool)
+    .await?;
     // update files information
     for f in &contents.files {
         let path = f.path.parent().and_then(|p| p.to_str()).map(normalize_path);
@@ -465,18 +964,18 @@ DELETE FROM pv_package_duplicate WHERE package=$1 AND version=$2 AND repo=$3"#,
     Ok(())
 }
 
-fn get_branch_name<P: AsRef<Path>>(rel_path: P) -> Result<(String, String)> {
-    let mut comp = rel_path.as_ref().strip_prefix("pool")?.components();
+fn get_branch_name<P: AsRef<Path>>(rel_path: P) -> ScanResult<(String, String)> {
+    let invalid_path = || ScanError::InvalidPath(rel_path.as_ref().display().to_string());
+    let mut comp = rel_path
+        .as_ref()
+        .strip_prefix("pool")
+        .map_err(|_| invalid_path())?
+        .components();
     let mut branch = None;
     for _ in 0..=1 {
         let cur = match comp.next() {
             Some(Component::Normal(p)) => p.to_string_lossy(),
-            Some(_) | None => {
-                return Err(anyhow!(
-                    "Unexpected path component: {}",
-                    rel_path.as_ref().display()
-                ))
-            }
+            Some(_) | None => return Err(invalid_path()),
         };
         if let Some(branch) = branch {
             return Ok((branch, cur.to_string()));
@@ -485,10 +984,7 @@ fn get_branch_name<P: AsRef<Path>>(rel_path: P) -> Result<(String, String)> {
         }
     }
 
-    Err(anyhow!(
-        "Unable to determine branch name for {}",
-        rel_path.as_ref().display()
-    ))
+    Err(invalid_path())
 }
 
 #[inline]
@@ -501,13 +997,17 @@ fn is_shared_object(path: &[u8]) -> bool {
                 .contains(".so."))
 }
 
-fn parse_elf(bytes: &[u8]) -> Result<Vec<&str>> {
+fn parse_elf(bytes: &[u8]) -> ScanResult<ElfInfo<'_>> {
     use goblin::{
         container::{Container, Ctx, Endian},
-        elf::{Dynamic, Elf, ProgramHeader},
+        elf::{
+            dynamic::{DT_RPATH, DT_RUNPATH},
+            program_header::PT_INTERP,
+            Dynamic, Elf, ProgramHeader,
+        },
         strtab::Strtab,
     };
-    let mut libraries = Vec::new();
+    let mut info = ElfInfo::default();
     let header = Elf::parse_header(bytes)?;
     let elf = Elf::lazy_parse(header)?;
     let container = if elf.is_64 {
@@ -525,24 +1025,39 @@ fn parse_elf(bytes: &[u8]) -> Result<Vec<&str>> {
     );
     let prog_headers =
         ProgramHeader::parse(bytes, header.e_phoff as usize, header.e_phnum as usize, ctx)?;
+    info.interpreter = prog_headers
+        .iter()
+        .find(|h| h.p_type == PT_INTERP)
+        .and_then(|h| bytes.get(h.file_range()))
+        .and_then(|interp| std::str::from_utf8(interp).ok())
+        .map(|interp| interp.trim_end_matches('\0'));
     let dynamic = Dynamic::parse(bytes, &prog_headers, ctx)?;
     if let Some(ref dynamic) = dynamic {
         let dyn_info = &dynamic.info;
         let dynstrtab = Strtab::parse(bytes, dyn_info.strtab, dyn_info.strsz, 0x0)?;
         if dyn_info.needed_count > 0 {
-            libraries = dynamic.get_libraries(&dynstrtab);
+            info.needed = dynamic.get_libraries(&dynstrtab);
+        }
+        for d in &dynamic.dyns {
+            match d.d_tag {
+                DT_RPATH => info.rpath = dynstrtab.get_at(d.d_val as usize),
+                DT_RUNPATH => info.runpath = dynstrtab.get_at(d.d_val as usize),
+                _ => (),
+            }
         }
     }
 
-    Ok(libraries)
+    Ok(info)
 }
 
-/// Scan ELF files for required libraries and soname information
+/// Scan ELF files for required libraries and soname information, and record their interpreter
+/// and library search paths
 fn scan_elf<R: Read>(
     entry: &mut tar::Entry<'_, R>,
     provides: &mut HashSet<String>,
     requires: &mut HashSet<String>,
-) -> Result<()> {
+    elf_files: &mut Vec<ElfFile>,
+) -> ScanResult<()> {
     let header = entry.header();
     // check if needs to parse as ELF
     if !header.entry_type().is_file() || entry.size() < 4 {
@@ -559,10 +1074,20 @@ fn scan_elf<R: Read>(
     let mut content = Vec::with_capacity(entry.size() as usize);
     entry.read_to_end(&mut content)?;
     elf_header.extend(content);
-    let libraries = parse_elf(&elf_header)?;
-    for i in libraries {
+    let info = parse_elf(&elf_header)?;
+    for i in info.needed {
         requires.insert(i.to_string());
     }
+    if info.interpreter.is_some() || info.rpath.is_some() || info.runpath.is_some() {
+        if let Some(path) = entry.path()?.to_str() {
+            elf_files.push(ElfFile {
+                path: normalize_path(path).to_string(),
+                interpreter: info.interpreter.map(|s| s.to_string()),
+                rpath: info.rpath.map(|s| s.to_string()),
+                runpath: info.runpath.map(|s| s.to_string()),
+            });
+        }
+    }
 
     // we should not use SONAME for so provides, since the dynamic linker only
     // uses the file name to handle DT_NEEDED requests
@@ -578,10 +1103,12 @@ fn scan_elf<R: Read>(
     Ok(())
 }
 
-/// Collect information on the package file contents
-fn collect_files<R: Read>(reader: R) -> Result<PackageContents> {
+/// Collect information on the package file contents, and on the ELF files among them
+/// when `with_elf` is set
+fn collect_files<R: Read>(reader: R, with_elf: bool) -> ScanResult<PackageContents> {
     let mut provides = HashSet::new();
     let mut requires = HashSet::new();
+    let mut elf = Vec::new();
     let mut tar = TarArchive::new(reader);
     let mut files = Vec::with_capacity(100);
     for entry in tar.entries()? {
@@ -598,6 +1125,9 @@ fn collect_files<R: Read>(reader: R) -> Result<PackageContents> {
             uname: header.username_bytes().map(|x| x.to_owned()),
             gname: header.groupname_bytes().map(|x| x.to_owned()),
         });
+        if !with_elf {
+            continue;
+        }
         // ================= ELF processing
         // find so provides and requires
         //
@@ -621,12 +1151,9 @@ fn collect_files<R: Read>(reader: R) -> Result<PackageContents> {
                 }
             }
         }
-        if let Err(e) = scan_elf(&mut entry, &mut provides, &mut requires) {
+        if let Err(e) = scan_elf(&mut entry, &mut provides, &mut requires, &mut elf) {
             let file_path = entry.path()?.to_path_buf();
-            error!(
-                "Problems parsing ELF: {:?}",
-                e.context(format!("when checking {:?}", file_path))
-            );
+            error!("Problems parsing ELF when checking {:?}: {}", file_path, e);
         }
     }
 
@@ -634,105 +1161,244 @@ fn collect_files<R: Read>(reader: R) -> Result<PackageContents> {
         files,
         so_provides: provides,
         so_requires: requires,
+        elf,
     })
 }
 
+/// Collect the package metadata from the parsed control fields, the fields left over are kept
+/// in `extra`
+fn parse_deb_meta(
+    mut meta: HashMap<&[u8], &[u8]>,
+    filename: &str,
+    debtime: u64,
+) -> ScanResult<DebMeta> {
+    let (desc, desc_body) = split_description(&must_have_text!(meta, "Description", filename));
+
+    Ok(DebMeta {
+        name: must_have!(meta, "Package"),
+        version: must_have!(meta, "Version"),
+        section: must_have!(meta, "Section"),
+        desc,
+        desc_body,
+        arch: must_have!(meta, "Architecture"),
+        inst_size: must_have!(meta, "Installed-Size"),
+        maintainer: must_have_text!(meta, "Maintainer", filename),
+        features: meta
+            .remove("X-AOSC-Features".as_bytes())
+            .map(|x| String::from_utf8_lossy(x).to_string()),
+        built_using: meta
+            .remove("Built-Using".as_bytes())
+            .map(|x| String::from_utf8_lossy(x).to_string()),
+        homepage: meta
+            .remove("Homepage".as_bytes())
+            .map(|x| String::from_utf8_lossy(x).to_string()),
+        bugs: meta
+            .remove("Bugs".as_bytes())
+            .map(|x| String::from_utf8_lossy(x).to_string()),
+        origin: meta
+            .remove("Origin".as_bytes())
+            .map(|x| String::from_utf8_lossy(x).to_string()),
+        tags: meta
+            .remove("Tag".as_bytes())
+            .map(|x| split_tags(&String::from_utf8_lossy(x)))
+            .unwrap_or_default(),
+        extra: collect_left_over_fields(meta),
+        debtime,
+    })
+}
+
+/// Check the content of the `debian-binary` member. Only the major version 2 is supported,
+/// the minor version is accepted as long as it is a number, as dpkg does
+fn check_debian_binary(content: &[u8]) -> ScanResult<()> {
+    let version = content.strip_suffix(b"\n").unwrap_or(content);
+    let version = String::from_utf8_lossy(version);
+    match version.split_once('.') {
+        Some(("2", minor)) if !minor.is_empty() && minor.bytes().all(|c| c.is_ascii_digit()) => {
+            Ok(())
+        }
+        _ => Err(ScanError::UnsupportedVersion(format!("{:?}", version))),
+    }
+}
+
 /// Advanced deb package reader. Scans control and package files
 fn open_deb_advanced<'a, R: Read + 'a>(
     reader: HashedReader<R>,
-    stat: Metadata,
-    filename: &str,
+    entry: &PackageEntry,
     branch: (String, String),
-) -> Result<PackageMeta> {
+    with_elf: bool,
+) -> ScanResult<PackageMeta> {
+    let filename = &entry.filename;
     let mut deb = ArArchive::new(reader);
     let mut metadata = None;
     let mut files = None;
+    let mut version_checked = false;
     while let Some(entry) = deb.next_entry() {
         if entry.is_err() {
             continue;
         }
-        let entry = entry?;
-        let filename = entry.header().identifier();
-        if filename.starts_with(b"control.tar") {
+        let mut entry = entry?;
+        let identifier = entry.header().identifier();
+        if !version_checked {
+            // like dpkg, require the format version to come first
+            if identifier != b"debian-binary" {
+                return Err(ScanError::NotDebian(format!(
+                    "first member is `{}` instead of `debian-binary`",
+                    String::from_utf8_lossy(identifier)
+                )));
+            }
+            let mut version = Vec::new();
+            entry.by_ref().take(64).read_to_end(&mut version)?;
+            check_debian_binary(&version)?;
+            version_checked = true;
+        } else if identifier.starts_with(b"control.tar") {
             let debtime = entry.header().mtime();
-            let format = determine_format(filename)?;
+            let format = determine_format(identifier)?;
             let control = open_compressed_control(entry, &format)?;
             let meta = crate::parser::single_package_map(&control);
             if let Err(e) = meta {
-                return Err(anyhow!("{:?}", e));
+                return Err(ScanError::MalformedControl(e.to_string()));
             }
-            let parsed_control = meta.unwrap();
-            let mut meta = parsed_control.1;
-            metadata = Some(DebMeta {
-                name: must_have!(meta, "Package"),
-                version: must_have!(meta, "Version"),
-                section: must_have!(meta, "Section"),
-                desc: must_have!(meta, "Description"),
-                arch: must_have!(meta, "Architecture"),
-                inst_size: must_have!(meta, "Installed-Size"),
-                maintainer: must_have!(meta, "Maintainer"),
-                features: meta
-                    .remove("X-AOSC-Features".as_bytes())
-                    .map(|x| String::from_utf8_lossy(&x).to_string()),
-                extra: collect_left_over_fields(meta),
-                debtime,
-            });
-        } else if filename.starts_with(b"data.tar") {
-            let format = determine_format(filename)?;
-            files = Some(open_compressed_data(entry, &format)?);
+            metadata = Some(parse_deb_meta(meta.unwrap().1, filename, debtime)?);
+        } else if identifier.starts_with(b"data.tar") {
+            let format = determine_format(identifier)?;
+            files = Some(open_compressed_data(entry, &format, with_elf)?);
         }
     }
 
-    if metadata.is_none() || files.is_none() {
-        Err(anyhow!("data archive not found or format unsupported"))
-    } else {
-        let sha256 = deb.into_inner()?.get_hash()?;
-        let metadata = metadata.unwrap();
-        let mtime = mtime(&stat)?;
-        Ok(PackageMeta {
-            repo: branch,
-            deb: metadata,
-            size: stat.len(),
-            filename: filename.to_string(),
+    match (metadata, files) {
+        (Some(metadata), Some(files)) => {
+            let sha256 = deb.into_inner()?.get_hash()?;
+            Ok(PackageMeta {
+                repo: branch,
+                deb: metadata,
+                size: entry.size,
+                filename: filename.clone(),
+                sha256,
+                mtime: entry.mtime,
+                contents: files,
+                imported: false,
+            })
+        }
+        _ => Err(ScanError::MissingData),
+    }
+}
+
+/// Read the packages listed in an existing `Packages` index, to import them without their files.
+/// `Filename`, `Size` and `SHA256` are taken as they are, the branch and component come from
+/// the `Filename`.
+pub fn read_packages_index(content: &[u8]) -> ScanResult<Vec<PackageMeta>> {
+    let (rest, stanzas) = crate::parser::parse_packages_stream(content)
+        .map_err(|e| ScanError::MalformedControl(e.to_string()))?;
+    if !rest.is_empty() {
+        return Err(ScanError::MalformedControl(format!(
+            "unexpected data at byte {}",
+            content.len() - rest.len()
+        )));
+    }
+    let mut packages = Vec::with_capacity(stanzas.len());
+    for mut meta in stanzas {
+        let filename = must_have!(meta, "Filename");
+        let size = must_have!(meta, "Size");
+        let size = size.parse::<u64>().map_err(|_| {
+            ScanError::MalformedControl(format!("{}: invalid Size {}", filename, size))
+        })?;
+        let sha256 = must_have!(meta, "SHA256");
+        for field in ["MD5sum", "SHA1", "SHA512", "Description-md5"] {
+            meta.remove(field.as_bytes());
+        }
+        packages.push(PackageMeta {
+            repo: get_branch_name(&filename)?,
+            deb: parse_deb_meta(meta, &filename, 0)?,
+            size,
             sha256,
-            mtime,
-            contents: files.unwrap(),
-        })
+            mtime: 0,
+            contents: PackageContents {
+                files: Vec::new(),
+                so_provides: HashSet::new(),
+                so_requires: HashSet::new(),
+                elf: Vec::new(),
+            },
+            imported: true,
+            filename,
+        });
     }
+
+    Ok(packages)
 }
 
 /// Advanced version of scanning deb packages. With bells and whistles.
-pub(crate) fn scan_single_deb_advanced<P: AsRef<Path>>(path: P, root: P) -> Result<PackageMeta> {
-    let stat = path.as_ref().metadata()?;
-    let f = File::open(path.as_ref())?;
-    let f = unsafe { memmap2::Mmap::map(&f)? };
-    let rel_filename = path.as_ref().strip_prefix(root.as_ref())?;
-    let component = get_branch_name(rel_filename)?;
-
-    open_deb_advanced(
-        HashedReader::new(&*f),
-        stat,
-        &rel_filename.to_string_lossy(),
-        component,
-    )
+/// When a keyring is given, the package must be signed by one of its keys.
+/// The ELF files are only parsed when `with_elf` is set.
+pub(crate) fn scan_single_deb_advanced(
+    source: &dyn PackageSource,
+    entry: &PackageEntry,
+    keyring: Option<&[sequoia_openpgp::Cert]>,
+    with_elf: bool,
+) -> ScanResult<PackageMeta> {
+    let component = get_branch_name(&entry.filename)?;
+    if let Some(keyring) = keyring {
+        super::signature::verify_package_signature(source, &entry.filename, keyring)?;
+    }
+    let f = source.open(&entry.filename)?;
+
+    open_deb_advanced(HashedReader::new(f), entry, component, with_elf)
 }
 
 #[test]
 fn test_deb_adv() {
+    let source = super::source::LocalSource::new("./tests");
+    let stat = |filename: &str| source.stat(filename).unwrap().unwrap();
     let content = scan_single_deb_advanced(
-        "./tests/pool/tests/fixtures/a2jmidid_9-0_amd64.deb",
-        "./tests",
+        &source,
+        &stat("pool/tests/fixtures/a2jmidid_9-0_amd64.deb"),
+        None,
+        true,
     )
     .unwrap();
     assert_eq!(
         &content.sha256,
         "6a7dd466854f6c1f4a597f0c547acf1f90d8298a04f4a2ca31f96a7c9dca8bc3"
     );
+    let mut elf = content
+        .contents
+        .elf
+        .iter()
+        .map(|e| {
+            (
+                e.path.as_str(),
+                e.interpreter.as_deref(),
+                e.rpath.as_deref(),
+            )
+        })
+        .collect::<Vec<_>>();
+    elf.sort_unstable();
+    assert_eq!(
+        elf,
+        [
+            (
+                "usr/bin/a2jmidi_bridge",
+                Some("/lib64/ld-linux-x86-64.so.2"),
+                None
+            ),
+            (
+                "usr/bin/a2jmidid",
+                Some("/lib64/ld-linux-x86-64.so.2"),
+                None
+            ),
+            (
+                "usr/bin/j2amidi_bridge",
+                Some("/lib64/ld-linux-x86-64.so.2"),
+                None
+            ),
+        ]
+    );
     println!("{:?}", content);
 
     let content = scan_single_deb_advanced(
-        "./tests/pool/tests/fixtures/aosc-aaa_11.6.0-1~pre20241017T062346Z_amd64.deb",
-        "./tests",
+        &source,
+        &stat("pool/tests/fixtures/aosc-aaa_11.6.0-1~pre20241017T062346Z_amd64.deb"),
+        None,
+        true,
     )
     .unwrap();
     assert_eq!(content.deb.features, Some("core".to_string()));
@@ -740,6 +1406,104 @@ fn test_deb_adv() {
     println!("{:?}", content);
 }
 
+#[test]
+fn test_deb_adv_without_elf() {
+    let source = super::source::LocalSource::new("./tests");
+    let entry = source
+        .stat("pool/tests/fixtures/a2jmidid_9-0_amd64.deb")
+        .unwrap()
+        .unwrap();
+    let with_elf = scan_single_deb_advanced(&source, &entry, None, true).unwrap();
+    let content = scan_single_deb_advanced(&source, &entry, None, false).unwrap();
+    assert_eq!(content.sha256, with_elf.sha256);
+    assert_eq!(content.contents.files.len(), with_elf.contents.files.len());
+    assert!(!with_elf.contents.so_requires.is_empty());
+    assert!(content.contents.so_requires.is_empty());
+    assert!(content.contents.so_provides.is_empty());
+    assert!(content.contents.elf.is_empty());
+}
+
+#[test]
+fn test_deb_adv_no_files() {
+    let source = super::source::LocalSource::new("./tests");
+    let entry = source
+        .stat("pool/tests/fixtures/aosc-meta-empty_1.0-0_noarch.deb")
+        .unwrap()
+        .unwrap();
+    let content = scan_single_deb_advanced(&source, &entry, None, true).unwrap();
+    assert_eq!(content.deb.name, "aosc-meta-empty");
+    assert_eq!(content.deb.arch, "all");
+    assert!(content.contents.files.is_empty());
+    assert!(content.contents.so_provides.is_empty());
+    assert_eq!(
+        render_contents_fragment(
+            &content.deb.section,
+            &content.deb.name,
+            &content.contents.files
+        ),
+        ""
+    );
+    // an empty stream and a bare end-of-archive marker are both valid empty archives
+    assert!(collect_files(&b""[..], true).unwrap().files.is_empty());
+    assert!(collect_files(&[0u8; 1024][..], true)
+        .unwrap()
+        .files
+        .is_empty());
+}
+
+#[test]
+fn test_deb_format_version() {
+    assert!(check_debian_binary(b"2.0\n").is_ok());
+    assert!(check_debian_binary(b"2.1\n").is_ok());
+    assert!(check_debian_binary(b"3.0\n").is_err());
+    assert!(check_debian_binary(b"2.\n").is_err());
+    assert!(check_debian_binary(b"!<arch>\n").is_err());
+
+    let open = |members: &[(&str, &[u8])]| {
+        let mut builder = ar::Builder::new(Vec::new());
+        for (name, data) in members {
+            let header = ar::Header::new(name.as_bytes().to_vec(), data.len() as u64);
+            builder.append(&header, *data).unwrap();
+        }
+        let archive = builder.into_inner().unwrap();
+        let entry = PackageEntry {
+            filename: "pool/stable/main/t/test_1.0_amd64.deb".to_string(),
+            size: archive.len() as u64,
+            mtime: 0,
+        };
+        open_deb_advanced(
+            HashedReader::new(&archive[..]),
+            &entry,
+            ("stable".to_string(), "main".to_string()),
+            true,
+        )
+    };
+    // a static library renamed to .deb
+    assert!(matches!(
+        open(&[("foo.o", b"\x7fELF")]),
+        Err(ScanError::NotDebian(_))
+    ));
+    assert!(matches!(
+        open(&[("control.tar.xz", b""), ("debian-binary", b"2.0\n")]),
+        Err(ScanError::NotDebian(_))
+    ));
+    assert!(matches!(
+        open(&[("debian-binary", b"3.0\n"), ("control.tar.xz", b"")]),
+        Err(ScanError::UnsupportedVersion(_))
+    ));
+    assert!(matches!(
+        open(&[("debian-binary", b"2.0\n")]),
+        Err(ScanError::MissingData)
+    ));
+}
+
+#[test]
+fn test_section_component_lint() {
+    assert!(check_section_component("games", "main").is_ok());
+    assert!(check_section_component("non-free/games", "non-free").is_ok());
+    assert!(check_section_component("non-free/games", "main").is_err());
+}
+
 #[test]
 fn so_name_splitter() {
     let so = "libclang.so.1";
@@ -747,3 +1511,194 @@ fn so_name_splitter() {
     let so = "libclang.so";
     assert_eq!(split_so_name(so), (Some("libclang.so"), None));
 }
+
+#[test]
+fn test_split_tags() {
+    assert_eq!(
+        split_tags("implemented-in::c, role::program,interface::commandline ,"),
+        vec![
+            "implemented-in::c",
+            "role::program",
+            "interface::commandline"
+        ]
+    );
+    assert!(split_tags(" ").is_empty());
+}
+
+#[test]
+fn test_read_packages_index() {
+    let index = b"Package: bash
+Version: 5.2-1
+Section: shells
+Architecture: amd64
+Installed-Size: 8000
+Maintainer: Bot <bot@aosc.io>
+Filename: pool/stable/main/b/bash_5.2-1_amd64.deb
+Size: 1234
+SHA256: abcd
+Description: GNU Bourne Again shell
+ Bash is an sh-compatible shell.
+Depends: glibc
+Tag: role::program
+
+Package: broken
+Version: 1
+Filename: pool/stable/main/b/broken_1_amd64.deb
+Size: 1
+SHA256: abcd
+";
+    assert!(matches!(
+        read_packages_index(index),
+        Err(ScanError::MissingField(ref f)) if f == "Description"
+    ));
+    let end = std::str::from_utf8(index).unwrap().find("\n\n").unwrap() + 2;
+    let packages = read_packages_index(&index[..end]).unwrap();
+    assert_eq!(packages.len(), 1);
+    let bash = &packages[0];
+    assert!(bash.imported);
+    assert_eq!(bash.repo, ("stable".to_string(), "main".to_string()));
+    assert_eq!(bash.size, 1234);
+    assert_eq!(bash.sha256, "abcd");
+    assert_eq!(bash.deb.desc, "GNU Bourne Again shell");
+    assert_eq!(bash.deb.tags, vec!["role::program"]);
+    assert_eq!(bash.deb.extra.get(&b"Depends"[..]).unwrap(), b"glibc");
+    assert!(!bash.deb.extra.contains_key(&b"Filename"[..]));
+}
+
+#[test]
+fn test_select_extra_fields() {
+    let extra = HashMap::from([
+        (b"Multi-Arch".to_vec(), b"same".to_vec()),
+        (b"X-Vendor-Id".to_vec(), b"42".to_vec()),
+        (b"Depends".to_vec(), b"glibc".to_vec()),
+    ]);
+    let wanted = ["x-vendor-id", "Depends", "Multi-Arch", "Missing"].map(|f| f.to_string());
+    assert_eq!(
+        select_extra_fields(&extra, &wanted),
+        vec![
+            ("X-Vendor-Id".to_string(), "42".to_string()),
+            ("Multi-Arch".to_string(), "same".to_string())
+        ]
+    );
+}
+
+#[test]
+fn test_scan_error_kinds() {
+    assert!(matches!(
+        determine_format(b"data.tar.bz2"),
+        Err(ScanError::UnknownFormat(ref f)) if f == "data.tar.bz2"
+    ));
+    assert!(matches!(
+        get_branch_name("debs/stable/main/a.deb"),
+        Err(ScanError::InvalidPath(_))
+    ));
+    assert!(matches!(
+        check_section_component("non-free/games", "main"),
+        Err(ScanError::SectionMismatch { .. })
+    ));
+    assert!(matches!(
+        scan_single_deb_advanced(
+            &super::source::LocalSource::new("./tests"),
+            &PackageEntry {
+                filename: "pool/tests/missing.deb".to_string(),
+                size: 0,
+                mtime: 0,
+            },
+            None,
+            true
+        ),
+        Err(ScanError::Io(_))
+    ));
+}
+
+#[test]
+fn test_repo_key_name() {
+    let repo = |component: &str| ("stable".to_string(), component.to_string());
+    assert_eq!(get_repo_key_name(&repo("main"), "amd64", "main"), "amd64");
+    assert_eq!(
+        get_repo_key_name(&repo("main"), "amd64", "core"),
+        "main-amd64"
+    );
+    assert_eq!(get_repo_key_name(&repo("core"), "arm64", "core"), "arm64");
+}
+
+#[test]
+fn test_render_contents_fragment() {
+    let file = |path: &str, type_: u8| PackageFile {
+        path: PathBuf::from(path),
+        is_dir: type_ == b'5',
+        size: 0,
+        type_,
+        perms: 0o644,
+        uid: 0,
+        gid: 0,
+        uname: None,
+        gname: None,
+    };
+    let files = [
+        file("./usr/share/doc/zsync/", b'5'),
+        file("./usr/bin/zsyncmake", b'0'),
+        file("./usr/bin/zsync", b'0'),
+        file("./usr/lib/libzsync.so", b'2'),
+    ];
+    assert_eq!(
+        render_contents_fragment("net", "zsync", &files),
+        "usr/bin/zsync   net/zsync\n\
+usr/bin/zsyncmake   net/zsync\n\
+usr/lib/libzsync.so   net/zsync\n"
+    );
+    assert_eq!(render_contents_fragment("net", "zsync", &files[..1]), "");
+}
+
+#[test]
+fn test_decode_latin1() {
+    assert_eq!(decode_latin1(b"Jos\xe9 M\xfcller"), "José Müller");
+    assert_eq!(decode_latin1(b"AOSC OS"), "AOSC OS");
+}
+
+#[test]
+fn test_collect_stale_metadata() {
+    let source = super::source::LocalSource::new("./tests");
+    let package = |filename: &str, size, mtime| db::PVPackage {
+        package: None,
+        version: None,
+        repo: None,
+        architecture: None,
+        filename: Some(filename.to_string()),
+        size: Some(size),
+        mtime: Some(mtime),
+        sha256: None,
+        imported: None,
+    };
+    let filename = "pool/tests/fixtures/a2jmidid_9-0_amd64.deb";
+    let mtime = source.stat(filename).unwrap().unwrap().mtime as i32;
+    let packages = vec![
+        package(filename, 32424, mtime),
+        package(filename, 32424, mtime - 1),
+        // size changed, needs a rescan
+        package(filename, 1, mtime - 1),
+        package("pool/tests/fixtures/missing.deb", 1, 0),
+    ];
+    let stale = collect_stale_metadata(&source, &packages);
+    assert_eq!(stale.len(), 1);
+    assert_eq!(stale.pop(), Some((filename.to_string(), mtime as u64)));
+}
+
+#[test]
+fn test_split_description() {
+    assert_eq!(
+        split_description("A daemon"),
+        ("A daemon".to_string(), None)
+    );
+    assert_eq!(
+        split_description("A daemon\n It bridges ALSA and JACK.\n .\n  Indented line"),
+        (
+            "A daemon".to_string(),
+            Some("It bridges ALSA and JACK.\n.\n Indented line".to_string())
+        )
+    );
+    assert_eq!(
+        split_description("A daemon\n \n"),
+        ("A daemon".to_string(), None)
+    );
+}
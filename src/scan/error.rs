@@ -0,0 +1,56 @@
+//! Errors returned by the scanning functions.
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ScanError {
+    /// Compression format of a member archive is not supported
+    #[error("Unknown format: {0}")]
+    UnknownFormat(String),
+    /// A mandatory field is missing from the control file
+    #[error("Missing `{0}` field")]
+    MissingField(String),
+    /// The control file is missing from control.tar
+    #[error("Could not read control file")]
+    MissingControl,
+    /// The file is not a Debian package (e.g. a static library with a `.deb` extension)
+    #[error("Not a Debian package: {0}")]
+    NotDebian(String),
+    /// The `debian-binary` member has a format version this program cannot read
+    #[error("Unsupported deb format version: {0}")]
+    UnsupportedVersion(String),
+    /// The data archive is missing from the package
+    #[error("data archive not found or format unsupported")]
+    MissingData,
+    /// The control file could not be parsed
+    #[error("Malformed control file: {0}")]
+    MalformedControl(String),
+    /// An ELF file in the package could not be parsed
+    #[error("Malformed ELF: {0}")]
+    MalformedElf(#[from] goblin::error::Error),
+    /// The package is not under `pool/<branch>/<component>`
+    #[error("Unexpected path: {0}")]
+    InvalidPath(String),
+    /// The `Section` prefix does not match the component of the package
+    #[error("Section `{section}` does not match component `{component}`")]
+    SectionMismatch { section: String, component: String },
+    /// The package has no signature, while signatures are required
+    #[error("Package is not signed")]
+    Unsigned,
+    /// The package signature is invalid or not made by a trusted key
+    #[error("Bad signature: {0}")]
+    BadSignature(String),
+    /// Requests to the object storage failed
+    #[error("Object storage error: {0}")]
+    ObjectStorage(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Walk(#[from] walkdir::Error),
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+pub type ScanResult<T> = std::result::Result<T, ScanError>;
@@ -1,38 +1,59 @@
-use anyhow::{anyhow, Result};
 use ar::Archive as ArArchive;
 use faster_hex::hex_string;
 use flate2::read::GzDecoder;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use rayon::prelude::*;
 use sha2::{Digest, Sha256};
 use std::fs::Metadata;
 use std::path::PathBuf;
+use std::sync::mpsc::{channel, sync_channel};
 use std::time::UNIX_EPOCH;
 use std::{io::Read, path::Path};
 use tar::Archive as TarArchive;
 use walkdir::{DirEntry, WalkDir};
 use xz2::read::XzDecoder;
+use xz2::stream::Stream as XzStream;
 use zstd::stream::read::Decoder as ZstdDecoder;
 
 mod dbscan;
+mod error;
+#[cfg(feature = "s3")]
+mod s3;
+mod signature;
+pub(crate) mod source;
 
 pub use self::dbscan::*;
+pub use self::error::{ScanError, ScanResult};
+pub use self::source::{open_package_source, PackageEntry, PackageSource};
 
-fn read_compressed<'a, O, R: Read + 'a, C: Fn(Box<dyn Read + 'a>) -> Result<O>>(
+fn read_compressed<'a, O, R: Read + 'a, C: Fn(Box<dyn Read + 'a>) -> ScanResult<O>>(
     format: &TarFormat,
     reader: R,
     control_callback: C,
-) -> Result<O> {
+) -> ScanResult<O> {
     match format {
         TarFormat::Xzip => control_callback(Box::new(XzDecoder::new(reader))),
         TarFormat::Gzip => control_callback(Box::new(GzDecoder::new(reader))),
         TarFormat::Zstd => control_callback(Box::new(ZstdDecoder::new(reader)?)),
+        TarFormat::Lzma => {
+            // legacy .lzma (LZMA-alone) format, found in some ancient packages
+            let stream = XzStream::new_lzma_decoder(u64::MAX).map_err(std::io::Error::from)?;
+            control_callback(Box::new(XzDecoder::new_stream(reader, stream)))
+        }
     }
 }
 
 pub struct HashedReader<R: Read> {
     inner: R,
     hasher: Sha256,
+    bytes_read: u64,
+}
+
+/// Digests and length of the data passed through a [`HashedReader`]
+#[derive(Debug, PartialEq, Eq)]
+pub struct ReadDigest {
+    pub bytes_read: u64,
+    pub sha256: String,
 }
 
 impl<R: Read> HashedReader<R> {
@@ -41,11 +62,12 @@ impl<R: Read> HashedReader<R> {
         Self {
             inner: reader,
             hasher: Sha256::new(),
+            bytes_read: 0,
         }
     }
 
-    /// Consume the current reader and return the sha256 hash of the data
-    pub fn get_hash(mut self) -> std::io::Result<String> {
+    /// Consume the current reader and return the length and digests of the data
+    pub fn finish(mut self) -> std::io::Result<ReadDigest> {
         // first, drain the inner reader until it reaches EOF
         let mut buffer = [0u8; 4096];
         loop {
@@ -57,31 +79,111 @@ impl<R: Read> HashedReader<R> {
             }
         }
 
-        Ok(hex_string(&self.hasher.finalize()))
+        Ok(ReadDigest {
+            bytes_read: self.bytes_read,
+            sha256: hex_string(&self.hasher.finalize()),
+        })
+    }
+
+    /// Consume the current reader and return the sha256 hash of the data
+    pub fn get_hash(self) -> std::io::Result<String> {
+        Ok(self.finish()?.sha256)
+    }
+}
+
+/// Size of the chunks passed from the reading thread to the hashing thread
+const PIPELINE_CHUNK_SIZE: usize = 1024 * 1024;
+/// Number of chunks read ahead of the hashing
+const PIPELINE_DEPTH: usize = 4;
+
+impl<R: Read + Send> HashedReader<R> {
+    /// Like [`finish`](Self::finish), but the data is read on a separate thread, so that the
+    /// I/O overlaps with the hashing. Only worth the thread for large inputs.
+    pub fn finish_pipelined(self) -> std::io::Result<ReadDigest> {
+        let HashedReader {
+            mut inner,
+            mut hasher,
+            mut bytes_read,
+        } = self;
+        let (chunk_tx, chunk_rx) = sync_channel::<std::io::Result<Vec<u8>>>(PIPELINE_DEPTH);
+        // hashed chunks are handed back to the reader to avoid reallocating them
+        let (free_tx, free_rx) = channel::<Vec<u8>>();
+        std::thread::scope(|s| {
+            s.spawn(move || loop {
+                let mut chunk = free_rx
+                    .try_recv()
+                    .unwrap_or_else(|_| vec![0u8; PIPELINE_CHUNK_SIZE]);
+                chunk.resize(PIPELINE_CHUNK_SIZE, 0);
+                let result = read_chunk(&mut inner, &mut chunk).map(|filled| {
+                    chunk.truncate(filled);
+                    chunk
+                });
+                let done = !matches!(result, Ok(ref chunk) if !chunk.is_empty());
+                // the hashing side is gone (failed) if the send fails
+                if chunk_tx.send(result).is_err() || done {
+                    break;
+                }
+            });
+            for chunk in chunk_rx {
+                let chunk = chunk?;
+                if chunk.is_empty() {
+                    break;
+                }
+                hasher.update(&chunk);
+                bytes_read += chunk.len() as u64;
+                let _ = free_tx.send(chunk);
+            }
+
+            Ok(ReadDigest {
+                bytes_read,
+                sha256: hex_string(&hasher.finalize()),
+            })
+        })
     }
 }
 
+/// Fill the buffer as much as possible, returns the number of bytes read (0 at EOF)
+fn read_chunk<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(filled)
+}
+
 impl<R: Read> Read for HashedReader<R> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         let size = self.inner.read(buf)?;
         self.hasher.update(&buf[..size]);
+        self.bytes_read += size as u64;
 
         Ok(size)
     }
 }
 
-pub(crate) fn mtime(stat: &Metadata) -> Result<u64> {
-    Ok(stat.modified()?.duration_since(UNIX_EPOCH)?.as_secs())
+pub(crate) fn mtime(stat: &Metadata) -> ScanResult<u64> {
+    Ok(stat
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_err(std::io::Error::other)?
+        .as_secs())
 }
 
 enum TarFormat {
     Xzip,
     Gzip,
     Zstd,
+    Lzma,
 }
 
 /// Collect control information
-fn collect_control<R: Read>(reader: R) -> Result<Vec<u8>> {
+fn collect_control<R: Read>(reader: R) -> ScanResult<Vec<u8>> {
     let mut tar = TarArchive::new(reader);
     for entry in tar.entries()? {
         let mut entry = entry?;
@@ -93,61 +195,119 @@ fn collect_control<R: Read>(reader: R) -> Result<Vec<u8>> {
         }
     }
 
-    Err(anyhow!("Could not read control file"))
+    Err(ScanError::MissingControl)
 }
 
-fn open_compressed_control<R: Read>(reader: R, format: &TarFormat) -> Result<Vec<u8>> {
+fn open_compressed_control<R: Read>(reader: R, format: &TarFormat) -> ScanResult<Vec<u8>> {
     read_compressed(format, reader, collect_control)
 }
 
 /// Determine the compression format based on the extension name
-fn determine_format(format: &[u8]) -> Result<TarFormat> {
+fn determine_format(format: &[u8]) -> ScanResult<TarFormat> {
     if format.ends_with(b".xz") {
         Ok(TarFormat::Xzip)
     } else if format.ends_with(b".gz") {
         Ok(TarFormat::Gzip)
     } else if format.ends_with(b".zst") {
         Ok(TarFormat::Zstd)
+    } else if format.ends_with(b".lzma") {
+        Ok(TarFormat::Lzma)
     } else {
-        Err(anyhow!("Unknown format: {:?}", format))
+        Err(ScanError::UnknownFormat(
+            String::from_utf8_lossy(format).to_string(),
+        ))
     }
 }
 
 /// Calculate the Sha256 checksum of the given stream
-pub fn sha256sum<R: Read>(mut reader: R) -> Result<String> {
+pub fn sha256sum<R: Read>(mut reader: R) -> ScanResult<String> {
     let mut hasher = Sha256::new();
     std::io::copy(&mut reader, &mut hasher)?;
 
     Ok(hex_string(&hasher.finalize()))
 }
 
+/// Whether the file is a package, installer packages (`.udeb`) are only included on request
 #[inline]
-fn is_deb(entry: &DirEntry) -> bool {
-    entry
-        .file_name()
-        .to_str()
-        .map(|s| s.ends_with(".deb"))
-        .unwrap_or(false)
+fn is_deb(filename: &str, include_udeb: bool) -> bool {
+    filename.ends_with(".deb") || (include_udeb && filename.ends_with(".udeb"))
 }
 
-pub fn scan_packages_advanced(entries: &[&Path], root: &Path) -> Vec<PackageMeta> {
-    entries
+/// A package that could not be scanned
+#[derive(Debug)]
+pub struct ScanFailure {
+    /// Filename (relative to the mirror root)
+    pub filename: String,
+    /// Reason of the failure
+    pub error: String,
+}
+
+/// Name of the package in a pool file name (`<name>_<version>_<arch>.deb`)
+fn package_name_of(filename: &str) -> &str {
+    let name = filename.rsplit('/').next().unwrap_or(filename);
+
+    name.split('_').next().unwrap_or(name)
+}
+
+/// Check if the file is of one of the `excluded` packages, matched by file name so that it is
+/// never opened
+pub fn is_package_excluded(filename: &str, excluded: &[String]) -> bool {
+    excluded
+        .iter()
+        .any(|name| name == package_name_of(filename))
+}
+
+/// Scan the packages. The ELF files are only parsed when `with_elf` is set
+pub fn scan_packages_advanced(
+    source: &dyn PackageSource,
+    entries: &[&PackageEntry],
+    strict: bool,
+    keyring: Option<&[sequoia_openpgp::Cert]>,
+    with_elf: bool,
+) -> (Vec<PackageMeta>, Vec<ScanFailure>) {
+    let progress = crate::status::Progress::new("Scanned", entries.len());
+    let results = entries
         .par_iter()
-        .filter_map(|entry| {
-            info!("Scanning {} ...", entry.display());
-            match scan_single_deb_advanced(entry, &root) {
-                Ok(meta) => Some(meta),
+        .map(|entry| {
+            info!("Scanning {} ...", entry.filename);
+            let failure = |err: String| ScanFailure {
+                filename: entry.filename.clone(),
+                error: err,
+            };
+            let result = scan_single_deb_advanced(source, entry, keyring, with_elf);
+            progress.inc();
+            match result {
+                Ok(meta) => {
+                    if let Err(err) = meta.lint_section() {
+                        if strict {
+                            error!("{}: {}", entry.filename, err);
+                            return Err(failure(err.to_string()));
+                        }
+                        warn!("{}: {}", entry.filename, err);
+                    }
+                    Ok(meta)
+                }
                 Err(err) => {
-                    error!("{}: {:?}", entry.display(), err);
-                    None
+                    error!("{}: {}", entry.filename, err);
+                    Err(failure(err.to_string()))
                 }
             }
         })
-        .collect()
+        .collect::<Vec<_>>();
+    let mut packages = Vec::with_capacity(results.len());
+    let mut failures = Vec::new();
+    for result in results {
+        match result {
+            Ok(meta) => packages.push(meta),
+            Err(failure) => failures.push(failure),
+        }
+    }
+
+    (packages, failures)
 }
 
 /// Auto-discover topics and components under the specified directory
-pub fn discover_topics_components<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>> {
+pub fn discover_topics_components<P: AsRef<Path>>(path: P) -> ScanResult<Vec<PathBuf>> {
     let mut topics = Vec::new();
 
     for entry in WalkDir::new(path.as_ref())
@@ -157,24 +317,87 @@ pub fn discover_topics_components<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf
         .filter_entry(|x| x.file_type().is_dir())
     {
         let entry = entry?;
-        let name = entry.path().strip_prefix(path.as_ref())?;
+        let name = entry
+            .path()
+            .strip_prefix(path.as_ref())
+            .map_err(|_| ScanError::InvalidPath(entry.path().display().to_string()))?;
         topics.push(name.to_owned());
     }
 
     Ok(topics)
 }
 
-/// Walk through all the packages in a repository (no scanning)
-pub fn collect_all_packages<P: AsRef<Path>>(path: P) -> Result<Vec<DirEntry>> {
-    let mut files = Vec::with_capacity(1000);
-    for entry in WalkDir::new(path.as_ref()) {
-        let entry = entry?;
-        if is_deb(&entry) {
-            files.push(entry);
+/// Whether the file or directory should be skipped: hidden files/directories
+/// and files with one of the ignored extensions
+fn is_ignored_name(name: &str, is_dir: bool, ignored_extensions: &[String]) -> bool {
+    if name.starts_with('.') {
+        return true;
+    }
+    if is_dir {
+        return false;
+    }
+    match name.rsplit_once('.') {
+        Some((_, ext)) => ignored_extensions.iter().any(|x| x == ext),
+        None => false,
+    }
+}
+
+/// Whether the entry should be pruned from the walk, see [`is_ignored_name`]
+fn is_ignored(entry: &DirEntry, ignored_extensions: &[String]) -> bool {
+    // never prune the starting directory itself
+    if entry.depth() == 0 {
+        return false;
+    }
+    match entry.file_name().to_str() {
+        Some(name) => is_ignored_name(name, entry.file_type().is_dir(), ignored_extensions),
+        None => false,
+    }
+}
+
+/// Whether the file at the `/`-separated path should be skipped, see [`is_ignored_name`]
+#[cfg_attr(not(feature = "s3"), allow(dead_code))]
+fn is_ignored_path(path: &str, ignored_extensions: &[String]) -> bool {
+    let mut components = path.split('/').peekable();
+    while let Some(name) = components.next() {
+        let is_dir = components.peek().is_some();
+        if is_ignored_name(name, is_dir, ignored_extensions) {
+            return true;
         }
     }
 
-    Ok(files)
+    false
+}
+
+/// Split a package list into paths: one path per line, blank lines and `#` comments are skipped
+pub fn split_package_list(list: &str) -> Vec<&str> {
+    list.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.trim_start_matches("./"))
+        .collect()
+}
+
+#[test]
+fn test_hashed_reader_pipelined() {
+    let test_data = (0..PIPELINE_CHUNK_SIZE * 3 + 7)
+        .map(|i| i as u8)
+        .collect::<Vec<_>>();
+    let expected = HashedReader::new(&test_data[..]).finish().unwrap();
+    assert_eq!(expected.bytes_read, test_data.len() as u64);
+    assert_eq!(
+        HashedReader::new(&test_data[..])
+            .finish_pipelined()
+            .unwrap(),
+        expected
+    );
+    // when reader is not fully read
+    let mut test_reader = HashedReader::new(&test_data[..]);
+    test_reader.read_exact(&mut [0u8; 5]).unwrap();
+    assert_eq!(test_reader.finish_pipelined().unwrap(), expected);
+    assert_eq!(
+        HashedReader::new(&b""[..]).finish_pipelined().unwrap(),
+        HashedReader::new(&b""[..]).finish().unwrap()
+    );
 }
 
 #[test]
@@ -196,10 +419,132 @@ fn test_hashed_reader() {
     // when reader is not fully read
     let mut buffer = [0u8; 4];
     let mut test_reader = HashedReader::new(test_data);
-    test_reader.read(&mut buffer).unwrap();
+    test_reader.read_exact(&mut buffer).unwrap();
     assert_eq!(buffer, &b"1234"[..]);
     assert_eq!(
         test_reader.get_hash().unwrap().as_str(),
         "c775e7b757ede630cd0aa1113bd102661ab38829ca52a6422ab782862f268646"
     );
 }
+
+#[test]
+fn test_hashed_reader_length() {
+    let test_data = &b"1234567890"[..];
+    let mut test_reader = HashedReader::new(test_data);
+    let mut buffer = [0u8; 4];
+    test_reader.read_exact(&mut buffer).unwrap();
+    // the rest of the data is drained when finishing
+    assert_eq!(
+        test_reader.finish().unwrap(),
+        ReadDigest {
+            bytes_read: 10,
+            sha256: "c775e7b757ede630cd0aa1113bd102661ab38829ca52a6422ab782862f268646".to_string()
+        }
+    );
+}
+
+#[test]
+fn test_lzma_format() {
+    use std::io::Write;
+    use xz2::stream::LzmaOptions;
+    use xz2::write::XzEncoder;
+
+    assert!(matches!(
+        determine_format(b"control.tar.lzma"),
+        Ok(TarFormat::Lzma)
+    ));
+    let stream = XzStream::new_lzma_encoder(&LzmaOptions::new_preset(6).unwrap()).unwrap();
+    let mut encoder = XzEncoder::new_stream(Vec::new(), stream);
+    encoder.write_all(b"1234567890").unwrap();
+    let compressed = encoder.finish().unwrap();
+    let decompressed = read_compressed(&TarFormat::Lzma, &compressed[..], |mut r| {
+        let mut buf = Vec::new();
+        r.read_to_end(&mut buf)?;
+        Ok(buf)
+    })
+    .unwrap();
+    assert_eq!(decompressed, b"1234567890");
+}
+
+#[test]
+fn test_collect_ignored_files() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let component = tempdir.path().join("pool/stable/main");
+    std::fs::create_dir_all(component.join(".staging")).unwrap();
+    for name in [
+        "a_1.0_amd64.deb",
+        "a-udeb_1.0_amd64.udeb",
+        "a_1.0_amd64.changes",
+        "a_1.0_amd64.deb.asc",
+        ".b_1.0_amd64.deb",
+        ".staging/c_1.0_amd64.deb",
+    ] {
+        std::fs::write(component.join(name), b"").unwrap();
+    }
+    let files = source::LocalSource::new(tempdir.path())
+        .list_packages(&["changes".to_string()], false)
+        .unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].filename, "pool/stable/main/a_1.0_amd64.deb");
+    let files = source::LocalSource::new(tempdir.path())
+        .list_packages(&["changes".to_string()], true)
+        .unwrap();
+    assert_eq!(files.len(), 2);
+    assert!(is_deb("a_1.0_amd64.deb", false));
+    assert!(!is_deb("a-udeb_1.0_amd64.udeb", false));
+    assert!(is_deb("a-udeb_1.0_amd64.udeb", true));
+    assert!(!is_deb("a_1.0_amd64.deb.asc", true));
+    let ignored = ["changes".to_string()];
+    assert!(!is_ignored_path("stable/main/a_1.0_amd64.deb", &ignored));
+    assert!(is_ignored_path("stable/main/a_1.0_amd64.changes", &ignored));
+    assert!(is_ignored_path("stable/main/.b_1.0_amd64.deb", &ignored));
+    assert!(is_ignored_path(
+        "stable/main/.staging/c_1.0_amd64.deb",
+        &ignored
+    ));
+}
+
+#[test]
+fn test_split_package_list() {
+    let list = "# changed by build 42\nstable/main/a/a_1.0_amd64.deb\n\n  ./stable/main/b/b_2.0_noarch.deb  \n";
+    assert_eq!(
+        split_package_list(list),
+        vec![
+            "stable/main/a/a_1.0_amd64.deb",
+            "stable/main/b/b_2.0_noarch.deb"
+        ]
+    );
+}
+
+#[test]
+fn test_collect_control_prefixes() {
+    for name in [&b"./control"[..], b"control", b"/control"] {
+        let mut header = tar::Header::new_old();
+        header.as_old_mut().name[..name.len()].copy_from_slice(name);
+        header.set_size(13);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.append(&header, &b"Package: foo\n"[..]).unwrap();
+        let tarball = builder.into_inner().unwrap();
+        assert_eq!(
+            collect_control(&tarball[..]).unwrap(),
+            b"Package: foo\n",
+            "{}",
+            String::from_utf8_lossy(name)
+        );
+    }
+}
+
+#[test]
+fn test_package_name_of() {
+    assert_eq!(
+        package_name_of("pool/stable/main/a2jmidid_9-0_amd64.deb"),
+        "a2jmidid"
+    );
+    assert_eq!(
+        package_name_of("pool/stable/main/aosc-aaa_11.6.0-1~pre20241017T062346Z_amd64.deb"),
+        "aosc-aaa"
+    );
+    assert_eq!(package_name_of("lib+plus_1.0_all.deb"), "lib+plus");
+}
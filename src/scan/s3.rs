@@ -0,0 +1,265 @@
+//! Packages in S3-compatible object storage
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use rusty_s3::actions::list_objects_v2::ListObjectsContent;
+use rusty_s3::actions::ListObjectsV2;
+use rusty_s3::{Bucket, Credentials, S3Action, UrlStyle};
+use time::format_description::well_known::{Rfc2822, Rfc3339};
+use time::OffsetDateTime;
+
+use super::source::{PackageEntry, PackageSource};
+use super::{is_deb, is_ignored_path, ScanError, ScanResult};
+use crate::config::GeneralConfig;
+
+/// How long the signed request URLs stay valid
+const SIGNATURE_EXPIRY: Duration = Duration::from_secs(3600);
+
+fn storage_error<E: ToString>(err: E) -> ScanError {
+    ScanError::ObjectStorage(err.to_string())
+}
+
+/// Split `bucket/prefix` into the bucket name and the key prefix (empty or ending with `/`)
+fn split_location(location: &str) -> (&str, String) {
+    match location.trim_end_matches('/').split_once('/') {
+        Some((bucket, prefix)) => (bucket, format!("{}/", prefix)),
+        None => (location.trim_end_matches('/'), String::new()),
+    }
+}
+
+/// Parse the `Last-Modified` header (an HTTP date) into seconds since the Unix epoch
+fn parse_last_modified(value: &str) -> ScanResult<u64> {
+    let mtime = OffsetDateTime::parse(value, &Rfc2822)
+        .map_err(storage_error)?
+        .unix_timestamp();
+
+    Ok(mtime.max(0) as u64)
+}
+
+/// Packages in a bucket of S3-compatible object storage.
+///
+/// The credentials are read from `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`
+/// (and `AWS_SESSION_TOKEN`), anonymous requests are made if they are not set.
+pub struct S3Source {
+    bucket: Bucket,
+    credentials: Option<Credentials>,
+    /// Key prefix of the mirror root
+    prefix: String,
+    agent: ureq::Agent,
+    /// Files in the pool by file name, from the first listing of the whole pool
+    listing: OnceLock<HashMap<String, PackageEntry>>,
+}
+
+impl S3Source {
+    /// Create a source from the location (`bucket/prefix`) of the mirror root
+    pub fn new(location: &str, config: &GeneralConfig) -> ScanResult<Self> {
+        let (bucket, prefix) = split_location(location);
+        let region = config
+            .s3_region
+            .clone()
+            .unwrap_or_else(|| "us-east-1".to_string());
+        let endpoint = match config.s3_endpoint {
+            Some(ref endpoint) => endpoint.clone(),
+            None => format!("https://s3.{}.amazonaws.com", region),
+        };
+        let style = if config.s3_path_style {
+            UrlStyle::Path
+        } else {
+            UrlStyle::VirtualHost
+        };
+        let bucket = Bucket::new(
+            endpoint.parse().map_err(storage_error)?,
+            style,
+            bucket.to_string(),
+            region,
+        )
+        .map_err(storage_error)?;
+
+        Ok(Self {
+            bucket,
+            credentials: Credentials::from_env(),
+            prefix,
+            agent: ureq::Agent::new(),
+            listing: OnceLock::new(),
+        })
+    }
+
+    /// List the objects (and the common prefixes if `delimiter` is set) under the key prefix
+    fn list(
+        &self,
+        prefix: &str,
+        delimiter: Option<&str>,
+    ) -> ScanResult<(Vec<ListObjectsContent>, Vec<String>)> {
+        let mut objects = Vec::new();
+        let mut prefixes = Vec::new();
+        let mut token = None;
+        loop {
+            let mut action = self.bucket.list_objects_v2(self.credentials.as_ref());
+            action.with_prefix(prefix.to_string());
+            // return the keys as they are instead of URL-encoded
+            action.query_mut().remove("encoding-type");
+            if let Some(delimiter) = delimiter {
+                action
+                    .query_mut()
+                    .insert("delimiter", delimiter.to_string());
+            }
+            if let Some(token) = token.take() {
+                action.with_continuation_token(token);
+            }
+            let url = action.sign(SIGNATURE_EXPIRY);
+            let body = self
+                .agent
+                .get(url.as_str())
+                .call()
+                .map_err(storage_error)?
+                .into_string()?;
+            let response = ListObjectsV2::parse_response(&body).map_err(storage_error)?;
+            objects.extend(response.contents);
+            prefixes.extend(response.common_prefixes.into_iter().map(|p| p.prefix));
+            match response.next_continuation_token {
+                Some(next) => token = Some(next),
+                None => break,
+            }
+        }
+
+        Ok((objects, prefixes))
+    }
+
+    /// List all the files in the pool, the listing is kept to answer `stat`
+    fn list_pool(&self) -> ScanResult<Vec<PackageEntry>> {
+        let (objects, _) = self.list(&format!("{}pool/", self.prefix), None)?;
+        let mut files = Vec::with_capacity(objects.len());
+        for object in objects.iter() {
+            files.extend(self.to_entry(object)?);
+        }
+        let listing = files
+            .iter()
+            .map(|f| (f.filename.clone(), f.clone()))
+            .collect();
+        // the first listing wins, a scan only looks at the pool as it was when it started
+        let _ = self.listing.set(listing);
+
+        Ok(files)
+    }
+
+    fn to_entry(&self, object: &ListObjectsContent) -> ScanResult<Option<PackageEntry>> {
+        let Some(filename) = object.key.strip_prefix(&self.prefix) else {
+            return Ok(None);
+        };
+        let mtime = OffsetDateTime::parse(&object.last_modified, &Rfc3339)
+            .map_err(storage_error)?
+            .unix_timestamp();
+
+        Ok(Some(PackageEntry {
+            filename: filename.to_string(),
+            size: object.size,
+            mtime: mtime.max(0) as u64,
+        }))
+    }
+}
+
+impl PackageSource for S3Source {
+    fn list_packages(
+        &self,
+        ignored_extensions: &[String],
+        include_udeb: bool,
+    ) -> ScanResult<Vec<PackageEntry>> {
+        let files = self
+            .list_pool()?
+            .into_iter()
+            .filter(|f| {
+                let path = &f.filename["pool/".len()..];
+                is_deb(path, include_udeb) && !is_ignored_path(path, ignored_extensions)
+            })
+            .collect();
+
+        Ok(files)
+    }
+
+    fn list_components(&self) -> ScanResult<Vec<PathBuf>> {
+        let pool_prefix = format!("{}pool/", self.prefix);
+        let (_, branches) = self.list(&pool_prefix, Some("/"))?;
+        let mut components = Vec::new();
+        for branch in branches {
+            let (_, prefixes) = self.list(&branch, Some("/"))?;
+            components.extend(
+                prefixes
+                    .iter()
+                    .map(|p| PathBuf::from(p[pool_prefix.len()..].trim_end_matches('/'))),
+            );
+        }
+
+        Ok(components)
+    }
+
+    fn stat(&self, filename: &str) -> ScanResult<Option<PackageEntry>> {
+        if let Some(listing) = self.listing.get() {
+            return Ok(listing.get(filename).cloned());
+        }
+        let key = format!("{}{}", self.prefix, filename);
+        let url = self
+            .bucket
+            .head_object(self.credentials.as_ref(), &key)
+            .sign(SIGNATURE_EXPIRY);
+        let response = match self.agent.head(url.as_str()).call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(404, _)) => return Ok(None),
+            Err(e) => return Err(storage_error(e)),
+        };
+        let size = response
+            .header("Content-Length")
+            .and_then(|size| size.parse().ok())
+            .ok_or_else(|| storage_error(format!("{}: no Content-Length", key)))?;
+        let last_modified = response
+            .header("Last-Modified")
+            .ok_or_else(|| storage_error(format!("{}: no Last-Modified", key)))?;
+
+        Ok(Some(PackageEntry {
+            filename: filename.to_string(),
+            size,
+            mtime: parse_last_modified(last_modified)?,
+        }))
+    }
+
+    fn preload(&self) -> ScanResult<()> {
+        if self.listing.get().is_none() {
+            self.list_pool()?;
+        }
+
+        Ok(())
+    }
+
+    fn open(&self, filename: &str) -> ScanResult<Box<dyn Read + Send>> {
+        let key = format!("{}{}", self.prefix, filename);
+        let url = self
+            .bucket
+            .get_object(self.credentials.as_ref(), &key)
+            .sign(SIGNATURE_EXPIRY);
+        let response = self.agent.get(url.as_str()).call().map_err(storage_error)?;
+
+        Ok(response.into_reader())
+    }
+}
+
+#[test]
+fn test_split_location() {
+    assert_eq!(split_location("mirror"), ("mirror", String::new()));
+    assert_eq!(split_location("mirror/"), ("mirror", String::new()));
+    assert_eq!(
+        split_location("mirror/aosc/debs/"),
+        ("mirror", "aosc/debs/".to_string())
+    );
+}
+
+#[test]
+fn test_parse_last_modified() {
+    assert_eq!(
+        parse_last_modified("Wed, 14 Jul 2021 10:54:24 GMT").unwrap(),
+        1626260064
+    );
+    assert!(parse_last_modified("2021-07-14T10:54:24Z").is_err());
+}
@@ -0,0 +1,262 @@
+//! Verification of the package signatures: detached (`<package>.asc` or `<package>.sig`
+//! next to the package) or embedded in the package by `debsigs` (the `_gpgorigin` member)
+
+use std::io::{ErrorKind, Read};
+
+use ar::Archive as ArArchive;
+use sequoia_openpgp::Cert;
+
+use super::source::PackageSource;
+use super::{ScanError, ScanResult};
+use crate::sign::verify_detached;
+
+const AR_MAGIC: &[u8] = b"!<arch>\n";
+const AR_HEADER_SIZE: usize = 60;
+
+/// Contents of the package members covered by an embedded signature: every member in order,
+/// except the signatures (`_gpg*`) themselves
+struct SignedMembers<R: Read> {
+    inner: R,
+    started: bool,
+    /// bytes left in the current member
+    remaining: u64,
+    /// whether the current member is skipped
+    skip: bool,
+    /// members are aligned to even offsets, a padding byte follows the odd-sized ones
+    padding: bool,
+}
+
+impl<R: Read> SignedMembers<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            started: false,
+            remaining: 0,
+            skip: false,
+            padding: false,
+        }
+    }
+
+    /// Read the next member header, `None` at the end of the archive
+    fn next_header(&mut self) -> std::io::Result<Option<[u8; AR_HEADER_SIZE]>> {
+        if self.padding {
+            self.padding = false;
+            let mut pad = [0u8; 1];
+            if self.inner.read(&mut pad)? == 0 {
+                return Ok(None);
+            }
+        }
+        let mut header = [0u8; AR_HEADER_SIZE];
+        let mut filled = 0;
+        while filled < AR_HEADER_SIZE {
+            match self.inner.read(&mut header[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(Some(header))
+    }
+}
+
+impl<R: Read> Read for SignedMembers<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if !self.started {
+            let mut magic = [0u8; 8];
+            self.inner.read_exact(&mut magic)?;
+            if magic != AR_MAGIC {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    "not an ar archive",
+                ));
+            }
+            self.started = true;
+        }
+        loop {
+            if self.remaining > 0 {
+                if self.skip {
+                    let skipped = std::io::copy(
+                        &mut (&mut self.inner).take(self.remaining),
+                        &mut std::io::sink(),
+                    )?;
+                    if skipped < self.remaining {
+                        return Err(ErrorKind::UnexpectedEof.into());
+                    }
+                    self.remaining = 0;
+                    continue;
+                }
+                let len = buf.len().min(self.remaining as usize);
+                let n = self.inner.read(&mut buf[..len])?;
+                if n == 0 && len > 0 {
+                    return Err(ErrorKind::UnexpectedEof.into());
+                }
+                self.remaining -= n as u64;
+                return Ok(n);
+            }
+            let Some(header) = self.next_header()? else {
+                return Ok(0);
+            };
+            let size = std::str::from_utf8(&header[48..58])
+                .ok()
+                .and_then(|s| s.trim().parse::<u64>().ok())
+                .ok_or_else(|| {
+                    std::io::Error::new(ErrorKind::InvalidData, "invalid ar member size")
+                })?;
+            self.skip = header.starts_with(b"_gpg");
+            self.remaining = size;
+            self.padding = size % 2 == 1;
+        }
+    }
+}
+
+/// Read the `_gpgorigin` member of the package, if any
+fn read_embedded_signature<R: Read>(reader: R) -> ScanResult<Option<Vec<u8>>> {
+    let mut deb = ArArchive::new(reader);
+    while let Some(entry) = deb.next_entry() {
+        let mut entry = entry?;
+        if entry.header().identifier() == b"_gpgorigin" {
+            let mut signature = Vec::new();
+            entry.read_to_end(&mut signature)?;
+            return Ok(Some(signature));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Check that the package is signed by one of the keys in the keyring. Detached signatures
+/// take precedence over the embedded one.
+pub(crate) fn verify_package_signature(
+    source: &dyn PackageSource,
+    filename: &str,
+    keyring: &[Cert],
+) -> ScanResult<()> {
+    for extension in ["asc", "sig"] {
+        let signature_file = format!("{}.{}", filename, extension);
+        if source.stat(&signature_file)?.is_none() {
+            continue;
+        }
+        let mut signature = Vec::new();
+        source.open(&signature_file)?.read_to_end(&mut signature)?;
+        return verify_detached(keyring, &signature, source.open(filename)?)
+            .map_err(|e| ScanError::BadSignature(format!("{}: {}", signature_file, e)));
+    }
+    let signature = read_embedded_signature(source.open(filename)?)?.ok_or(ScanError::Unsigned)?;
+
+    verify_detached(
+        keyring,
+        &signature,
+        SignedMembers::new(source.open(filename)?),
+    )
+    .map_err(|e| ScanError::BadSignature(format!("_gpgorigin: {}", e)))
+}
+
+#[test]
+fn test_signed_members() {
+    let mut builder = ar::Builder::new(Vec::new());
+    let members: &[(&str, &[u8])] = &[
+        ("debian-binary", b"2.0\n"),
+        ("control.tar.xz", b"odd"),
+        ("data.tar.xz", b"data"),
+        ("_gpgorigin", b"signature"),
+    ];
+    for (name, data) in members {
+        let header = ar::Header::new(name.as_bytes().to_vec(), data.len() as u64);
+        builder.append(&header, *data).unwrap();
+    }
+    let archive = builder.into_inner().unwrap();
+    let mut content = Vec::new();
+    SignedMembers::new(&archive[..])
+        .read_to_end(&mut content)
+        .unwrap();
+    assert_eq!(content, b"2.0\nodddata");
+    assert!(SignedMembers::new(&b"not an archive"[..])
+        .read_to_end(&mut Vec::new())
+        .is_err());
+}
+
+#[test]
+fn test_verify_package_signature() {
+    use secrecy::ExposeSecret;
+    use sequoia_openpgp::parse::Parse;
+    use sequoia_openpgp::policy::StandardPolicy;
+    use sequoia_openpgp::serialize::stream::{Armorer, Message, Signer};
+    use std::io::Write;
+
+    let new_cert = || {
+        let generated = crate::sign::generate_certificate(
+            "Test <test@aosc.io>",
+            crate::sign::KeyAlgorithm::Ed25519,
+        )
+        .unwrap();
+        Cert::from_bytes(generated.privkey.expose_secret()).unwrap()
+    };
+    let sign = |cert: &Cert, content: &[u8]| {
+        let policy = StandardPolicy::new();
+        let keypair = cert
+            .keys()
+            .secret()
+            .with_policy(&policy, None)
+            .for_signing()
+            .next()
+            .unwrap()
+            .key()
+            .clone()
+            .into_keypair()
+            .unwrap();
+        let mut sink = Vec::new();
+        let message = Armorer::new(Message::new(&mut sink)).build().unwrap();
+        let mut signer = Signer::new(message, keypair).detached().build().unwrap();
+        signer.write_all(content).unwrap();
+        signer.finalize().unwrap();
+        sink
+    };
+    let build_ar = |members: &[(&str, &[u8])]| {
+        let mut builder = ar::Builder::new(Vec::new());
+        for (name, data) in members {
+            let header = ar::Header::new(name.as_bytes().to_vec(), data.len() as u64);
+            builder.append(&header, *data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    };
+    let cert = new_cert();
+    let other = new_cert();
+    let keyring = [cert.clone()];
+    let dir = tempfile::tempdir().unwrap();
+    let source = super::source::LocalSource::new(dir.path());
+    let members: &[(&str, &[u8])] = &[
+        ("debian-binary", b"2.0\n"),
+        ("control.tar.xz", b"control"),
+        ("data.tar.xz", b"data"),
+    ];
+    let unsigned = build_ar(members);
+    std::fs::write(dir.path().join("a.deb"), &unsigned).unwrap();
+    assert!(matches!(
+        verify_package_signature(&source, "a.deb", &keyring),
+        Err(ScanError::Unsigned)
+    ));
+    // detached signatures
+    std::fs::write(dir.path().join("a.deb.asc"), sign(&cert, &unsigned)).unwrap();
+    assert!(verify_package_signature(&source, "a.deb", &keyring).is_ok());
+    std::fs::write(dir.path().join("a.deb.asc"), sign(&other, &unsigned)).unwrap();
+    assert!(matches!(
+        verify_package_signature(&source, "a.deb", &keyring),
+        Err(ScanError::BadSignature(_))
+    ));
+    std::fs::remove_file(dir.path().join("a.deb.asc")).unwrap();
+    // embedded signature
+    let signature = sign(&cert, b"2.0\ncontroldata");
+    let mut signed = members.to_vec();
+    signed.push(("_gpgorigin", &signature));
+    std::fs::write(dir.path().join("a.deb"), build_ar(&signed)).unwrap();
+    assert!(verify_package_signature(&source, "a.deb", &keyring).is_ok());
+    signed[2] = ("data.tar.xz", b"tampered");
+    std::fs::write(dir.path().join("a.deb"), build_ar(&signed)).unwrap();
+    assert!(matches!(
+        verify_package_signature(&source, "a.deb", &keyring),
+        Err(ScanError::BadSignature(_))
+    ));
+}
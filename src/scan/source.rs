@@ -0,0 +1,196 @@
+//! Storage backends of the package pool
+
+use std::fs::File;
+use std::io::{BufReader, Cursor, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+
+use walkdir::WalkDir;
+
+use super::{is_deb, is_ignored, mtime, ScanError, ScanResult};
+use crate::config::GeneralConfig;
+
+/// A package file in the pool
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackageEntry {
+    /// Path relative to the mirror root (e.g. `pool/stable/main/a/a_1.0_amd64.deb`)
+    pub filename: String,
+    /// Size in bytes
+    pub size: u64,
+    /// Last modified time (seconds since the Unix epoch)
+    pub mtime: u64,
+}
+
+/// Where the packages are read from
+pub trait PackageSource: Send + Sync {
+    /// List all the packages in the pool, skipping hidden files and files with the ignored extensions.
+    /// Installer packages (`.udeb`) are only listed if `include_udeb` is set
+    fn list_packages(
+        &self,
+        ignored_extensions: &[String],
+        include_udeb: bool,
+    ) -> ScanResult<Vec<PackageEntry>>;
+    /// List the components (e.g. `stable/main`) in the pool
+    fn list_components(&self) -> ScanResult<Vec<PathBuf>>;
+    /// Get the size and mtime of a package, `None` if it does not exist
+    fn stat(&self, filename: &str) -> ScanResult<Option<PackageEntry>>;
+    /// Prepare for `stat` on many files. Sources making a request per `stat` list the whole pool
+    /// once instead, and answer from that listing from then on
+    fn preload(&self) -> ScanResult<()> {
+        Ok(())
+    }
+    /// Open a package for reading
+    fn open(&self, filename: &str) -> ScanResult<Box<dyn Read + Send>>;
+}
+
+/// Open the package source specified by `path`: a local directory or an `s3://bucket/prefix` URL
+pub fn open_package_source(config: &GeneralConfig) -> ScanResult<Box<dyn PackageSource>> {
+    match config.path.strip_prefix("s3://") {
+        #[cfg(feature = "s3")]
+        Some(location) => Ok(Box::new(super::s3::S3Source::new(location, config)?)),
+        #[cfg(not(feature = "s3"))]
+        Some(_) => Err(ScanError::ObjectStorage(
+            "p-vector is built without the `s3` feature".to_string(),
+        )),
+        None => Ok(Box::new(
+            LocalSource::new(&config.path).with_mmap_max_size(config.mmap_max_size),
+        )),
+    }
+}
+
+/// Buffer size when streaming the packages not memory-mapped
+const STREAM_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Packages on the local filesystem
+pub struct LocalSource {
+    root: PathBuf,
+    /// largest package (in bytes) memory-mapped when opened, `None` for no limit
+    mmap_max_size: Option<u64>,
+}
+
+impl LocalSource {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            root: root.as_ref().to_owned(),
+            mmap_max_size: None,
+        }
+    }
+
+    /// Stream the packages larger than `limit` instead of memory-mapping them
+    pub fn with_mmap_max_size(mut self, limit: Option<u64>) -> Self {
+        self.mmap_max_size = limit;
+        self
+    }
+}
+
+impl PackageSource for LocalSource {
+    fn list_packages(
+        &self,
+        ignored_extensions: &[String],
+        include_udeb: bool,
+    ) -> ScanResult<Vec<PackageEntry>> {
+        let mut files = Vec::with_capacity(1000);
+        for entry in WalkDir::new(self.root.join("pool"))
+            .into_iter()
+            .filter_entry(|x| !is_ignored(x, ignored_extensions))
+        {
+            let entry = entry?;
+            if !entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| is_deb(name, include_udeb))
+            {
+                continue;
+            }
+            let filename = entry
+                .path()
+                .strip_prefix(&self.root)
+                .map_err(|_| ScanError::InvalidPath(entry.path().display().to_string()))?;
+            let stat = entry.metadata()?;
+            files.push(PackageEntry {
+                filename: filename.to_string_lossy().to_string(),
+                size: stat.len(),
+                mtime: mtime(&stat)?,
+            });
+        }
+
+        Ok(files)
+    }
+
+    fn list_components(&self) -> ScanResult<Vec<PathBuf>> {
+        super::discover_topics_components(self.root.join("pool"))
+    }
+
+    fn stat(&self, filename: &str) -> ScanResult<Option<PackageEntry>> {
+        let stat = match self.root.join(filename).metadata() {
+            Ok(stat) => stat,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if !stat.is_file() {
+            return Ok(None);
+        }
+
+        Ok(Some(PackageEntry {
+            filename: filename.to_string(),
+            size: stat.len(),
+            mtime: mtime(&stat)?,
+        }))
+    }
+
+    fn open(&self, filename: &str) -> ScanResult<Box<dyn Read + Send>> {
+        let f = File::open(self.root.join(filename))?;
+        let size = f.metadata()?.len();
+        if self.mmap_max_size.is_some_and(|limit| size > limit) {
+            return Ok(Box::new(BufReader::with_capacity(STREAM_BUFFER_SIZE, f)));
+        }
+        let f = unsafe { memmap2::Mmap::map(&f)? };
+
+        Ok(Box::new(Cursor::new(f)))
+    }
+}
+
+#[test]
+fn test_local_source_streaming() {
+    let filename = "pool/tests/fixtures/a2jmidid_9-0_amd64.deb";
+    let read = |source: LocalSource| {
+        let mut content = Vec::new();
+        source
+            .open(filename)
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        content
+    };
+    let mapped = read(LocalSource::new("./tests"));
+    assert_eq!(mapped.len(), 32424);
+    // larger than the limit, streamed
+    assert_eq!(
+        read(LocalSource::new("./tests").with_mmap_max_size(Some(0))),
+        mapped
+    );
+    assert_eq!(
+        read(LocalSource::new("./tests").with_mmap_max_size(Some(1 << 20))),
+        mapped
+    );
+}
+
+#[test]
+fn test_local_source() {
+    let source = LocalSource::new("./tests");
+    let filename = "pool/tests/fixtures/a2jmidid_9-0_amd64.deb";
+    let entry = source.stat(filename).unwrap().unwrap();
+    assert_eq!(entry.filename, filename);
+    assert_eq!(entry.size, 32424);
+    assert!(source
+        .stat("pool/tests/fixtures/missing.deb")
+        .unwrap()
+        .is_none());
+    assert!(source.stat("pool/tests/fixtures").unwrap().is_none());
+    let mut data = Vec::new();
+    source
+        .open(filename)
+        .unwrap()
+        .read_to_end(&mut data)
+        .unwrap();
+    assert_eq!(data.len(), 32424);
+}
@@ -1,19 +1,62 @@
 use anyhow::{anyhow, Result};
-use openpgp::cert::{Cert, CertBuilder};
+use log::warn;
+use openpgp::cert::CertParser;
+use openpgp::cert::{Cert, CertBuilder, CipherSuite};
+use openpgp::parse::stream::{
+    DetachedVerifierBuilder, MessageLayer, MessageStructure, VerificationHelper, VerifierBuilder,
+};
 use openpgp::parse::Parse;
 use openpgp::policy::StandardPolicy;
 use openpgp::serialize::stream::{Message, Signer};
 use openpgp::serialize::SerializeInto;
 use openpgp::types::KeyFlags;
 use sailfish::TemplateSimple;
+use serde::Serialize;
+
+use crate::template::Templates;
 use secrecy::SecretSlice;
 use sequoia_openpgp as openpgp;
-use std::io::Write;
-use std::path::Path;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::time::{Duration, UNIX_EPOCH};
 
 const CERT_LIFETIME: u64 = 2 * 31_556_952; // ~2 years
 
+/// Public key algorithm used for generating new certificates
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum KeyAlgorithm {
+    /// EdDSA over Curve25519 (sequoia's default)
+    #[default]
+    Ed25519,
+    /// 4096-bit RSA, for compatibility with older clients
+    Rsa4096,
+}
+
+impl FromStr for KeyAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ed25519" => Ok(KeyAlgorithm::Ed25519),
+            "rsa4096" => Ok(KeyAlgorithm::Rsa4096),
+            _ => Err(anyhow!(
+                "Unknown key algorithm: {} (expected ed25519 or rsa4096)",
+                s
+            )),
+        }
+    }
+}
+
+impl From<KeyAlgorithm> for CipherSuite {
+    fn from(algo: KeyAlgorithm) -> Self {
+        match algo {
+            KeyAlgorithm::Ed25519 => CipherSuite::Cv25519,
+            KeyAlgorithm::Rsa4096 => CipherSuite::RSA4k,
+        }
+    }
+}
+
 pub struct GeneratedCert {
     pub id: String,
     pub pubkey: SecretSlice<u8>,
@@ -21,7 +64,7 @@ pub struct GeneratedCert {
     pub expiry: u64,
 }
 
-#[derive(TemplateSimple)]
+#[derive(TemplateSimple, Serialize)]
 #[template(path = "gen-key-instructions.stpl")]
 struct InstructionsTemplate {
     pubkey: String,
@@ -35,21 +78,25 @@ pub fn generate_instructions(
     privkey: String,
     expdate: String,
     config_file: &str,
+    templates: &Templates,
 ) -> Result<String> {
-    Ok(InstructionsTemplate {
-        pubkey,
-        privkey,
-        expdate,
-        config_file: config_file.to_string(),
-    }
-    .render_once()?)
+    templates.render(
+        "gen-key-instructions",
+        InstructionsTemplate {
+            pubkey,
+            privkey,
+            expdate,
+            config_file: config_file.to_string(),
+        },
+    )
 }
 
-pub fn generate_certificate(userid: &str) -> Result<GeneratedCert> {
+pub fn generate_certificate(userid: &str, algo: KeyAlgorithm) -> Result<GeneratedCert> {
     let now = std::time::SystemTime::now()
         .duration_since(UNIX_EPOCH)?
         .as_secs();
     let (cert, _) = CertBuilder::new()
+        .set_cipher_suite(algo.into())
         .add_userid(userid)
         .set_validity_period(Duration::from_secs(CERT_LIFETIME))
         .add_subkey(
@@ -76,56 +123,380 @@ pub fn load_certificate<P: AsRef<Path>>(cert_path: P) -> Result<Cert> {
     Cert::from_file(cert_path.as_ref())
 }
 
-pub fn sign_message_agent(cert: &Cert, content: &[u8]) -> Result<Vec<u8>> {
-    use sequoia_gpg_agent::gnupg::Context;
-    use sequoia_gpg_agent::KeyPair;
-
+fn has_signing_key(cert: &Cert) -> bool {
     let policy = StandardPolicy::new();
-    let keypair = cert
-        .keys()
+    cert.keys()
         .with_policy(&policy, None)
         .supported()
         .alive()
         .revoked(false)
         .for_signing()
-        .next();
-    if keypair.is_none() {
-        return Err(anyhow!("No usable signing key found in your certificate."));
+        .next()
+        .is_some()
+}
+
+/// Load the signing certificates from the `certificate` option.
+/// `dir:<path>` loads every `*.pub` and `*.key` certificate in the directory.
+pub fn load_certificates(spec: &str) -> Result<Vec<Cert>> {
+    let dir = match spec.strip_prefix("dir:") {
+        Some(dir) => dir,
+        None => return Ok(vec![load_certificate(spec)?]),
+    };
+    let mut paths = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    paths.sort();
+    let mut certs: Vec<Cert> = Vec::new();
+    for path in paths {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("pub") | Some("key") => (),
+            _ => continue,
+        }
+        let cert = load_certificate(&path)
+            .map_err(|e| anyhow!("Failed to load {}: {}", path.display(), e))?;
+        if !has_signing_key(&cert) {
+            warn!("{} has no usable signing key, skipping.", path.display());
+            continue;
+        }
+        // merge the public and private parts of the same key (as written by gen-key)
+        match certs
+            .iter_mut()
+            .find(|c| c.fingerprint() == cert.fingerprint())
+        {
+            Some(existing) => *existing = existing.clone().merge_public_and_secret(cert)?,
+            None => certs.push(cert),
+        }
     }
-    let pubkey = keypair.unwrap().key();
-    let ctx = Context::new()?;
-    let offloaded_keypair = KeyPair::new_for_gnupg_context(&ctx, pubkey)?;
+    if certs.is_empty() {
+        return Err(anyhow!("No usable signing keys found in {}", dir));
+    }
+
+    Ok(certs)
+}
+
+/// Write the armored public keys of the certificates into `dir` as `<fingerprint>.asc`,
+/// returning the paths written
+pub fn export_public_keys(certs: &[Cert], dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut written = Vec::with_capacity(certs.len());
+    for cert in certs {
+        // only the public parts are serialized unless `as_tsk()` is used
+        let path = dir.join(format!("{}.asc", cert.fingerprint()));
+        std::fs::write(&path, cert.armored().to_vec()?)?;
+        written.push(path);
+    }
+
+    Ok(written)
+}
+
+/// Armor line opening the signature of a cleartext signed message
+const SIGNATURE_ARMOR: &[u8] = b"\n-----BEGIN PGP SIGNATURE-----\n";
+
+/// Add a `Comment` armor header to the signature of a cleartext signed message.
+/// Armor headers are not covered by the signature, so the message still verifies.
+fn add_armor_comment(mut signed: Vec<u8>, comment: &str) -> Vec<u8> {
+    let position = signed
+        .windows(SIGNATURE_ARMOR.len())
+        .rposition(|w| w == SIGNATURE_ARMOR);
+    if let Some(position) = position {
+        // a header is a single line
+        let comment = comment.split_whitespace().collect::<Vec<_>>().join(" ");
+        let header = format!("Comment: {}\n", comment);
+        let at = position + SIGNATURE_ARMOR.len();
+        signed.splice(at..at, header.into_bytes());
+    }
+
+    signed
+}
+
+fn sign_with_keypairs<S>(keypairs: Vec<S>, content: &[u8], comment: Option<&str>) -> Result<Vec<u8>>
+where
+    S: openpgp::crypto::Signer + Send + Sync,
+{
+    let mut keypairs = keypairs.into_iter();
+    let first = keypairs
+        .next()
+        .ok_or_else(|| anyhow!("No signing key specified."))?;
     let mut data_sink = Vec::new();
     let message = Message::new(&mut data_sink);
-    let mut message = Signer::new(message, offloaded_keypair)
-        .cleartext()
-        .build()?;
+    let mut signer = Signer::new(message, first);
+    for keypair in keypairs {
+        signer = signer.add_signer(keypair);
+    }
+    let mut message = signer.cleartext().build()?;
     message.write_all(content)?;
     message.finalize()?;
 
-    Ok(data_sink)
+    Ok(match comment {
+        Some(comment) => add_armor_comment(data_sink, comment),
+        None => data_sink,
+    })
+}
+
+pub fn sign_message_agent(
+    certs: &[Cert],
+    content: &[u8],
+    comment: Option<&str>,
+) -> Result<Vec<u8>> {
+    use sequoia_gpg_agent::gnupg::Context;
+    use sequoia_gpg_agent::KeyPair;
+
+    let policy = StandardPolicy::new();
+    let ctx = Context::new()?;
+    let mut keypairs = Vec::with_capacity(certs.len());
+    for cert in certs {
+        let keypair = cert
+            .keys()
+            .with_policy(&policy, None)
+            .supported()
+            .alive()
+            .revoked(false)
+            .for_signing()
+            .next();
+        if keypair.is_none() {
+            return Err(anyhow!(
+                "No usable signing key found in certificate {}.",
+                cert.fingerprint()
+            ));
+        }
+        let pubkey = keypair.unwrap().key();
+        keypairs.push(KeyPair::new_for_gnupg_context(&ctx, pubkey)?);
+    }
+
+    sign_with_keypairs(keypairs, content, comment)
+}
+
+pub fn sign_message(certs: &[Cert], content: &[u8], comment: Option<&str>) -> Result<Vec<u8>> {
+    let policy = StandardPolicy::new();
+    let mut keypairs = Vec::with_capacity(certs.len());
+    for cert in certs {
+        let keypair = cert
+            .keys()
+            .secret()
+            .with_policy(&policy, None)
+            .supported()
+            .alive()
+            .revoked(false)
+            .for_signing()
+            .next();
+        if keypair.is_none() {
+            return Err(anyhow!(
+                "No usable signing key found in certificate {}.",
+                cert.fingerprint()
+            ));
+        }
+        keypairs.push(keypair.unwrap().key().clone().into_keypair()?);
+    }
+
+    sign_with_keypairs(keypairs, content, comment)
 }
 
-pub fn sign_message(cert: &Cert, content: &[u8]) -> Result<Vec<u8>> {
+struct VerifyHelper<'a> {
+    certs: &'a [Cert],
+}
+
+impl VerificationHelper for VerifyHelper<'_> {
+    fn get_certs(&mut self, _ids: &[openpgp::KeyHandle]) -> openpgp::Result<Vec<Cert>> {
+        Ok(self.certs.to_vec())
+    }
+
+    fn check(&mut self, structure: MessageStructure) -> openpgp::Result<()> {
+        let mut good = 0;
+        for layer in structure.into_iter() {
+            if let MessageLayer::SignatureGroup { results } = layer {
+                good += results.iter().filter(|r| r.is_ok()).count();
+            }
+        }
+        // every certificate should have made a valid signature
+        if good < self.certs.len() {
+            return Err(anyhow!(
+                "Expected {} valid signature(s), found {}",
+                self.certs.len(),
+                good
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Load all the certificates in a keyring file (concatenated certificates, armored or not)
+pub fn load_keyring<P: AsRef<Path>>(path: P) -> Result<Vec<Cert>> {
+    let certs = CertParser::from_file(path.as_ref())?.collect::<openpgp::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(anyhow!(
+            "No certificates found in {}",
+            path.as_ref().display()
+        ));
+    }
+
+    Ok(certs)
+}
+
+/// Unlike [VerifyHelper], a single valid signature made by any of the certificates is enough
+struct KeyringHelper<'a> {
+    certs: &'a [Cert],
+}
+
+impl VerificationHelper for KeyringHelper<'_> {
+    fn get_certs(&mut self, _ids: &[openpgp::KeyHandle]) -> openpgp::Result<Vec<Cert>> {
+        Ok(self.certs.to_vec())
+    }
+
+    fn check(&mut self, structure: MessageStructure) -> openpgp::Result<()> {
+        for layer in structure.into_iter() {
+            if let MessageLayer::SignatureGroup { results } = layer {
+                if results.iter().any(|r| r.is_ok()) {
+                    return Ok(());
+                }
+            }
+        }
+
+        Err(anyhow!("No valid signature made by the trusted keys"))
+    }
+}
+
+/// The verifier wants a `Sync` reader, which the package readers are not
+struct SyncReader<R>(std::sync::Mutex<R>);
+
+impl<R: Read> Read for SyncReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0
+            .get_mut()
+            .map_err(|_| std::io::Error::other("poisoned reader"))?
+            .read(buf)
+    }
+}
+
+/// Verify a detached signature over `data`, made by any of the certificates in the keyring
+pub fn verify_detached<R: Read + Send>(keyring: &[Cert], signature: &[u8], data: R) -> Result<()> {
     let policy = StandardPolicy::new();
-    let keypair = cert
+    let mut verifier = DetachedVerifierBuilder::from_bytes(signature)?.with_policy(
+        &policy,
+        None,
+        KeyringHelper { certs: keyring },
+    )?;
+    verifier.verify_reader(SyncReader(std::sync::Mutex::new(data)))?;
+
+    Ok(())
+}
+
+/// Verify a signed message against the certificates, returning the signed content
+pub fn verify_message(certs: &[Cert], signed: &[u8]) -> Result<Vec<u8>> {
+    let policy = StandardPolicy::new();
+    let mut verifier =
+        VerifierBuilder::from_bytes(signed)?.with_policy(&policy, None, VerifyHelper { certs })?;
+    let mut content = Vec::new();
+    verifier.read_to_end(&mut content)?;
+
+    Ok(content)
+}
+
+#[test]
+fn test_key_algorithm() {
+    use openpgp::types::PublicKeyAlgorithm;
+
+    assert_eq!(
+        "ed25519".parse::<KeyAlgorithm>().unwrap(),
+        KeyAlgorithm::Ed25519
+    );
+    assert_eq!(
+        "rsa4096".parse::<KeyAlgorithm>().unwrap(),
+        KeyAlgorithm::Rsa4096
+    );
+    assert!("dsa".parse::<KeyAlgorithm>().is_err());
+
+    let generated = generate_certificate("Test <test@aosc.io>", KeyAlgorithm::Ed25519).unwrap();
+    let cert = {
+        use secrecy::ExposeSecret;
+        Cert::from_bytes(generated.privkey.expose_secret()).unwrap()
+    };
+    let policy = StandardPolicy::new();
+    let key = cert
         .keys()
-        .secret()
         .with_policy(&policy, None)
-        .supported()
-        .alive()
-        .revoked(false)
         .for_signing()
-        .next();
-    if keypair.is_none() {
-        return Err(anyhow!("No usable signing key found in your certificate."));
+        .next()
+        .unwrap();
+    assert_eq!(key.pk_algo(), PublicKeyAlgorithm::EdDSA);
+    assert!(sign_message(&[cert], b"test", None).is_ok());
+}
+
+#[test]
+fn test_verify_message() {
+    let generated = generate_certificate("Test <test@aosc.io>", KeyAlgorithm::Ed25519).unwrap();
+    let cert = {
+        use secrecy::ExposeSecret;
+        Cert::from_bytes(generated.privkey.expose_secret()).unwrap()
+    };
+    let content = b"Origin: AOSC\nSuite: stable\n";
+    let signed = sign_message(std::slice::from_ref(&cert), content, None).unwrap();
+    assert_eq!(
+        verify_message(std::slice::from_ref(&cert), &signed).unwrap(),
+        content
+    );
+    // tampered content
+    let tampered = String::from_utf8(signed)
+        .unwrap()
+        .replace("Suite: stable", "Suite: testing");
+    assert!(verify_message(std::slice::from_ref(&cert), tampered.as_bytes()).is_err());
+    // signed by another certificate
+    let other = generate_certificate("Other <other@aosc.io>", KeyAlgorithm::Ed25519).unwrap();
+    let other = {
+        use secrecy::ExposeSecret;
+        Cert::from_bytes(other.privkey.expose_secret()).unwrap()
+    };
+    let signed = sign_message(std::slice::from_ref(&other), content, None).unwrap();
+    assert!(verify_message(std::slice::from_ref(&cert), &signed).is_err());
+    // signed by both
+    let both = [cert, other];
+    let signed = sign_message(&both, content, None).unwrap();
+    assert_eq!(verify_message(&both, &signed).unwrap(), content);
+    assert!(verify_message(&both[..1], &signed).is_ok());
+    // the armor comment is not part of the signed content
+    let signed = sign_message(&both, content, Some("Signed by\nAOSC")).unwrap();
+    assert!(String::from_utf8_lossy(&signed)
+        .contains("-----BEGIN PGP SIGNATURE-----\nComment: Signed by AOSC\n"));
+    assert_eq!(verify_message(&both, &signed).unwrap(), content);
+}
+
+#[test]
+fn test_load_certificates_dir() {
+    use secrecy::ExposeSecret;
+
+    let dir = tempfile::tempdir().unwrap();
+    let spec = format!("dir:{}", dir.path().display());
+    assert!(load_certificates(&spec).is_err());
+    for _ in 0..2 {
+        let generated = generate_certificate("Test <test@aosc.io>", KeyAlgorithm::Ed25519).unwrap();
+        let path = dir.path().join(&generated.id);
+        std::fs::write(
+            path.with_extension("key"),
+            generated.privkey.expose_secret(),
+        )
+        .unwrap();
+        std::fs::write(path.with_extension("pub"), generated.pubkey.expose_secret()).unwrap();
     }
-    let keypair = keypair.unwrap().key().clone().into_keypair()?;
-    let mut data_sink = Vec::new();
-    let message = Message::new(&mut data_sink);
-    let mut message = Signer::new(message, keypair).cleartext().build()?;
-    message.write_all(content)?;
-    message.finalize()?;
+    std::fs::write(dir.path().join("README"), b"not a key").unwrap();
+    let certs = load_certificates(&spec).unwrap();
+    assert_eq!(certs.len(), 2);
+    assert!(certs.iter().all(|cert| cert.is_tsk()));
+    assert!(sign_message(&certs, b"test", None).is_ok());
+}
+
+#[test]
+fn test_export_public_keys() {
+    use secrecy::ExposeSecret;
 
-    Ok(data_sink)
+    let generated = generate_certificate("Test <test@aosc.io>", KeyAlgorithm::Ed25519).unwrap();
+    let cert = Cert::from_bytes(generated.privkey.expose_secret()).unwrap();
+    assert!(cert.is_tsk());
+    let dir = tempfile::tempdir().unwrap();
+    let written = export_public_keys(std::slice::from_ref(&cert), dir.path()).unwrap();
+    assert_eq!(
+        written,
+        vec![dir.path().join(format!("{}.asc", cert.fingerprint()))]
+    );
+    let exported = Cert::from_file(&written[0]).unwrap();
+    assert_eq!(exported.fingerprint(), cert.fingerprint());
+    assert!(!exported.is_tsk());
 }
@@ -0,0 +1,64 @@
+//! Progress shown in the service status (`systemctl status p-vector`)
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Shortest interval between two updates of a [Progress]
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Set the status line of the service, does nothing when not running under systemd
+pub fn set_status(message: &str) {
+    #[cfg(feature = "systemd")]
+    sd_notify::notify(false, &[sd_notify::NotifyState::Status(message)]).ok();
+    #[cfg(not(feature = "systemd"))]
+    let _ = message;
+}
+
+/// Counter of the packages processed by a phase, reported as the service status
+/// (e.g. `Scanned 1200/5000 packages`). Can be shared between threads, the updates are
+/// throttled so that a large number of small packages does not flood the service manager
+pub struct Progress {
+    action: &'static str,
+    total: usize,
+    done: AtomicUsize,
+    last_update: Mutex<Instant>,
+}
+
+impl Progress {
+    pub fn new(action: &'static str, total: usize) -> Self {
+        set_status(&format!("{} 0/{} packages", action, total));
+        Self {
+            action,
+            total,
+            done: AtomicUsize::new(0),
+            last_update: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Count a processed package
+    pub fn inc(&self) {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        // another thread updating the status right now is just as good
+        let Ok(mut last_update) = self.last_update.try_lock() else {
+            return;
+        };
+        if done == self.total || last_update.elapsed() >= PROGRESS_INTERVAL {
+            *last_update = Instant::now();
+            set_status(&self.message(done));
+        }
+    }
+
+    fn message(&self, done: usize) -> String {
+        format!("{} {}/{} packages", self.action, done, self.total)
+    }
+}
+
+#[test]
+fn test_progress() {
+    let progress = Progress::new("Scanned", 3);
+    progress.inc();
+    progress.inc();
+    assert_eq!(progress.done.load(Ordering::Relaxed), 2);
+    assert_eq!(progress.message(2), "Scanned 2/3 packages");
+}
@@ -0,0 +1,52 @@
+//! Runtime template overrides
+
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use log::info;
+use sailfish::TemplateSimple;
+use serde::Serialize;
+use tera::{Context, Tera};
+
+/// Templates loaded from the `template_dir` at runtime, overriding the compiled-in ones
+#[derive(Clone, Default)]
+pub struct Templates {
+    overrides: Option<Arc<Tera>>,
+}
+
+impl Templates {
+    /// Load all the `*.tera` templates in the directory (if any)
+    pub fn load(dir: Option<&str>) -> Result<Self> {
+        let dir = match dir {
+            Some(dir) => Path::new(dir),
+            None => return Ok(Self::default()),
+        };
+        if !dir.is_dir() {
+            return Err(anyhow!(
+                "Template directory {} does not exist",
+                dir.display()
+            ));
+        }
+        let tera = Tera::new(&format!("{}/*.tera", dir.display()))?;
+        for name in tera.get_template_names() {
+            info!("Using template override {}", name);
+        }
+
+        Ok(Self {
+            overrides: Some(Arc::new(tera)),
+        })
+    }
+
+    /// Render `name` using its override (`<name>.tera`) if there is one,
+    /// or the compiled-in template otherwise
+    pub fn render<T: TemplateSimple + Serialize>(&self, name: &str, template: T) -> Result<String> {
+        let file = format!("{}.tera", name);
+        match self.overrides {
+            Some(ref tera) if tera.get_template_names().any(|n| n == file) => {
+                Ok(tera.render(&file, &Context::from_serialize(&template)?)?)
+            }
+            _ => Ok(template.render_once()?),
+        }
+    }
+}